@@ -1,5 +1,10 @@
-use std::collections::BTreeSet;
-use tokio::{select, sync::mpsc};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::{
+    select,
+    sync::{mpsc, watch},
+};
 use uuid::Uuid;
 
 use ble_peripheral_rust::{
@@ -12,17 +17,268 @@ use ble_peripheral_rust::{
 
 use crate::consts::*;
 use crate::hid::{
-    build_hid_service, build_keyboard_report, build_mouse_report, keyboard_usage_to_modifier,
+    build_absolute_report, build_consumer_report, build_hid_service, build_keyboard_report,
+    build_mouse_report, build_nkro_report, keyboard_usage_to_modifier,
 };
+use crate::input::InputRx;
+use crate::mouse::MouseScheduler;
+use crate::record::Recorder;
+use crate::settings::{LiveSettings, Output};
+use crate::status::Status;
 use crate::ui::AppCmd;
 
+pub struct BleOptions {
+    /// Identity, enabled reports and timing; changes apply while running
+    pub settings: watch::Receiver<LiveSettings>,
+    /// Device Information Service characteristic values, fixed once registered
+    pub device_info: Vec<(u16, Vec<u8>)>,
+    /// Log every received command to a file
+    pub record: Option<Recorder>,
+}
+
+/// Keyboard and consumer control state as last reported to the host.
+/// Press times are kept so the lease watchdog can release keys whose KeyUp never arrived.
+#[derive(Default)]
+struct KeyState {
+    modifiers: u8,
+    pressed: BTreeSet<u8>,
+    held_since: BTreeMap<u8, Instant>,
+    /// Report keys in the NKRO bitmap; only usages past it use the 6KRO report
+    nkro: bool,
+    /// Consumer controls held and when, most recent last; the report carries the most recent one
+    media: Vec<(u16, Instant)>,
+}
+
+impl KeyState {
+    fn press(&mut self, usage: u8) {
+        if let Some(m) = keyboard_usage_to_modifier(usage) {
+            self.modifiers |= m;
+        } else {
+            self.pressed.insert(usage);
+            // Keys sharing the six 6KRO slots past the sixth drop out, lowest usage first
+            let slotted: Vec<u8> = self
+                .pressed
+                .iter()
+                .copied()
+                .filter(|&u| !self.nkro || u > NKRO_MAX_USAGE)
+                .collect();
+            for u in &slotted[..slotted.len().saturating_sub(6)] {
+                self.pressed.remove(u);
+                self.held_since.remove(u);
+            }
+        }
+        self.held_since.entry(usage).or_insert_with(Instant::now);
+    }
+
+    fn release(&mut self, usage: u8) {
+        if let Some(m) = keyboard_usage_to_modifier(usage) {
+            self.modifiers &= !m;
+        } else {
+            self.pressed.remove(&usage);
+        }
+        self.held_since.remove(&usage);
+    }
+
+    /// Forget everything; returns true if any key was held
+    fn clear(&mut self) -> bool {
+        let any = self.modifiers != 0 || !self.pressed.is_empty();
+        self.modifiers = 0;
+        self.pressed.clear();
+        self.held_since.clear();
+        any
+    }
+
+    /// Usages held for longer than `lease`
+    fn expired(&self, lease: Duration) -> Vec<u8> {
+        self.held_since
+            .iter()
+            .filter(|(_, t)| t.elapsed() >= lease)
+            .map(|(&u, _)| u)
+            .collect()
+    }
+
+    fn media_down(&mut self, usage: u16) -> [u8; 3] {
+        self.media.retain(|&(u, _)| u != usage);
+        self.media.push((usage, Instant::now()));
+        build_consumer_report(usage)
+    }
+
+    fn media_up(&mut self, usage: u16) -> [u8; 3] {
+        self.media.retain(|&(u, _)| u != usage);
+        build_consumer_report(self.media.last().map_or(0, |&(u, _)| u))
+    }
+
+    /// Consumer controls held for longer than `lease`
+    fn expired_media(&self, lease: Duration) -> Vec<u16> {
+        self.media
+            .iter()
+            .filter(|(_, t)| t.elapsed() >= lease)
+            .map(|&(u, _)| u)
+            .collect()
+    }
+
+    /// The report that carries `usage`
+    fn report_for(&self, usage: u8) -> Vec<u8> {
+        if !self.nkro {
+            build_keyboard_report(self.modifiers, &self.pressed).to_vec()
+        } else if usage > NKRO_MAX_USAGE && keyboard_usage_to_modifier(usage).is_none() {
+            let high = self.pressed.range(NKRO_MAX_USAGE + 1..).copied().collect();
+            build_keyboard_report(0, &high).to_vec()
+        } else {
+            build_nkro_report(self.modifiers, &self.pressed).to_vec()
+        }
+    }
+
+    /// Every report carrying keyboard state
+    fn reports(&self) -> Vec<Vec<u8>> {
+        if self.nkro {
+            vec![self.report_for(0), self.report_for(NKRO_MAX_USAGE + 1)]
+        } else {
+            vec![self.report_for(0)]
+        }
+    }
+
+    fn publish(&self, status: &watch::Sender<Status>) {
+        status.send_if_modified(|s| {
+            let keys: Vec<u8> = self.pressed.iter().copied().collect();
+            let changed = s.modifiers != self.modifiers || s.keys != keys;
+            s.modifiers = self.modifiers;
+            s.keys = keys;
+            changed
+        });
+    }
+}
+
+/// Push all-released keyboard, consumer and mouse reports so the host drops anything it thinks
+/// is held
+async fn send_release_all(peripheral: &mut Peripheral, input_uuid: Uuid) -> anyhow::Result<()> {
+    let empty = BTreeSet::new();
+    for report in [
+        build_keyboard_report(0, &empty).to_vec(),
+        build_nkro_report(0, &empty).to_vec(),
+        build_consumer_report(0).to_vec(),
+        build_mouse_report(0, 0, 0, 0, 0).to_vec(),
+    ] {
+        peripheral
+            .update_characteristic(input_uuid, report.into())
+            .await?;
+    }
+    Ok(())
+}
+
+/// Send each of `reports` in order
+async fn send_reports(
+    peripheral: &mut Peripheral,
+    input_uuid: Uuid,
+    reports: Vec<Vec<u8>>,
+) -> anyhow::Result<()> {
+    for report in reports {
+        peripheral
+            .update_characteristic(input_uuid, report.into())
+            .await?;
+    }
+    Ok(())
+}
+
+async fn start_advertising(
+    peripheral: &mut Peripheral,
+    settings: &LiveSettings,
+) -> anyhow::Result<()> {
+    peripheral
+        .start_advertising(
+            &settings.name,
+            &[
+                Uuid::from_short(UUID_HID_SERVICE),
+                Uuid::from_short(UUID_BAS_SERVICE),
+                Uuid::from_short(UUID_DIS_SERVICE),
+            ],
+            Some(settings.appearance),
+        )
+        .await?;
+    tracing::info!("Advertising {}", &settings.name);
+    Ok(())
+}
+
+/// The profile of the most recently connected host that has one bound
+fn pick_profile(settings: &LiveSettings, hosts: &[String]) -> Option<String> {
+    hosts
+        .iter()
+        .rev()
+        .find_map(|h| settings.profiles.for_host(h))
+        .map(|(name, _)| name.to_owned())
+}
+
+/// Leave the host with nothing held through reports `next` switches off or replaces
+async fn release_disabled(
+    peripheral: &mut Peripheral,
+    input_uuid: Uuid,
+    input_notify: bool,
+    keys: &mut KeyState,
+    mouse: &mut MouseScheduler,
+    status: &watch::Sender<Status>,
+    (prev, next): (Output, Output),
+) -> anyhow::Result<()> {
+    // Keys held under one keyboard report are released there before the other takes over
+    let switching = prev.nkro != next.nkro;
+    if ((prev.keyboard && !next.keyboard) || switching) && keys.clear() {
+        keys.publish(status);
+        if input_notify {
+            send_reports(peripheral, input_uuid, keys.reports()).await?;
+        }
+    }
+    keys.nkro = next.nkro;
+    if prev.consumer && !next.consumer && !keys.media.is_empty() {
+        keys.media.clear();
+        if input_notify {
+            peripheral
+                .update_characteristic(input_uuid, build_consumer_report(0).to_vec().into())
+                .await?;
+        }
+    }
+    if prev.mouse && !next.mouse {
+        mouse.reset();
+        status.send_if_modified(|s| std::mem::replace(&mut s.buttons, 0) != 0);
+        if input_notify {
+            peripheral
+                .update_characteristic(
+                    input_uuid,
+                    build_mouse_report(0, 0, 0, 0, 0).to_vec().into(),
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Lease watchdog period: a quarter of the lease, polled only when a lease is configured
+fn lease_interval(lease: Option<Duration>) -> tokio::time::Interval {
+    tokio::time::interval(lease.map_or(Duration::from_secs(1), |l| {
+        (l / 4).max(Duration::from_millis(10))
+    }))
+}
+
+fn mouse_interval(slot: Duration) -> tokio::time::Interval {
+    let mut tick = tokio::time::interval(slot);
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    tick
+}
+
 pub async fn ble_owner_task(
-    mut cmd_rx: mpsc::Receiver<AppCmd>,
+    mut input: InputRx,
     mut evt_rx: mpsc::Receiver<PeripheralEvent>,
     evt_tx: mpsc::Sender<PeripheralEvent>,
-    device_name: String,
-    appearance: Option<u16>,
+    status: watch::Sender<Status>,
+    opts: BleOptions,
 ) -> anyhow::Result<()> {
+    let BleOptions {
+        settings: mut settings_rx,
+        device_info,
+        mut record,
+    } = opts;
+    let mut settings = settings_rx.borrow_and_update().clone();
+    // Profile of the host input currently goes to, and what it lets through
+    let mut profile: Option<String> = None;
+    let mut output = settings.output(None);
     let (hid_service, input_uuid) = build_hid_service();
 
     let bas_service = ble_peripheral_rust::gatt::service::Service {
@@ -43,30 +299,22 @@ pub async fn ble_owner_task(
     let dis_service = ble_peripheral_rust::gatt::service::Service {
         uuid: Uuid::from_short(UUID_DIS_SERVICE),
         primary: true,
-        characteristics: vec![
-            ble_peripheral_rust::gatt::characteristic::Characteristic {
-                uuid: Uuid::from_short(UUID_MFG_NAME),
-                properties: vec![
-                    ble_peripheral_rust::gatt::properties::CharacteristicProperty::Read,
-                ],
-                permissions: vec![
-                    ble_peripheral_rust::gatt::properties::AttributePermission::Readable,
-                ],
-                value: Some(device_name.as_bytes().to_vec()),
-                ..Default::default()
-            },
-            ble_peripheral_rust::gatt::characteristic::Characteristic {
-                uuid: Uuid::from_short(UUID_MODEL_NUM),
-                properties: vec![
-                    ble_peripheral_rust::gatt::properties::CharacteristicProperty::Read,
-                ],
-                permissions: vec![
-                    ble_peripheral_rust::gatt::properties::AttributePermission::Readable,
-                ],
-                value: Some(device_name.as_bytes().to_vec()),
-                ..Default::default()
-            },
-        ],
+        characteristics: device_info
+            .into_iter()
+            .map(
+                |(uuid, value)| ble_peripheral_rust::gatt::characteristic::Characteristic {
+                    uuid: Uuid::from_short(uuid),
+                    properties: vec![
+                        ble_peripheral_rust::gatt::properties::CharacteristicProperty::Read,
+                    ],
+                    permissions: vec![
+                        ble_peripheral_rust::gatt::properties::AttributePermission::Readable,
+                    ],
+                    value: Some(value),
+                    ..Default::default()
+                },
+            )
+            .collect(),
     };
 
     let mut peripheral = Peripheral::new(evt_tx).await?;
@@ -80,33 +328,39 @@ pub async fn ble_owner_task(
         tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
         delay_ms = (delay_ms * 2).min(1000);
     }
+    status.send_modify(|s| s.powered = true);
 
     peripheral.add_service(&hid_service).await?;
     peripheral.add_service(&bas_service).await?;
     peripheral.add_service(&dis_service).await?;
 
+    let mut powered = true;
     let mut advertising = false;
-    if !advertising {
-        peripheral
-            .start_advertising(
-                &device_name,
-                &[
-                    Uuid::from_short(UUID_HID_SERVICE),
-                    Uuid::from_short(UUID_BAS_SERVICE),
-                    Uuid::from_short(UUID_DIS_SERVICE),
-                ],
-                appearance,
-            )
-            .await?;
+    if settings.advertise {
+        start_advertising(&mut peripheral, &settings).await?;
         advertising = true;
-        tracing::info!("Advertising {}", &device_name);
+    } else {
+        tracing::info!("Advertising disabled");
     }
+    status.send_modify(|s| s.advertising = advertising);
 
-    let mut modifiers: u8 = 0;
-    let mut pressed: BTreeSet<u8> = BTreeSet::new();
+    let mut keys = KeyState {
+        nkro: output.nkro,
+        ..Default::default()
+    };
+    let mut mouse = MouseScheduler::new();
+    let mut mouse_tick = mouse_interval(settings.mouse_interval);
     let mut input_notify = false;
     let mut battery_notify = false;
     let mut last_battery: u8 = 95;
+    status.send_modify(|s| s.battery = last_battery);
+    // Input commands discarded because the host is not subscribed to reports
+    let mut discarded: u64 = 0;
+    let mut logged_stats = (0u64, 0u64, 0u64);
+    let mut stats_tick = tokio::time::interval(Duration::from_secs(30));
+
+    let mut lease_tick = lease_interval(settings.key_lease);
+    let mut settings_open = true;
 
     loop {
         select! {
@@ -114,17 +368,10 @@ pub async fn ble_owner_task(
                 match ev {
                     Some(PeripheralEvent::StateUpdate{ is_powered }) => {
                         tracing::info!(%is_powered, "Adapter powered");
+                        powered = is_powered;
                         if is_powered {
-                            if !advertising {
-                                if let Err(e) = peripheral.start_advertising(
-                                    &device_name,
-                                    &[
-                                        Uuid::from_short(UUID_HID_SERVICE),
-                                        Uuid::from_short(UUID_BAS_SERVICE),
-                                        Uuid::from_short(UUID_DIS_SERVICE),
-                                    ],
-                                    appearance,
-                                ).await {
+                            if !advertising && settings.advertise {
+                                if let Err(e) = start_advertising(&mut peripheral, &settings).await {
                                     tracing::error!(error = %format!("{e:#}"), "advertise start error");
                                 } else {
                                     advertising = true;
@@ -135,12 +382,57 @@ pub async fn ble_owner_task(
                                 if let Err(e) = peripheral.stop_advertising().await { tracing::error!(error = %format!("{e:#}"), "advertise stop error"); }
                                 advertising = false;
                             }
+                            // Link is gone with the adapter; nothing we held survives on the host
+                            if keys.clear() {
+                                tracing::info!("Adapter off, cleared key state");
+                            }
+                            keys.media.clear();
                         }
+                        status.send_modify(|s| {
+                            s.powered = is_powered;
+                            s.advertising = advertising;
+                            s.modifiers = keys.modifiers;
+                            s.keys.clear();
+                            if !is_powered {
+                                s.hosts.clear();
+                            }
+                        });
+                        input_notify = !status.borrow().hosts.is_empty();
                     }
                     Some(PeripheralEvent::CharacteristicSubscriptionUpdate { request, subscribed }) => {
                         if request.characteristic == input_uuid {
-                            input_notify = subscribed;
                             tracing::info!(%subscribed, "Report notify INPUT");
+                            // Unsubscribe is also how a disconnect shows up. Either way the host's
+                            // view of held keys is stale, so start both sides from all-released.
+                            if keys.clear() {
+                                tracing::info!(%subscribed, "Cleared held keys on subscription change");
+                            }
+                            keys.media.clear();
+                            mouse.reset();
+                            status.send_modify(|s| {
+                                s.hosts.retain(|h| *h != request.client);
+                                if subscribed {
+                                    s.hosts.push(request.client.clone());
+                                }
+                                s.connected = !s.hosts.is_empty();
+                                s.modifiers = 0;
+                                s.keys.clear();
+                                s.buttons = 0;
+                            });
+                            // Reports keep flowing while any host is still subscribed
+                            input_notify = !status.borrow().hosts.is_empty();
+                            if let Err(e) = send_release_all(&mut peripheral, input_uuid).await {
+                                tracing::warn!(error = %format!("{e:#}"), "release-all report error");
+                            }
+                            let next = pick_profile(&settings, &status.borrow().hosts);
+                            if next != profile {
+                                tracing::info!(profile = next.as_deref().unwrap_or("default"), host = %request.client, "Profile switched");
+                                status.send_modify(|s| s.profile = next.clone());
+                                profile = next;
+                                // Held state was just cleared, so there is nothing to release
+                                output = settings.output(profile.as_deref());
+                                keys.nkro = output.nkro;
+                            }
                         } else if request.characteristic == Uuid::from_short(UUID_BATTERY_LEVEL) {
                             battery_notify = subscribed;
                             tracing::info!(%subscribed, "Report notify BATTERY");
@@ -167,34 +459,56 @@ pub async fn ble_owner_task(
                     None => break,
                 }
             }
-            cmd = cmd_rx.recv() => {
+            cmd = input.recv() => {
                 tracing::trace!(?cmd, "Received command");
+                // A writer that gave up has logged why
+                if let (Some(rec), Some(cmd)) = (&record, &cmd)
+                    && !rec.write(cmd)
+                {
+                    record = None;
+                }
                 match cmd {
-                    Some(AppCmd::Mouse { buttons, dx, dy, wheel }) if input_notify => {
-                        let pkt = build_mouse_report(buttons, dx, dy, wheel);
-                        tracing::trace!(buttons = %format!("{buttons:#04b}"), %dx, %dy, %wheel, "TX mouse");
-                        peripheral.update_characteristic(input_uuid, pkt.to_vec().into()).await?;
-                    }
-                    Some(AppCmd::KeyDown(usage)) if input_notify => {
-                        if let Some(m) = keyboard_usage_to_modifier(usage) { modifiers |= m; }
-                        else {
-                            pressed.insert(usage);
-                            while pressed.len() > 6 { let first = *pressed.iter().next().unwrap(); pressed.remove(&first); }
+                    Some(AppCmd::Mouse { buttons, dx, dy, wheel, pan }) if input_notify && output.mouse => {
+                        // Button changes go out now; motion waits for the next report slot
+                        status.send_if_modified(|s| std::mem::replace(&mut s.buttons, buttons) != buttons);
+                        for pkt in mouse.push(buttons, dx, dy, wheel as i32, pan as i32) {
+                            tracing::trace!(buttons = %format!("{buttons:#04b}"), ?pkt, "TX mouse (buttons)");
+                            peripheral.update_characteristic(input_uuid, pkt.to_vec().into()).await?;
                         }
-                        let pkt = build_keyboard_report(modifiers, &pressed);
-                        tracing::trace!(mods = %format!("{modifiers:#010b}"), ?pressed, "TX keybd DOWN");
+                    }
+                    Some(AppCmd::KeyDown(usage)) if input_notify && output.keyboard => {
+                        keys.press(usage);
+                        keys.publish(&status);
+                        let pkt = keys.report_for(usage);
+                        tracing::trace!(mods = %format!("{:#010b}", keys.modifiers), pressed = ?keys.pressed, "TX keybd DOWN");
+                        peripheral.update_characteristic(input_uuid, pkt.into()).await?;
+                    }
+                    Some(AppCmd::KeyUp(usage)) if input_notify && output.keyboard => {
+                        keys.release(usage);
+                        keys.publish(&status);
+                        let pkt = keys.report_for(usage);
+                        tracing::trace!(mods = %format!("{:#010b}", keys.modifiers), pressed = ?keys.pressed, "TX keybd UP");
+                        peripheral.update_characteristic(input_uuid, pkt.into()).await?;
+                    }
+                    Some(AppCmd::ConsumerDown(usage)) if input_notify && output.consumer => {
+                        let pkt = keys.media_down(usage);
+                        tracing::trace!(usage = %format!("{usage:#05x}"), "TX consumer DOWN");
                         peripheral.update_characteristic(input_uuid, pkt.to_vec().into()).await?;
                     }
-                    Some(AppCmd::KeyUp(usage)) if input_notify => {
-                        if let Some(m) = keyboard_usage_to_modifier(usage) { modifiers &= !m; }
-                        else { pressed.remove(&usage); }
-                        let pkt = build_keyboard_report(modifiers, &pressed);
-                        tracing::trace!(mods = %format!("{modifiers:#010b}"), ?pressed, "TX keybd UP");
+                    Some(AppCmd::ConsumerUp(usage)) if input_notify && output.consumer => {
+                        let pkt = keys.media_up(usage);
+                        tracing::trace!(usage = %format!("{usage:#05x}"), "TX consumer UP");
                         peripheral.update_characteristic(input_uuid, pkt.to_vec().into()).await?;
                     }
+                    Some(AppCmd::MouseTo { x, y }) if input_notify && output.absolute => {
+                        let buttons = status.borrow().buttons;
+                        tracing::trace!(%x, %y, "TX absolute");
+                        peripheral.update_characteristic(input_uuid, build_absolute_report(buttons, x, y).to_vec().into()).await?;
+                    }
                     Some(AppCmd::Battery(level)) => {
                         if level != last_battery {
                             last_battery = level;
+                            status.send_modify(|s| s.battery = level);
                             if battery_notify {
                                 peripheral.update_characteristic(Uuid::from_short(UUID_BATTERY_LEVEL), vec![level].into()).await?;
                             }
@@ -203,12 +517,125 @@ pub async fn ble_owner_task(
                     }
                     Some(AppCmd::Exit) => break,
                     None => break,
-                    Some(_) => {}
+                    Some(
+                        AppCmd::Mouse { .. }
+                        | AppCmd::KeyDown(_)
+                        | AppCmd::KeyUp(_)
+                        | AppCmd::ConsumerDown(_)
+                        | AppCmd::ConsumerUp(_)
+                        | AppCmd::MouseTo { .. },
+                    ) => discarded += 1,
+                }
+            }
+            _ = stats_tick.tick() => {
+                let stats = input.stats();
+                let now = (stats.merged.load(Ordering::Relaxed), stats.dropped.load(Ordering::Relaxed), discarded);
+                if now != logged_stats {
+                    let (merged, dropped, discarded) = now;
+                    if dropped > logged_stats.1 {
+                        tracing::warn!(%merged, %dropped, %discarded, "Input stats");
+                    } else {
+                        tracing::debug!(%merged, %dropped, %discarded, "Input stats");
+                    }
+                    logged_stats = now;
+                }
+            }
+            _ = mouse_tick.tick(), if input_notify && mouse.has_pending() => {
+                if let Some(pkt) = mouse.take_report() {
+                    tracing::trace!(?pkt, "TX mouse");
+                    peripheral.update_characteristic(input_uuid, pkt.to_vec().into()).await?;
+                }
+            }
+            changed = settings_rx.changed(), if settings_open => {
+                if changed.is_err() {
+                    settings_open = false;
+                    continue;
+                }
+                let next = settings_rx.borrow_and_update().clone();
+                let identity = |s: &LiveSettings| (s.name.clone(), s.appearance, s.advertise);
+                if powered && identity(&next) != identity(&settings) {
+                    if advertising {
+                        if let Err(e) = peripheral.stop_advertising().await {
+                            tracing::error!(error = %format!("{e:#}"), "advertise stop error");
+                        }
+                        advertising = false;
+                    }
+                    if next.advertise {
+                        if let Err(e) = start_advertising(&mut peripheral, &next).await {
+                            tracing::error!(error = %format!("{e:#}"), "advertise start error");
+                        } else {
+                            advertising = true;
+                        }
+                    } else {
+                        tracing::info!("Advertising disabled");
+                    }
+                    status.send_modify(|s| s.advertising = advertising);
+                }
+                if next.key_lease != settings.key_lease {
+                    lease_tick = lease_interval(next.key_lease);
+                }
+                if next.mouse_interval != settings.mouse_interval {
+                    mouse_tick = mouse_interval(next.mouse_interval);
+                }
+                let next_profile = pick_profile(&next, &status.borrow().hosts);
+                if next_profile != profile {
+                    tracing::info!(profile = next_profile.as_deref().unwrap_or("default"), "Profile switched");
+                    status.send_modify(|s| s.profile = next_profile.clone());
+                    profile = next_profile;
+                }
+                let next_output = next.output(profile.as_deref());
+                release_disabled(&mut peripheral, input_uuid, input_notify, &mut keys, &mut mouse, &status, (output, next_output)).await?;
+                output = next_output;
+                tracing::info!(?next, "Settings applied");
+                settings = next;
+            }
+            _ = lease_tick.tick(), if settings.key_lease.is_some() && input_notify => {
+                let lease = settings.key_lease.unwrap();
+                let expired = keys.expired(lease);
+                if !expired.is_empty() {
+                    tracing::warn!(?expired, "Key lease expired, auto-releasing");
+                    for usage in expired {
+                        keys.release(usage);
+                    }
+                    keys.publish(&status);
+                    send_reports(&mut peripheral, input_uuid, keys.reports()).await?;
+                }
+                let expired = keys.expired_media(lease);
+                if !expired.is_empty() {
+                    tracing::warn!(?expired, "Media key lease expired, auto-releasing");
+                    let mut pkt = build_consumer_report(0);
+                    for usage in expired {
+                        pkt = keys.media_up(usage);
+                    }
+                    peripheral.update_characteristic(input_uuid, pkt.to_vec().into()).await?;
                 }
             }
         }
     }
 
-    peripheral.stop_advertising().await?;
+    let stats = input.stats();
+    tracing::info!(
+        merged = stats.merged.load(Ordering::Relaxed),
+        dropped = stats.dropped.load(Ordering::Relaxed),
+        %discarded,
+        "Input stats at exit"
+    );
+
+    // Graceful teardown: flush pending motion and leave the host with nothing held
+    if input_notify {
+        while let Some(pkt) = mouse.take_report() {
+            peripheral
+                .update_characteristic(input_uuid, pkt.to_vec().into())
+                .await?;
+        }
+        keys.clear();
+        send_release_all(&mut peripheral, input_uuid).await?;
+        tracing::info!("Sent release-all before exit");
+    }
+    if advertising {
+        peripheral.stop_advertising().await?;
+    }
+    // Dropping the peripheral unregisters the GATT application, which disconnects the host
+    drop(peripheral);
     Ok(())
 }
@@ -0,0 +1,270 @@
+// Client subcommands: JSON-RPC requests to the running instance, exit code for scripts
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use serde_json::{Value, json};
+
+use crate::Command;
+use crate::consts::{LED_CAPS_LOCK, LED_NUM_LOCK, LED_SCROLL_LOCK};
+use crate::ducky;
+use crate::hid::{BUTTON_NAMES, usage_name};
+use crate::macros::Op;
+use crate::status::Status;
+use crate::typing::{KeyEvent, Keymap, Timing};
+
+// Process exit codes
+pub const EXIT_OK: i32 = 0;
+/// The instance rejected or could not fully carry out the request
+pub const EXIT_FAILED: i32 = 1;
+/// No instance is listening on the control socket
+pub const EXIT_NOT_RUNNING: i32 = 3;
+
+enum ClientError {
+    NotRunning(std::io::Error),
+    Io(std::io::Error),
+    Rpc(String),
+    /// The request was rejected before reaching the instance
+    Invalid(anyhow::Error),
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Run one client subcommand; returns the process exit code
+pub fn run(cmd: Command, socket: &Path) -> i32 {
+    match execute(cmd, socket) {
+        Ok(code) => code,
+        Err(ClientError::NotRunning(e)) => {
+            eprintln!("bluper is not running ({}: {e})", socket.display());
+            EXIT_NOT_RUNNING
+        }
+        Err(ClientError::Io(e)) => {
+            eprintln!("control socket error: {e}");
+            EXIT_FAILED
+        }
+        Err(ClientError::Rpc(msg)) => {
+            eprintln!("{msg}");
+            EXIT_FAILED
+        }
+        Err(ClientError::Invalid(e)) => {
+            eprintln!("{e:#}");
+            EXIT_FAILED
+        }
+    }
+}
+
+fn execute(cmd: Command, socket: &Path) -> Result<i32, ClientError> {
+    // Payloads compile here, so a dry run needs no instance
+    if let Command::Ducky {
+        file,
+        dry_run,
+        layout,
+        unicode,
+        delay_ms,
+    } = cmd
+    {
+        let delay = Duration::from_millis(delay_ms);
+        let timing = Timing {
+            hold: delay,
+            gap: delay,
+        };
+        let ops = std::fs::read_to_string(&file)
+            .with_context(|| format!("read {}", file.display()))
+            .and_then(|text| {
+                ducky::compile(&text, &Keymap::new(layout).with_unicode(unicode), timing)
+            })
+            .with_context(|| format!("payload {}", file.display()))
+            .map_err(ClientError::Invalid)?;
+        if dry_run {
+            print_reports(&ops);
+            return Ok(EXIT_OK);
+        }
+        let mut conn = Connection::open(socket)?;
+        return run_payload(&mut conn, &ops).map(|()| EXIT_OK);
+    }
+    let mut conn = Connection::open(socket)?;
+    match cmd {
+        Command::Type {
+            text,
+            layout,
+            delay_ms,
+            unicode,
+        } => {
+            let params = json!({
+                "text": text,
+                "layout": layout.map(|l| l.name()),
+                "delay_ms": delay_ms,
+                "unicode": unicode.map(|u| u.name()),
+            });
+            let result = conn.call("type_text", params)?;
+            let skipped = result["skipped"].as_array().map_or(0, |s| s.len());
+            if skipped > 0 {
+                eprintln!("could not type: {}", result["skipped"]);
+                return Ok(EXIT_FAILED);
+            }
+        }
+        Command::Key { chord } => {
+            conn.call("tap", json!({ "keys": chord }))?;
+        }
+        Command::Click { button, count } => {
+            let button = format!("{button:?}").to_lowercase();
+            conn.call("click", json!({ "button": button, "count": count }))?;
+        }
+        Command::Move { dx, dy } => {
+            conn.call("mouse_move", json!({ "dx": dx, "dy": dy }))?;
+        }
+        Command::Status { json } => {
+            let value = conn.call("status", Value::Null)?;
+            if json {
+                println!("{value}");
+            } else {
+                let status: Status = serde_json::from_value(value)
+                    .map_err(|e| ClientError::Rpc(format!("bad status reply: {e}")))?;
+                print_status(&status);
+            }
+        }
+        Command::Hosts => {
+            let value = conn.call("status", Value::Null)?;
+            let status: Status = serde_json::from_value(value)
+                .map_err(|e| ClientError::Rpc(format!("bad status reply: {e}")))?;
+            for host in &status.hosts {
+                println!("{host}");
+            }
+        }
+        Command::Run(_) => unreachable!("run is handled in main"),
+        Command::Ducky { .. } => unreachable!("handled above"),
+    }
+    Ok(EXIT_OK)
+}
+
+/// Send payload key events on the payload's own clock
+fn run_payload(conn: &mut Connection, ops: &[Op]) -> Result<(), ClientError> {
+    let start = Instant::now();
+    let mut at = Duration::ZERO;
+    for op in ops {
+        let (method, usage) = match *op {
+            Op::Wait(d) => {
+                at += d;
+                std::thread::sleep((start + at).saturating_duration_since(Instant::now()));
+                continue;
+            }
+            Op::Key(KeyEvent::Down(usage)) => ("key_down", usage),
+            Op::Key(KeyEvent::Up(usage)) => ("key_up", usage),
+            Op::Mouse { .. } => continue,
+        };
+        if let Err(e) = conn.call(method, json!({ "key": usage_name(usage) })) {
+            let _ = conn.call("release_all", Value::Null);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn print_reports(ops: &[Op]) {
+    let mut out = std::io::stdout().lock();
+    for r in ducky::reports(ops) {
+        let bytes: Vec<String> = r.bytes.iter().map(|b| format!("{b:02x}")).collect();
+        let (dir, usage) = match r.event {
+            KeyEvent::Down(u) => ('+', u),
+            KeyEvent::Up(u) => ('-', u),
+        };
+        let line = writeln!(
+            out,
+            "{:>10.3} ms  {}  {dir}{}",
+            r.at.as_secs_f64() * 1000.0,
+            bytes.join(" "),
+            usage_name(usage)
+        );
+        // Piped into head and the like
+        if line.is_err() {
+            return;
+        }
+    }
+}
+
+struct Connection {
+    writer: UnixStream,
+    reader: BufReader<UnixStream>,
+    next_id: u64,
+}
+
+impl Connection {
+    fn open(socket: &Path) -> Result<Self, ClientError> {
+        let writer = UnixStream::connect(socket).map_err(ClientError::NotRunning)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self {
+            writer,
+            reader,
+            next_id: 1,
+        })
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> Result<Value, ClientError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut line =
+            json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }).to_string();
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+
+        let mut reply = String::new();
+        if self.reader.read_line(&mut reply)? == 0 {
+            return Err(ClientError::Rpc("instance closed the connection".into()));
+        }
+        let mut reply: Value = serde_json::from_str(&reply)
+            .map_err(|e| ClientError::Rpc(format!("bad reply: {e}")))?;
+        match reply.get("error") {
+            Some(err) => Err(ClientError::Rpc(
+                err["message"]
+                    .as_str()
+                    .unwrap_or("request failed")
+                    .to_string(),
+            )),
+            None => Ok(reply["result"].take()),
+        }
+    }
+}
+
+fn print_status(s: &Status) {
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+    println!("powered:     {}", yes_no(s.powered));
+    println!("advertising: {}", yes_no(s.advertising));
+    println!("connected:   {}", yes_no(s.connected));
+    match s.leds {
+        Some(bits) => {
+            let leds: Vec<&str> = [
+                (LED_NUM_LOCK, "num"),
+                (LED_CAPS_LOCK, "caps"),
+                (LED_SCROLL_LOCK, "scroll"),
+            ]
+            .iter()
+            .filter(|(bit, _)| bits & bit != 0)
+            .map(|&(_, name)| name)
+            .collect();
+            println!("leds:        {}", leds.join(" "));
+        }
+        None => println!("leds:        unavailable"),
+    }
+    let mut held: Vec<String> = (0..8)
+        .filter(|b| s.modifiers & (1 << b) != 0)
+        .map(|b| usage_name(0xE0 + b))
+        .collect();
+    held.extend(s.keys.iter().map(|&u| usage_name(u)));
+    println!("keys:        {}", held.join(" "));
+    let buttons: Vec<&str> = BUTTON_NAMES
+        .iter()
+        .filter(|(_, bit)| s.buttons & bit != 0)
+        .map(|&(name, _)| name)
+        .collect();
+    println!("buttons:     {}", buttons.join(" "));
+    println!("battery:     {}%", s.battery);
+    println!("profile:     {}", s.profile.as_deref().unwrap_or("default"));
+}
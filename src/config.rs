@@ -0,0 +1,430 @@
+// Configuration file: identity, device information, advertising, reports, battery, input
+// sources, remaps and the control socket, as TOML:
+//
+//     [identity]
+//     name = "Desk KVM"
+//     appearance = 0x03C1
+//
+//     [device_info]
+//     manufacturer = "Acme"
+//     serial = "0001"
+//
+//     [input]
+//     evdev = ["logitech"]
+//     remap = "remap.toml"
+//
+//     [profiles.mac]
+//     hosts = ["AA:BB:CC:DD:EE:FF"]
+//     remap = "mac.toml"
+//     unicode = "macos"
+//
+// Every setting is optional; flags given on the command line win over the file. Relative paths
+// are taken from the file's directory.
+//
+// The running instance watches the file. `changes` sorts what differs between two versions by
+// what applying it takes: some settings are picked up live, some only at startup, and some are
+// baked into the GATT services registered with the adapter.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Deserializer};
+
+use crate::mouse::PointerSettings;
+use crate::typing::{Layout, UnicodeInput};
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub identity: Identity,
+    pub device_info: DeviceInfo,
+    pub advertising: Advertising,
+    pub reports: Reports,
+    pub battery: Battery,
+    pub input: Input,
+    pub pointer: PointerSettings,
+    pub control: Control,
+    pub profiles: Profiles,
+}
+
+/// Name and appearance the peripheral advertises
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Identity {
+    pub name: Option<String>,
+    pub appearance: Option<u16>,
+}
+
+/// Device Information Service strings; manufacturer and model default to the device name
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceInfo {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub hardware_revision: Option<String>,
+    pub firmware_revision: Option<String>,
+    pub software_revision: Option<String>,
+    pub pnp_id: Option<PnpId>,
+}
+
+/// Vendor and product IDs hosts use to pick drivers and quirks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PnpId {
+    #[serde(default)]
+    pub vendor_source: VendorSource,
+    pub vendor: u16,
+    pub product: u16,
+    #[serde(default)]
+    pub version: u16,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VendorSource {
+    Bluetooth,
+    #[default]
+    Usb,
+}
+
+impl PnpId {
+    /// PnP ID characteristic value: source, then little-endian vendor, product and version
+    pub fn to_bytes(self) -> [u8; 7] {
+        let source = match self.vendor_source {
+            VendorSource::Bluetooth => 1,
+            VendorSource::Usb => 2,
+        };
+        let [v0, v1] = self.vendor.to_le_bytes();
+        let [p0, p1] = self.product.to_le_bytes();
+        let [r0, r1] = self.version.to_le_bytes();
+        [source, v0, v1, p0, p1, r0, r1]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Advertising {
+    /// Stay connectable for new hosts; off keeps bonded hosts that are already connected only
+    pub enabled: bool,
+}
+
+impl Default for Advertising {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// Which reports reach the host. The report map declares all of them, so each can be switched
+/// on or off at any time.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Reports {
+    pub keyboard: bool,
+    pub mouse: bool,
+    pub nkro: bool,
+    pub consumer: bool,
+    pub absolute: bool,
+}
+
+impl Default for Reports {
+    fn default() -> Self {
+        Self {
+            keyboard: true,
+            mouse: true,
+            nkro: false,
+            consumer: false,
+            absolute: false,
+        }
+    }
+}
+
+/// Where the Battery Service level comes from
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Battery {
+    pub source: BatterySource,
+    /// Level reported with `source = "fixed"`
+    pub level: u8,
+    /// How often `source = "host"` reads the local battery
+    pub poll_secs: u64,
+}
+
+impl Default for Battery {
+    fn default() -> Self {
+        Self {
+            source: BatterySource::Host,
+            level: 100,
+            poll_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatterySource {
+    /// The battery of the machine bluper runs on, if it has one
+    Host,
+    Fixed,
+}
+
+/// Input sources and how input is shaped on its way to the host
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Input {
+    pub headless: bool,
+    pub capture: bool,
+    pub osk: bool,
+    pub tty: bool,
+    pub evdev: Vec<String>,
+    pub evdev_grab: bool,
+    pub key_lease_ms: Option<u64>,
+    pub mouse_interval_ms: Option<f32>,
+    #[serde(deserialize_with = "parse_opt")]
+    pub layout: Option<Layout>,
+    #[serde(deserialize_with = "parse_opt")]
+    pub unicode: Option<UnicodeInput>,
+    pub type_delay_ms: Option<u64>,
+    pub remap: Option<PathBuf>,
+    pub macros: Option<PathBuf>,
+    pub scripts: Option<PathBuf>,
+}
+
+/// JSON-RPC control socket
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Control {
+    pub enabled: bool,
+    pub socket: Option<PathBuf>,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket: None,
+        }
+    }
+}
+
+/// Named per-host settings, keyed by profile name
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Profiles(pub BTreeMap<String, Profile>);
+
+/// Settings used while one of `hosts` is the most recently connected host. Anything left out
+/// keeps the top-level value.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Host addresses as `bluper hosts` shows them, compared case-insensitively
+    pub hosts: Vec<String>,
+    #[serde(deserialize_with = "parse_opt")]
+    pub layout: Option<Layout>,
+    #[serde(deserialize_with = "parse_opt")]
+    pub unicode: Option<UnicodeInput>,
+    /// Used instead of `input.remap`
+    pub remap: Option<PathBuf>,
+    /// Multiplier on pointer motion
+    pub pointer_speed: Option<f32>,
+    pub reports: Option<Reports>,
+}
+
+impl Profiles {
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.0.get(name)
+    }
+
+    /// The profile bound to `host`, with its name
+    pub fn for_host(&self, host: &str) -> Option<(&str, &Profile)> {
+        self.0
+            .iter()
+            .find(|(_, p)| p.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+            .map(|(name, p)| (name.as_str(), p))
+    }
+}
+
+fn parse_opt<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr<Err = String>,
+{
+    Option::<String>::deserialize(d)?
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// What it takes for a changed setting to take effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Apply {
+    /// The running instance picks it up
+    Live,
+    /// Read once at startup
+    Restart,
+    /// Part of the registered GATT services; hosts may also need to forget and re-pair the
+    /// device to see it
+    Gatt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    /// Dotted path of the setting, e.g. `identity.name`
+    pub field: &'static str,
+    pub apply: Apply,
+}
+
+impl Config {
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        let config: Config = toml::from_str(text)?;
+        config.pointer.validate().context("[pointer]")?;
+        let mut bound: BTreeMap<String, &str> = BTreeMap::new();
+        for (name, profile) in &config.profiles.0 {
+            anyhow::ensure!(
+                profile
+                    .pointer_speed
+                    .is_none_or(|s| s.is_finite() && s > 0.0),
+                "profiles.{name}.pointer_speed must be positive"
+            );
+            for host in &profile.hosts {
+                if let Some(other) = bound.insert(host.to_ascii_lowercase(), name) {
+                    anyhow::bail!("host {host} is in profiles {other} and {name}");
+                }
+            }
+        }
+        anyhow::ensure!(config.battery.level <= 100, "battery.level must be 0-100");
+        anyhow::ensure!(
+            config.battery.poll_secs > 0,
+            "battery.poll_secs must be positive"
+        );
+        Ok(config)
+    }
+
+    /// Read a config file, resolving its relative paths against the file's directory
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read config file {}", path.display()))?;
+        let mut config =
+            Self::from_toml(&text).with_context(|| format!("config file {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let input = &mut config.input;
+        for p in [&mut input.remap, &mut input.macros, &mut input.scripts]
+            .into_iter()
+            .flatten()
+            .chain(config.control.socket.as_mut())
+            .chain(
+                config
+                    .profiles
+                    .0
+                    .values_mut()
+                    .filter_map(|p| p.remap.as_mut()),
+            )
+        {
+            *p = dir.join(&*p);
+        }
+        Ok(config)
+    }
+
+    /// Settings that differ in `new`, in file order
+    pub fn changes(&self, new: &Config) -> Vec<Change> {
+        use Apply::*;
+        let (a, b) = (self, new);
+        let mut out = Vec::new();
+        let mut check = |field, apply, differs: bool| {
+            if differs {
+                out.push(Change { field, apply });
+            }
+        };
+        check("identity.name", Live, a.identity.name != b.identity.name);
+        check(
+            "identity.appearance",
+            Live,
+            a.identity.appearance != b.identity.appearance,
+        );
+        // The DIS strings fall back to the name, but the service keeps what it was built with
+        check(
+            "device_info.manufacturer",
+            Gatt,
+            a.device_info
+                .manufacturer
+                .as_ref()
+                .or(a.identity.name.as_ref())
+                != b.device_info
+                    .manufacturer
+                    .as_ref()
+                    .or(b.identity.name.as_ref()),
+        );
+        check(
+            "device_info.model",
+            Gatt,
+            a.device_info.model.as_ref().or(a.identity.name.as_ref())
+                != b.device_info.model.as_ref().or(b.identity.name.as_ref()),
+        );
+        let (da, db) = (&a.device_info, &b.device_info);
+        check("device_info.serial", Gatt, da.serial != db.serial);
+        check(
+            "device_info.hardware_revision",
+            Gatt,
+            da.hardware_revision != db.hardware_revision,
+        );
+        check(
+            "device_info.firmware_revision",
+            Gatt,
+            da.firmware_revision != db.firmware_revision,
+        );
+        check(
+            "device_info.software_revision",
+            Gatt,
+            da.software_revision != db.software_revision,
+        );
+        check("device_info.pnp_id", Gatt, da.pnp_id != db.pnp_id);
+        check("advertising", Live, a.advertising != b.advertising);
+        let (ra, rb) = (&a.reports, &b.reports);
+        check("reports.keyboard", Live, ra.keyboard != rb.keyboard);
+        check("reports.mouse", Live, ra.mouse != rb.mouse);
+        check("reports.nkro", Live, ra.nkro != rb.nkro);
+        check("reports.consumer", Live, ra.consumer != rb.consumer);
+        check("reports.absolute", Live, ra.absolute != rb.absolute);
+        check("battery", Live, a.battery != b.battery);
+        let (ia, ib) = (&a.input, &b.input);
+        check("input.headless", Restart, ia.headless != ib.headless);
+        check("input.capture", Restart, ia.capture != ib.capture);
+        check("input.osk", Restart, ia.osk != ib.osk);
+        check("input.tty", Restart, ia.tty != ib.tty);
+        check("input.evdev", Restart, ia.evdev != ib.evdev);
+        check("input.evdev_grab", Restart, ia.evdev_grab != ib.evdev_grab);
+        check(
+            "input.key_lease_ms",
+            Live,
+            ia.key_lease_ms != ib.key_lease_ms,
+        );
+        check(
+            "input.mouse_interval_ms",
+            Live,
+            ia.mouse_interval_ms != ib.mouse_interval_ms,
+        );
+        check("input.layout", Restart, ia.layout != ib.layout);
+        check("input.unicode", Restart, ia.unicode != ib.unicode);
+        check(
+            "input.type_delay_ms",
+            Restart,
+            ia.type_delay_ms != ib.type_delay_ms,
+        );
+        check("input.remap", Live, ia.remap != ib.remap);
+        check("input.macros", Restart, ia.macros != ib.macros);
+        check("input.scripts", Restart, ia.scripts != ib.scripts);
+        check("pointer", Live, a.pointer != b.pointer);
+        check("control", Restart, a.control != b.control);
+        check("profiles", Live, a.profiles != b.profiles);
+        out
+    }
+}
+
+/// `$XDG_CONFIG_HOME/bluper/config.toml`, else `~/.config/bluper/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+    Some(base.join("bluper").join("config.toml"))
+}
@@ -13,9 +13,40 @@ pub const UUID_HID_REPORT: u16 = 0x2A4D;
 pub const UUID_BATTERY_LEVEL: u16 = 0x2A19;
 pub const UUID_MFG_NAME: u16 = 0x2A29;
 pub const UUID_MODEL_NUM: u16 = 0x2A24;
+pub const UUID_SERIAL_NUM: u16 = 0x2A25;
+pub const UUID_FW_REV: u16 = 0x2A26;
+pub const UUID_HW_REV: u16 = 0x2A27;
+pub const UUID_SW_REV: u16 = 0x2A28;
+pub const UUID_PNP_ID: u16 = 0x2A50;
 
 pub const PERIPHERAL_APPEARANCE: u16 = 0x03C0;
 
+// Upper bound for the release-all/teardown sequence on exit
+pub const SHUTDOWN_TIMEOUT_MS: u64 = 3000;
+
+// How often the evdev source looks for newly attached devices
+pub const EVDEV_RESCAN_MS: u64 = 2000;
+
 // Report IDs
 pub const RID_MOUSE: u8 = 0x01;
 pub const RID_KEYBD: u8 = 0x02;
+pub const RID_CONSUMER: u8 = 0x03;
+pub const RID_NKRO: u8 = 0x04;
+pub const RID_ABSOLUTE: u8 = 0x05;
+
+// Highest usage in the NKRO bitmap, so the report fits a default-MTU notification
+pub const NKRO_MAX_USAGE: u8 = 0x7F;
+// Absolute pointer coordinates run from 0 to this on each axis
+pub const ABSOLUTE_MAX: u16 = 0x7FFF;
+// Most wheel or pan steps one scroll request sends, ten full reports
+pub const SCROLL_LIMIT: i32 = 10 * i8::MAX as i32;
+
+// Mouse report button bits
+pub const BUTTON_LEFT: u8 = 1 << 0;
+pub const BUTTON_MIDDLE: u8 = 1 << 1;
+pub const BUTTON_RIGHT: u8 = 1 << 2;
+
+// Keyboard output report (host LED) bits
+pub const LED_NUM_LOCK: u8 = 1 << 0;
+pub const LED_CAPS_LOCK: u8 = 1 << 1;
+pub const LED_SCROLL_LOCK: u8 = 1 << 2;
@@ -0,0 +1,383 @@
+// Local control socket: newline-delimited JSON-RPC 2.0 for scripts, test runners and launchers
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+
+use crate::consts::ABSOLUTE_MAX;
+use crate::hid::{button_from_name, consumer_from_name, parse_chord, usage_from_name};
+use crate::input::{InputClosed, InputTx, Presses};
+use crate::settings::TypingDefaults;
+use crate::status::Status;
+use crate::typing::{Keymap, Layout, Timing, UnicodeInput};
+use crate::ui::AppCmd;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+/// Most clicks one `click` request may ask for
+const MAX_CLICKS: u32 = 100;
+
+/// `$XDG_RUNTIME_DIR/bluper.sock`, else a per-user path under /tmp
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("bluper.sock"),
+        None => {
+            let user = std::env::var("USER").unwrap_or_else(|_| "default".into());
+            std::env::temp_dir().join(format!("bluper-{user}.sock"))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+}
+
+impl From<InputClosed> for RpcError {
+    fn from(e: InputClosed) -> Self {
+        Self::new(SERVER_ERROR, e.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    /// None for a notification, which gets no reply; an explicit `null` is still a request
+    #[serde(default, deserialize_with = "present")]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+fn present<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(d).map(Some)
+}
+
+#[derive(Deserialize)]
+struct KeyParams {
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct TapParams {
+    keys: String,
+}
+
+#[derive(Deserialize)]
+struct TextParams {
+    text: String,
+    /// Overrides the instance's `--layout`
+    layout: Option<String>,
+    /// Overrides the instance's `--type-delay-ms`
+    delay_ms: Option<u64>,
+    /// Overrides the instance's `--unicode`
+    unicode: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MoveParams {
+    dx: f32,
+    dy: f32,
+}
+
+/// Absolute position as fractions of the host screen, 0 to 1 from the top left
+#[derive(Deserialize)]
+struct PositionParams {
+    x: f32,
+    y: f32,
+}
+
+#[derive(Deserialize)]
+struct ClickParams {
+    #[serde(default = "default_button")]
+    button: String,
+    #[serde(default = "default_count")]
+    count: u32,
+}
+
+fn default_button() -> String {
+    "left".into()
+}
+
+fn default_count() -> u32 {
+    1
+}
+
+#[derive(Deserialize)]
+struct ScrollParams {
+    #[serde(default)]
+    amount: i32,
+    /// Horizontal steps, positive is right
+    #[serde(default)]
+    pan: i32,
+}
+
+/// Removes the socket file when the server goes away
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Bind a socket only the owner can connect to. It is made inside a fresh 0700 directory and
+/// moved into place, so it is never reachable under looser permissions.
+fn bind_private(path: &Path) -> anyhow::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    let parent = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let staging = parent.join(format!(".bluper-{}", std::process::id()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .with_context(|| format!("create {}", staging.display()))?;
+    let staged = staging.join("sock");
+    let bound = (|| {
+        let listener = UnixListener::bind(&staged)?;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        anyhow::Ok(listener)
+    })();
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&staging);
+    bound
+}
+
+/// Accept control clients until the runtime shuts down
+pub async fn serve(
+    path: PathBuf,
+    input: InputTx,
+    status: watch::Receiver<Status>,
+    typing: watch::Receiver<TypingDefaults>,
+) -> anyhow::Result<()> {
+    if path.exists() {
+        // A live socket means another instance owns it; a dead one is left over from a crash
+        if UnixStream::connect(&path).await.is_ok() {
+            anyhow::bail!("{} is in use by another bluper", path.display());
+        }
+        std::fs::remove_file(&path)?;
+    }
+    let listener = bind_private(&path)?;
+    let _cleanup = SocketFile(path.clone());
+    tracing::info!(path = %path.display(), "Control socket listening");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let client = Client {
+            presses: Presses::new(input.new_source()),
+            typing: typing.clone(),
+        };
+        tokio::spawn(handle(stream, client, status.clone()));
+    }
+}
+
+async fn handle(stream: UnixStream, mut client: Client, status: watch::Receiver<Status>) {
+    let (rd, mut wr) = stream.into_split();
+    let mut lines = BufReader::new(rd).lines();
+    tracing::debug!("Control client connected");
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str::<Request>(&line) {
+            Ok(req) => {
+                let result = client.call(&req.method, req.params, &status).await;
+                let Some(id) = req.id else {
+                    if let Err(e) = result {
+                        tracing::debug!(
+                            method = %req.method,
+                            error = %e.message,
+                            "Control notification failed"
+                        );
+                    }
+                    continue;
+                };
+                match result {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err(e) => error_reply(id, e),
+                }
+            }
+            Err(e) => error_reply(Value::Null, RpcError::new(PARSE_ERROR, e.to_string())),
+        };
+        let mut out = reply.to_string();
+        out.push('\n');
+        if wr.write_all(out.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+    // Only this client's keys and buttons; other clients and local input keep theirs
+    client.presses.release_all();
+    tracing::debug!("Control client disconnected");
+}
+
+fn error_reply(id: Value, e: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": e.code, "message": e.message },
+    })
+}
+
+fn parse<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::params(e.to_string()))
+}
+
+/// One connection and what it currently holds on the host
+struct Client {
+    presses: Presses,
+    /// Follows the active profile
+    typing: watch::Receiver<TypingDefaults>,
+}
+
+impl Client {
+    async fn call(
+        &mut self,
+        method: &str,
+        params: Value,
+        status: &watch::Receiver<Status>,
+    ) -> Result<Value, RpcError> {
+        match method {
+            "key_down" => {
+                let p: KeyParams = parse(params)?;
+                self.presses.key_down(key_usage(&p.key)?)?;
+            }
+            "key_up" => {
+                let p: KeyParams = parse(params)?;
+                self.presses.key_up(key_usage(&p.key)?)?;
+            }
+            "tap" => {
+                let p: TapParams = parse(params)?;
+                let chord = parse_chord(&p.keys)
+                    .ok_or_else(|| RpcError::params(format!("unknown key in '{}'", p.keys)))?;
+                self.presses.tap(&chord)?;
+            }
+            "type_text" => {
+                let p: TextParams = parse(params)?;
+                return self.type_text(p).await;
+            }
+            "mouse_move" => {
+                let p: MoveParams = parse(params)?;
+                self.presses.mouse(p.dx, p.dy, 0, 0)?;
+            }
+            "mouse_to" => {
+                let p: PositionParams = parse(params)?;
+                let x = absolute_axis(p.x)?;
+                let y = absolute_axis(p.y)?;
+                self.presses.send(AppCmd::MouseTo { x, y })?;
+            }
+            "media" => {
+                let p: KeyParams = parse(params)?;
+                let usage = consumer_from_name(&p.key)
+                    .ok_or_else(|| RpcError::params(format!("unknown media key '{}'", p.key)))?;
+                self.presses.send(AppCmd::ConsumerDown(usage))?;
+                self.presses.send(AppCmd::ConsumerUp(usage))?;
+            }
+            "click" => {
+                let p: ClickParams = parse(params)?;
+                let bit = button_bit(&p.button)?;
+                if p.count > MAX_CLICKS {
+                    return Err(RpcError::params(format!(
+                        "count {} is over {MAX_CLICKS}",
+                        p.count
+                    )));
+                }
+                for _ in 0..p.count {
+                    self.presses.button_down(bit)?;
+                    self.presses.button_up(bit)?;
+                }
+            }
+            "scroll" => {
+                let p: ScrollParams = parse(params)?;
+                self.presses.mouse(0.0, 0.0, p.amount, p.pan)?;
+            }
+            "release_all" => self.presses.release_all(),
+            "status" => {
+                return serde_json::to_value(&*status.borrow())
+                    .map_err(|e| RpcError::new(SERVER_ERROR, e.to_string()));
+            }
+            _ => {
+                return Err(RpcError::new(
+                    METHOD_NOT_FOUND,
+                    format!("unknown method '{method}'"),
+                ));
+            }
+        }
+        Ok(Value::Null)
+    }
+
+    async fn type_text(&mut self, p: TextParams) -> Result<Value, RpcError> {
+        let defaults = *self.typing.borrow();
+        let layout = match p.layout {
+            Some(name) => name.parse::<Layout>().map_err(RpcError::params)?,
+            None => defaults.layout,
+        };
+        let unicode = match p.unicode {
+            Some(name) => name.parse::<UnicodeInput>().map_err(RpcError::params)?,
+            None => defaults.unicode,
+        };
+        let timing = p.delay_ms.map_or(defaults.timing, |ms| Timing {
+            hold: Duration::from_millis(ms),
+            gap: Duration::from_millis(ms),
+        });
+        let typed = Keymap::new(layout).with_unicode(unicode).type_text(&p.text);
+        for (ev, wait) in self.presses.typing(&typed, &timing) {
+            if let Some(ev) = ev {
+                self.presses.key(ev)?;
+            }
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+        let skipped: Vec<String> = typed
+            .unmappable
+            .iter()
+            .map(|(_, c)| c.to_string())
+            .collect();
+        let typed = p.text.chars().count() - skipped.len();
+        Ok(json!({ "typed": typed, "skipped": skipped }))
+    }
+}
+
+fn key_usage(name: &str) -> Result<u8, RpcError> {
+    usage_from_name(name).ok_or_else(|| RpcError::params(format!("unknown key '{name}'")))
+}
+
+fn absolute_axis(fraction: f32) -> Result<u16, RpcError> {
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(RpcError::params(format!(
+            "position {fraction} is outside 0 to 1"
+        )));
+    }
+    Ok((fraction * ABSOLUTE_MAX as f32).round() as u16)
+}
+
+fn button_bit(name: &str) -> Result<u8, RpcError> {
+    button_from_name(name).ok_or_else(|| RpcError::params(format!("unknown button '{name}'")))
+}
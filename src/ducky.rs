@@ -0,0 +1,478 @@
+// DuckyScript payload interpreter: the common command set compiled to timed key events
+//
+// Supported: REM, STRING, STRINGLN, DELAY, DEFAULT_DELAY, STRING_DELAY, REPEAT, DEFINE, VAR and
+// `$var = expr` assignments, HOLD/RELEASE, and key or combo lines such as `GUI r` or
+// `CTRL ALT DELETE`. Numeric arguments are integer expressions over literals and `$variables`.
+// Control flow (IF, WHILE, FUNCTION) and device commands (ATTACKMODE, ...) are rejected at
+// compile time rather than skipped, so a payload never runs half-understood.
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::hid::{build_keyboard_report, keyboard_usage_to_modifier, usage_from_name};
+use crate::macros::Op;
+use crate::typing::{KeyEvent, Keymap, Timing};
+
+/// Upper bound on compiled ops, against REPEAT blowing up
+const MAX_OPS: usize = 1_000_000;
+
+const ENTER: u8 = 0x28;
+
+// DuckyScript key names that `usage_from_name` does not already know
+const DUCKY_KEYS: &[(&str, u8)] = &[
+    ("windows", 0xE3),
+    ("command", 0xE3),
+    ("uparrow", 0x52),
+    ("downarrow", 0x51),
+    ("leftarrow", 0x50),
+    ("rightarrow", 0x4F),
+    ("break", 0x48),
+    ("printscrn", 0x46),
+];
+
+const UNSUPPORTED: &[&str] = &[
+    "IF",
+    "ELSE",
+    "END_IF",
+    "WHILE",
+    "END_WHILE",
+    "FUNCTION",
+    "END_FUNCTION",
+    "RETURN",
+    "ATTACKMODE",
+    "WAIT_FOR_BUTTON_PRESS",
+    "BUTTON_DEF",
+    "EXFIL",
+];
+
+/// Compile a payload; `keymap` and `timing` decide how STRING lines are typed
+pub fn compile(script: &str, keymap: &Keymap, timing: Timing) -> anyhow::Result<Vec<Op>> {
+    let mut it = Interp {
+        keymap,
+        timing,
+        default_delay: Duration::ZERO,
+        string_delay: None,
+        defines: Vec::new(),
+        vars: HashMap::new(),
+        held: Vec::new(),
+        ops: Vec::new(),
+    };
+    let mut last: Option<String> = None;
+    for (n, raw) in script.lines().enumerate() {
+        let line = it.substitute(raw.trim_start_matches('\u{feff}').trim_end_matches('\r'));
+        let (cmd, arg) = split_command(&line);
+        let result = match cmd {
+            "" | "REM" => Ok(()),
+            "REPEAT" | "REPLAY" => match &last {
+                Some(prev) => it.eval(arg).and_then(|count| {
+                    // Lines that emit nothing never reach the op limit, so bound the count too
+                    anyhow::ensure!(
+                        count <= MAX_OPS as i64,
+                        "REPEAT count {count} is over {MAX_OPS}"
+                    );
+                    for _ in 0..count.max(0) {
+                        it.line(prev)?;
+                    }
+                    Ok(())
+                }),
+                None => Err(anyhow::anyhow!("REPEAT without a command before it")),
+            },
+            "DEFINE" => it.define(arg),
+            _ => {
+                let result = it.line(&line);
+                last = Some(line.clone());
+                result
+            }
+        };
+        result.with_context(|| format!("line {}: {}", n + 1, raw.trim()))?;
+    }
+    // A payload that ends mid-HOLD must not leave keys down on the host
+    for usage in std::mem::take(&mut it.held).into_iter().rev() {
+        it.push(Op::Key(KeyEvent::Up(usage)))?;
+    }
+    Ok(it.ops)
+}
+
+/// One keyboard report as the host would receive it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// Time since the payload started
+    pub at: Duration,
+    pub event: KeyEvent,
+    pub bytes: [u8; 9],
+}
+
+/// The keyboard reports a compiled payload produces, for dry runs
+pub fn reports(ops: &[Op]) -> Vec<Report> {
+    let mut at = Duration::ZERO;
+    let mut mods = 0u8;
+    let mut pressed = BTreeSet::new();
+    let mut out = Vec::new();
+    for op in ops {
+        let event = match *op {
+            Op::Wait(d) => {
+                at += d;
+                continue;
+            }
+            Op::Key(event) => event,
+            Op::Mouse { .. } => continue,
+        };
+        match (event, keyboard_usage_to_modifier(event_usage(event))) {
+            (KeyEvent::Down(_), Some(bit)) => mods |= bit,
+            (KeyEvent::Up(_), Some(bit)) => mods &= !bit,
+            (KeyEvent::Down(u), None) => {
+                pressed.insert(u);
+            }
+            (KeyEvent::Up(u), None) => {
+                pressed.remove(&u);
+            }
+        }
+        out.push(Report {
+            at,
+            event,
+            bytes: build_keyboard_report(mods, &pressed),
+        });
+    }
+    out
+}
+
+fn event_usage(event: KeyEvent) -> u8 {
+    match event {
+        KeyEvent::Down(u) | KeyEvent::Up(u) => u,
+    }
+}
+
+/// Command word and the rest of the line; STRING keeps its argument verbatim
+fn split_command(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    match line.split_once(' ') {
+        Some((cmd, arg)) => (cmd, arg),
+        None => (line.trim_end(), ""),
+    }
+}
+
+struct Interp<'a> {
+    keymap: &'a Keymap,
+    timing: Timing,
+    default_delay: Duration,
+    string_delay: Option<Duration>,
+    /// DEFINE names and their replacements, longest name first
+    defines: Vec<(String, String)>,
+    vars: HashMap<String, i64>,
+    /// Keys pressed by HOLD, in press order
+    held: Vec<u8>,
+    ops: Vec<Op>,
+}
+
+impl Interp<'_> {
+    /// Run one command line other than REM, REPEAT and DEFINE
+    fn line(&mut self, line: &str) -> anyhow::Result<()> {
+        let (cmd, arg) = split_command(line);
+        match cmd {
+            "STRING" => self.string(arg)?,
+            "STRINGLN" => {
+                self.string(arg)?;
+                self.tap(&[ENTER])?;
+            }
+            "DELAY" => {
+                let ms = self.eval(arg)?;
+                self.wait(millis(ms))?;
+            }
+            "DEFAULT_DELAY" | "DEFAULTDELAY" => {
+                self.default_delay = millis(self.eval(arg)?);
+                return Ok(());
+            }
+            "STRING_DELAY" | "STRINGDELAY" => {
+                self.string_delay = Some(millis(self.eval(arg)?));
+                return Ok(());
+            }
+            "VAR" => {
+                let (name, expr) = arg.split_once('=').context("expected VAR $name = value")?;
+                let name = var_name(name)?;
+                let value = self.eval(expr)?;
+                self.vars.insert(name, value);
+                return Ok(());
+            }
+            "HOLD" => {
+                for usage in keys(arg)? {
+                    if !self.held.contains(&usage) {
+                        self.held.push(usage);
+                        self.push(Op::Key(KeyEvent::Down(usage)))?;
+                    }
+                }
+            }
+            "RELEASE" => {
+                for usage in keys(arg)?.into_iter().rev() {
+                    if let Some(i) = self.held.iter().position(|&u| u == usage) {
+                        self.held.remove(i);
+                        self.push(Op::Key(KeyEvent::Up(usage)))?;
+                    }
+                }
+            }
+            c if UNSUPPORTED.contains(&c) => anyhow::bail!("{c} is not supported"),
+            c if c.starts_with('$') => {
+                let (name, expr) = line.split_once('=').context("expected $name = value")?;
+                let name = var_name(name)?;
+                anyhow::ensure!(self.vars.contains_key(&name), "${name} is not declared");
+                let value = self.eval(expr)?;
+                self.vars.insert(name, value);
+                return Ok(());
+            }
+            _ => self.tap(&keys(line)?)?,
+        }
+        self.wait(self.default_delay)
+    }
+
+    fn define(&mut self, arg: &str) -> anyhow::Result<()> {
+        let (name, value) = arg.trim_start().split_once(' ').unwrap_or((arg.trim(), ""));
+        anyhow::ensure!(!name.is_empty(), "expected DEFINE NAME value");
+        self.defines.retain(|(n, _)| n != name);
+        self.defines.push((name.to_string(), value.to_string()));
+        self.defines
+            .sort_by_key(|(n, _)| std::cmp::Reverse(n.len()));
+        Ok(())
+    }
+
+    /// Apply DEFINEs to a line; DEFINE lines themselves are left alone so names can be redefined
+    fn substitute(&self, line: &str) -> String {
+        if self.defines.is_empty() || split_command(line).0 == "DEFINE" {
+            return line.to_string();
+        }
+        self.defines
+            .iter()
+            .fold(line.to_string(), |line, (name, value)| {
+                line.replace(name, value)
+            })
+    }
+
+    fn string(&mut self, text: &str) -> anyhow::Result<()> {
+        let typed = self.keymap.type_text(text);
+        if !typed.unmappable.is_empty() {
+            let chars: String = typed.unmappable.iter().map(|&(_, c)| c).collect();
+            anyhow::bail!("cannot type {chars:?} with this layout");
+        }
+        let timing = Timing {
+            hold: self.timing.hold,
+            gap: self.string_delay.unwrap_or(self.timing.gap),
+        };
+        for (ev, wait) in typed.events(&timing) {
+            self.push(Op::Key(ev))?;
+            self.wait(wait)?;
+        }
+        Ok(())
+    }
+
+    /// Press in order, release in reverse; keys held by HOLD stay down
+    fn tap(&mut self, chord: &[u8]) -> anyhow::Result<()> {
+        let fresh: Vec<u8> = chord
+            .iter()
+            .copied()
+            .filter(|u| !self.held.contains(u))
+            .collect();
+        for &u in &fresh {
+            self.push(Op::Key(KeyEvent::Down(u)))?;
+        }
+        self.wait(self.timing.hold)?;
+        for &u in fresh.iter().rev() {
+            self.push(Op::Key(KeyEvent::Up(u)))?;
+        }
+        self.wait(self.timing.gap)
+    }
+
+    fn wait(&mut self, d: Duration) -> anyhow::Result<()> {
+        if d.is_zero() {
+            return Ok(());
+        }
+        // Back-to-back waits merge so dry runs and playback see one pause
+        if let Some(Op::Wait(prev)) = self.ops.last_mut() {
+            *prev += d;
+            return Ok(());
+        }
+        self.push(Op::Wait(d))
+    }
+
+    fn push(&mut self, op: Op) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.ops.len() < MAX_OPS,
+            "payload expands to more than {MAX_OPS} steps"
+        );
+        self.ops.push(op);
+        Ok(())
+    }
+
+    fn eval(&self, expr: &str) -> anyhow::Result<i64> {
+        let tokens = tokenize(expr)?;
+        let mut p = Parser {
+            tokens: &tokens,
+            pos: 0,
+            vars: &self.vars,
+        };
+        let value = p.sum()?;
+        anyhow::ensure!(
+            p.pos == tokens.len(),
+            "unexpected text in '{}'",
+            expr.trim()
+        );
+        Ok(value)
+    }
+}
+
+fn millis(ms: i64) -> Duration {
+    Duration::from_millis(ms.max(0) as u64)
+}
+
+fn var_name(text: &str) -> anyhow::Result<String> {
+    let name = text.trim();
+    let bare = name
+        .strip_prefix('$')
+        .with_context(|| format!("variable '{name}' must start with $"))?;
+    anyhow::ensure!(
+        !bare.is_empty() && bare.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        "bad variable name '{name}'"
+    );
+    Ok(bare.to_string())
+}
+
+/// Usages for a key line; tokens are space separated, with `CTRL-ALT` style combos split on '-'
+fn keys(line: &str) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    for token in line.split_whitespace() {
+        match key(token) {
+            Some(usage) => out.push(usage),
+            None if token.len() > 1 && token.contains('-') => {
+                for part in token.split('-') {
+                    out.push(key(part).with_context(|| format!("unknown key '{part}'"))?);
+                }
+            }
+            None => anyhow::bail!("unknown command or key '{token}'"),
+        }
+    }
+    anyhow::ensure!(!out.is_empty(), "no keys given");
+    Ok(out)
+}
+
+fn key(name: &str) -> Option<u8> {
+    let lower = name.to_ascii_lowercase();
+    DUCKY_KEYS
+        .iter()
+        .find(|(n, _)| *n == lower)
+        .map(|&(_, u)| u)
+        .or_else(|| usage_from_name(name))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(i64),
+    Var(String),
+    Op(char),
+}
+
+fn tokenize(expr: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() {
+            let mut n: i64 = 0;
+            while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                n = n
+                    .checked_mul(10)
+                    .and_then(|n| n.checked_add(d as i64))
+                    .context("number too large")?;
+                chars.next();
+            }
+            tokens.push(Token::Num(n));
+        } else if c == '$' {
+            chars.next();
+            let mut name = String::new();
+            while let Some(&c) = chars
+                .peek()
+                .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+            {
+                name.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Var(name));
+        } else if "+-*/%()".contains(c) {
+            tokens.push(Token::Op(c));
+            chars.next();
+        } else {
+            anyhow::bail!("unexpected '{c}' in '{}'", expr.trim());
+        }
+    }
+    Ok(tokens)
+}
+
+/// Integer expressions with the usual precedence
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    vars: &'a HashMap<String, i64>,
+}
+
+impl Parser<'_> {
+    fn next_op(&mut self, ops: &str) -> Option<char> {
+        match self.tokens.get(self.pos) {
+            Some(&Token::Op(c)) if ops.contains(c) => {
+                self.pos += 1;
+                Some(c)
+            }
+            _ => None,
+        }
+    }
+
+    fn sum(&mut self) -> anyhow::Result<i64> {
+        let mut value = self.product()?;
+        while let Some(op) = self.next_op("+-") {
+            let rhs = self.product()?;
+            value = match op {
+                '+' => value.checked_add(rhs),
+                _ => value.checked_sub(rhs),
+            }
+            .context("arithmetic overflow")?;
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> anyhow::Result<i64> {
+        let mut value = self.unary()?;
+        while let Some(op) = self.next_op("*/%") {
+            let rhs = self.unary()?;
+            value = match op {
+                '*' => value.checked_mul(rhs).context("arithmetic overflow")?,
+                '/' => value.checked_div(rhs).context("division by zero")?,
+                _ => value.checked_rem(rhs).context("division by zero")?,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> anyhow::Result<i64> {
+        if self.next_op("-").is_some() {
+            return self.unary()?.checked_neg().context("arithmetic overflow");
+        }
+        match self.tokens.get(self.pos).cloned() {
+            Some(Token::Num(n)) => {
+                self.pos += 1;
+                Ok(n)
+            }
+            Some(Token::Var(name)) => {
+                self.pos += 1;
+                self.vars
+                    .get(&name)
+                    .copied()
+                    .with_context(|| format!("${name} is not declared"))
+            }
+            Some(Token::Op('(')) => {
+                self.pos += 1;
+                let value = self.sum()?;
+                anyhow::ensure!(self.next_op(")").is_some(), "missing ')'");
+                Ok(value)
+            }
+            _ => anyhow::bail!("expected a number"),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+// Linux evdev input source: forwards attached keyboards and mice, optionally grabbed exclusively
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use evdev::{Device, EventStream, EventSummary, KeyCode, RelativeAxisCode, SynchronizationCode};
+use tokio::task::JoinHandle;
+
+use crate::consts::{BUTTON_LEFT, BUTTON_MIDDLE, BUTTON_RIGHT, EVDEV_RESCAN_MS};
+use crate::hid::{linux_keycode_to_consumer, linux_keycode_to_hid};
+use crate::input::InputTx;
+use crate::settings::ProfileSpeed;
+use crate::ui::AppCmd;
+
+/// One `--evdev` selector
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceFilter {
+    /// `*`: every keyboard or mouse
+    Any,
+    /// `vvvv:pppp` in hex
+    Id { vendor: u16, product: u16 },
+    /// Case-insensitive substring of the device name
+    Name(String),
+}
+
+impl DeviceFilter {
+    pub fn parse(s: &str) -> Self {
+        if s == "*" {
+            return Self::Any;
+        }
+        if let Some((v, p)) = s.split_once(':')
+            && v.len() == 4
+            && p.len() == 4
+            && let (Ok(vendor), Ok(product)) =
+                (u16::from_str_radix(v, 16), u16::from_str_radix(p, 16))
+        {
+            return Self::Id { vendor, product };
+        }
+        Self::Name(s.to_lowercase())
+    }
+
+    fn matches(&self, dev: &Device) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Id { vendor, product } => {
+                let id = dev.input_id();
+                id.vendor() == *vendor && id.product() == *product
+            }
+            Self::Name(needle) => dev
+                .name()
+                .is_some_and(|n| n.to_lowercase().contains(needle.as_str())),
+        }
+    }
+}
+
+pub struct EvdevOptions {
+    pub filters: Vec<DeviceFilter>,
+    /// Take EVIOCGRAB so the local console and compositor stop seeing the devices
+    pub grab: bool,
+    pub speed: ProfileSpeed,
+}
+
+/// Attach to matching devices and keep rescanning `/dev/input` for hot-plugged ones
+pub async fn run(input: InputTx, opts: EvdevOptions) {
+    let mut attached: HashMap<PathBuf, JoinHandle<()>> = HashMap::new();
+    let mut rescan = tokio::time::interval(Duration::from_millis(EVDEV_RESCAN_MS));
+    let mut warned_empty = false;
+    loop {
+        rescan.tick().await;
+        attached.retain(|path, task| {
+            let alive = !task.is_finished();
+            if !alive {
+                tracing::info!(path = %path.display(), "evdev device detached");
+            }
+            alive
+        });
+
+        for (path, mut dev) in evdev::enumerate() {
+            if attached.contains_key(&path)
+                || !is_keyboard_or_mouse(&dev)
+                || !opts.filters.iter().any(|f| f.matches(&dev))
+            {
+                continue;
+            }
+            let name = dev.name().unwrap_or("unnamed").to_string();
+            let id = dev.input_id();
+            if opts.grab
+                && let Err(e) = dev.grab()
+            {
+                tracing::warn!(%name, error = %e, "evdev grab failed, forwarding without it");
+            }
+            match dev.into_event_stream() {
+                Ok(events) => {
+                    tracing::info!(
+                        path = %path.display(),
+                        %name,
+                        id = %format!("{:04x}:{:04x}", id.vendor(), id.product()),
+                        grabbed = events.device().is_grabbed(),
+                        "evdev device attached"
+                    );
+                    let task = tokio::spawn(forward(
+                        events,
+                        name,
+                        input.new_source(),
+                        opts.speed.clone(),
+                    ));
+                    attached.insert(path, task);
+                }
+                Err(e) => tracing::warn!(%name, error = %e, "evdev open failed"),
+            }
+        }
+
+        if attached.is_empty() && !warned_empty {
+            tracing::warn!(
+                "No matching evdev devices yet (check filters and /dev/input permissions)"
+            );
+        }
+        warned_empty = attached.is_empty();
+    }
+}
+
+fn is_keyboard_or_mouse(dev: &Device) -> bool {
+    dev.supported_keys()
+        .is_some_and(|k| k.contains(KeyCode::KEY_A))
+        || dev
+            .supported_relative_axes()
+            .is_some_and(|r| r.contains(RelativeAxisCode::REL_X))
+}
+
+fn button_bit(code: KeyCode) -> Option<u8> {
+    match code {
+        KeyCode::BTN_LEFT => Some(BUTTON_LEFT),
+        KeyCode::BTN_MIDDLE => Some(BUTTON_MIDDLE),
+        KeyCode::BTN_RIGHT => Some(BUTTON_RIGHT),
+        _ => None,
+    }
+}
+
+/// Translate one device's events until it goes away, then release whatever it held
+async fn forward(mut events: EventStream, name: String, input: InputTx, speed: ProfileSpeed) {
+    let mut held = Held::default();
+    let mut buttons = 0u8;
+    if let Err(e) = pump(&mut events, &input, &speed, &mut held, &mut buttons).await {
+        tracing::debug!(%name, error = %e, "evdev device read ended");
+    }
+    for usage in held.keys {
+        let _ = input.send(AppCmd::KeyUp(usage));
+    }
+    for usage in held.consumer {
+        let _ = input.send(AppCmd::ConsumerUp(usage));
+    }
+    if buttons != 0 {
+        let _ = input.send(AppCmd::Mouse {
+            buttons: 0,
+            dx: 0.0,
+            dy: 0.0,
+            wheel: 0,
+            pan: 0,
+        });
+    }
+}
+
+/// Keys and media keys a device holds down
+#[derive(Default)]
+struct Held {
+    keys: BTreeSet<u8>,
+    consumer: BTreeSet<u16>,
+}
+
+async fn pump(
+    events: &mut EventStream,
+    input: &InputTx,
+    speed: &ProfileSpeed,
+    held: &mut Held,
+    buttons: &mut u8,
+) -> anyhow::Result<()> {
+    // Pointer state accumulates until SYN_REPORT closes the frame
+    let mut frame_buttons = *buttons;
+    let (mut dx, mut dy, mut wheel, mut pan) = (0i32, 0i32, 0i32, 0i32);
+    loop {
+        match events.next_event().await?.destructure() {
+            EventSummary::Key(_, code, value) => {
+                if let Some(bit) = button_bit(code) {
+                    if value == 0 {
+                        frame_buttons &= !bit;
+                    } else {
+                        frame_buttons |= bit;
+                    }
+                } else if let Some(usage) = linux_keycode_to_hid(code.code()) {
+                    // 2 is kernel autorepeat; the host repeats on its own
+                    match value {
+                        1 if held.keys.insert(usage) => input.send(AppCmd::KeyDown(usage))?,
+                        0 if held.keys.remove(&usage) => input.send(AppCmd::KeyUp(usage))?,
+                        _ => {}
+                    }
+                } else if let Some(usage) = linux_keycode_to_consumer(code.code()) {
+                    match value {
+                        1 if held.consumer.insert(usage) => {
+                            input.send(AppCmd::ConsumerDown(usage))?
+                        }
+                        0 if held.consumer.remove(&usage) => {
+                            input.send(AppCmd::ConsumerUp(usage))?
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            EventSummary::RelativeAxis(_, RelativeAxisCode::REL_X, v) => dx += v,
+            EventSummary::RelativeAxis(_, RelativeAxisCode::REL_Y, v) => dy += v,
+            EventSummary::RelativeAxis(_, RelativeAxisCode::REL_WHEEL, v) => wheel += v,
+            EventSummary::RelativeAxis(_, RelativeAxisCode::REL_HWHEEL, v) => pan += v,
+            EventSummary::Synchronization(_, SynchronizationCode::SYN_REPORT, _)
+                if frame_buttons != *buttons || dx != 0 || dy != 0 || wheel != 0 || pan != 0 =>
+            {
+                *buttons = frame_buttons;
+                let speed = speed.get();
+                input.send(AppCmd::Mouse {
+                    buttons: frame_buttons,
+                    dx: dx as f32 * speed,
+                    dy: dy as f32 * speed,
+                    wheel: wheel.clamp(i8::MIN as i32, i8::MAX as i32) as i8,
+                    pan: pan.clamp(i8::MIN as i32, i8::MAX as i32) as i8,
+                })?;
+                (dx, dy, wheel, pan) = (0, 0, 0, 0);
+            }
+            _ => {}
+        }
+    }
+}
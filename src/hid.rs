@@ -125,6 +125,145 @@ pub fn keycode_to_hid(code: KeyCode) -> Option<u8> {
     })
 }
 
+/// Linux input event code (`KEY_*` from input-event-codes.h) to HID keyboard usage
+pub fn linux_keycode_to_hid(code: u16) -> Option<u8> {
+    Some(match code {
+        1 => 0x29,                           // KEY_ESC
+        2 => 0x1E,                           // KEY_1
+        3 => 0x1F,                           // KEY_2
+        4 => 0x20,                           // KEY_3
+        5 => 0x21,                           // KEY_4
+        6 => 0x22,                           // KEY_5
+        7 => 0x23,                           // KEY_6
+        8 => 0x24,                           // KEY_7
+        9 => 0x25,                           // KEY_8
+        10 => 0x26,                          // KEY_9
+        11 => 0x27,                          // KEY_0
+        12 => 0x2D,                          // KEY_MINUS
+        13 => 0x2E,                          // KEY_EQUAL
+        14 => 0x2A,                          // KEY_BACKSPACE
+        15 => 0x2B,                          // KEY_TAB
+        16 => 0x14,                          // KEY_Q
+        17 => 0x1A,                          // KEY_W
+        18 => 0x08,                          // KEY_E
+        19 => 0x15,                          // KEY_R
+        20 => 0x17,                          // KEY_T
+        21 => 0x1C,                          // KEY_Y
+        22 => 0x18,                          // KEY_U
+        23 => 0x0C,                          // KEY_I
+        24 => 0x12,                          // KEY_O
+        25 => 0x13,                          // KEY_P
+        26 => 0x2F,                          // KEY_LEFTBRACE
+        27 => 0x30,                          // KEY_RIGHTBRACE
+        28 => 0x28,                          // KEY_ENTER
+        29 => 0xE0,                          // KEY_LEFTCTRL
+        30 => 0x04,                          // KEY_A
+        31 => 0x16,                          // KEY_S
+        32 => 0x07,                          // KEY_D
+        33 => 0x09,                          // KEY_F
+        34 => 0x0A,                          // KEY_G
+        35 => 0x0B,                          // KEY_H
+        36 => 0x0D,                          // KEY_J
+        37 => 0x0E,                          // KEY_K
+        38 => 0x0F,                          // KEY_L
+        39 => 0x33,                          // KEY_SEMICOLON
+        40 => 0x34,                          // KEY_APOSTROPHE
+        41 => 0x35,                          // KEY_GRAVE
+        42 => 0xE1,                          // KEY_LEFTSHIFT
+        43 => 0x31,                          // KEY_BACKSLASH
+        44 => 0x1D,                          // KEY_Z
+        45 => 0x1B,                          // KEY_X
+        46 => 0x06,                          // KEY_C
+        47 => 0x19,                          // KEY_V
+        48 => 0x05,                          // KEY_B
+        49 => 0x11,                          // KEY_N
+        50 => 0x10,                          // KEY_M
+        51 => 0x36,                          // KEY_COMMA
+        52 => 0x37,                          // KEY_DOT
+        53 => 0x38,                          // KEY_SLASH
+        54 => 0xE5,                          // KEY_RIGHTSHIFT
+        55 => 0x55,                          // KEY_KPASTERISK
+        56 => 0xE2,                          // KEY_LEFTALT
+        57 => 0x2C,                          // KEY_SPACE
+        58 => 0x39,                          // KEY_CAPSLOCK
+        59..=68 => 0x3A + (code - 59) as u8, // KEY_F1..KEY_F10
+        69 => 0x53,                          // KEY_NUMLOCK
+        70 => 0x47,                          // KEY_SCROLLLOCK
+        71 => 0x5F,                          // KEY_KP7
+        72 => 0x60,                          // KEY_KP8
+        73 => 0x61,                          // KEY_KP9
+        74 => 0x56,                          // KEY_KPMINUS
+        75 => 0x5C,                          // KEY_KP4
+        76 => 0x5D,                          // KEY_KP5
+        77 => 0x5E,                          // KEY_KP6
+        78 => 0x57,                          // KEY_KPPLUS
+        79 => 0x59,                          // KEY_KP1
+        80 => 0x5A,                          // KEY_KP2
+        81 => 0x5B,                          // KEY_KP3
+        82 => 0x62,                          // KEY_KP0
+        83 => 0x63,                          // KEY_KPDOT
+        86 => 0x64,                          // KEY_102ND
+        87 => 0x44,                          // KEY_F11
+        88 => 0x45,                          // KEY_F12
+        96 => 0x58,                          // KEY_KPENTER
+        97 => 0xE4,                          // KEY_RIGHTCTRL
+        98 => 0x54,                          // KEY_KPSLASH
+        99 => 0x46,                          // KEY_SYSRQ
+        100 => 0xE6,                         // KEY_RIGHTALT
+        102 => 0x4A,                         // KEY_HOME
+        103 => 0x52,                         // KEY_UP
+        104 => 0x4B,                         // KEY_PAGEUP
+        105 => 0x50,                         // KEY_LEFT
+        106 => 0x4F,                         // KEY_RIGHT
+        107 => 0x4D,                         // KEY_END
+        108 => 0x51,                         // KEY_DOWN
+        109 => 0x4E,                         // KEY_PAGEDOWN
+        110 => 0x49,                         // KEY_INSERT
+        111 => 0x4C,                         // KEY_DELETE
+        119 => 0x48,                         // KEY_PAUSE
+        125 => 0xE3,                         // KEY_LEFTMETA
+        126 => 0xE7,                         // KEY_RIGHTMETA
+        127 => 0x65,                         // KEY_COMPOSE
+        _ => return None,
+    })
+}
+
+/// Media and system keys from the window, as Consumer page usages
+pub fn keycode_to_consumer(code: KeyCode) -> Option<u16> {
+    use KeyCode::*;
+    Some(match code {
+        AudioVolumeMute => 0xE2,
+        AudioVolumeUp => 0xE9,
+        AudioVolumeDown => 0xEA,
+        MediaPlayPause => 0xCD,
+        MediaStop => 0xB7,
+        MediaTrackNext => 0xB5,
+        MediaTrackPrevious => 0xB6,
+        BrowserHome => 0x223,
+        BrowserBack => 0x224,
+        _ => return None,
+    })
+}
+
+/// Linux input event code to Consumer page usage, for media keys
+pub fn linux_keycode_to_consumer(code: u16) -> Option<u16> {
+    Some(match code {
+        113 => 0xE2,  // KEY_MUTE
+        114 => 0xEA,  // KEY_VOLUMEDOWN
+        115 => 0xE9,  // KEY_VOLUMEUP
+        116 => 0x30,  // KEY_POWER
+        158 => 0x224, // KEY_BACK
+        163 => 0xB5,  // KEY_NEXTSONG
+        164 => 0xCD,  // KEY_PLAYPAUSE
+        165 => 0xB6,  // KEY_PREVIOUSSONG
+        166 => 0xB7,  // KEY_STOPCD
+        172 => 0x223, // KEY_HOMEPAGE
+        224 => 0x70,  // KEY_BRIGHTNESSDOWN
+        225 => 0x6F,  // KEY_BRIGHTNESSUP
+        _ => return None,
+    })
+}
+
 pub fn keyboard_usage_to_modifier(usage: u8) -> Option<u8> {
     match usage {
         0xE0 => Some(1 << 0), // LCtrl
@@ -139,8 +278,199 @@ pub fn keyboard_usage_to_modifier(usage: u8) -> Option<u8> {
     }
 }
 
-pub fn build_mouse_report(buttons: u8, dx: i8, dy: i8, wheel: i8) -> [u8; 5] {
-    [RID_MOUSE, buttons, dx as u8, dy as u8, wheel as u8]
+// Named keys; the first name listed for a usage is the one `usage_name` reports
+const KEY_NAMES: &[(&str, u8)] = &[
+    ("enter", 0x28),
+    ("return", 0x28),
+    ("esc", 0x29),
+    ("escape", 0x29),
+    ("backspace", 0x2A),
+    ("bksp", 0x2A),
+    ("tab", 0x2B),
+    ("space", 0x2C),
+    ("spc", 0x2C),
+    ("minus", 0x2D),
+    ("equal", 0x2E),
+    ("lbracket", 0x2F),
+    ("rbracket", 0x30),
+    ("backslash", 0x31),
+    ("nonus", 0x32),
+    ("semicolon", 0x33),
+    ("quote", 0x34),
+    ("grave", 0x35),
+    ("comma", 0x36),
+    ("period", 0x37),
+    ("slash", 0x38),
+    ("capslock", 0x39),
+    ("caps", 0x39),
+    ("printscreen", 0x46),
+    ("prtsc", 0x46),
+    ("scrolllock", 0x47),
+    ("pause", 0x48),
+    ("insert", 0x49),
+    ("ins", 0x49),
+    ("home", 0x4A),
+    ("pageup", 0x4B),
+    ("pgup", 0x4B),
+    ("delete", 0x4C),
+    ("del", 0x4C),
+    ("end", 0x4D),
+    ("pagedown", 0x4E),
+    ("pgdn", 0x4E),
+    ("right", 0x4F),
+    ("left", 0x50),
+    ("down", 0x51),
+    ("up", 0x52),
+    ("numlock", 0x53),
+    ("menu", 0x65),
+    ("app", 0x65),
+    ("ctrl", 0xE0),
+    ("control", 0xE0),
+    ("lctrl", 0xE0),
+    ("shift", 0xE1),
+    ("lshift", 0xE1),
+    ("alt", 0xE2),
+    ("lalt", 0xE2),
+    ("option", 0xE2),
+    ("gui", 0xE3),
+    ("super", 0xE3),
+    ("win", 0xE3),
+    ("cmd", 0xE3),
+    ("meta", 0xE3),
+    ("lgui", 0xE3),
+    ("rctrl", 0xE4),
+    ("rshift", 0xE5),
+    ("ralt", 0xE6),
+    ("altgr", 0xE6),
+    ("rgui", 0xE7),
+];
+
+/// Look up a key by name, e.g. "a", "f5", "enter", "ctrl", "ralt", or a raw usage like "0x87".
+/// Case-insensitive.
+pub fn usage_from_name(name: &str) -> Option<u8> {
+    let n = name.trim().to_ascii_lowercase();
+    if let Some(hex) = n.strip_prefix("0x") {
+        return u8::from_str_radix(hex, 16).ok();
+    }
+    let b = n.as_bytes();
+    if b.len() == 1 {
+        return match b[0] {
+            c @ b'a'..=b'z' => Some(0x04 + (c - b'a')),
+            b'0' => Some(0x27),
+            c @ b'1'..=b'9' => Some(0x1E + (c - b'1')),
+            b'-' => Some(0x2D),
+            b'=' => Some(0x2E),
+            b'[' => Some(0x2F),
+            b']' => Some(0x30),
+            b'\\' => Some(0x31),
+            b';' => Some(0x33),
+            b'\'' => Some(0x34),
+            b'`' => Some(0x35),
+            b',' => Some(0x36),
+            b'.' => Some(0x37),
+            b'/' => Some(0x38),
+            _ => None,
+        };
+    }
+    if let Some(num) = n.strip_prefix('f').and_then(|d| d.parse::<u8>().ok()) {
+        return match num {
+            1..=12 => Some(0x3A + num - 1),
+            13..=24 => Some(0x68 + num - 13),
+            _ => None,
+        };
+    }
+    KEY_NAMES.iter().find(|(k, _)| *k == n).map(|&(_, u)| u)
+}
+
+/// Short display name for a usage; the inverse of `usage_from_name`
+pub fn usage_name(usage: u8) -> String {
+    match usage {
+        0x04..=0x1D => ((b'a' + usage - 0x04) as char).to_string(),
+        0x1E..=0x26 => ((b'1' + usage - 0x1E) as char).to_string(),
+        0x27 => "0".to_owned(),
+        0x3A..=0x45 => format!("f{}", usage - 0x3A + 1),
+        0x68..=0x73 => format!("f{}", usage - 0x68 + 13),
+        _ => KEY_NAMES
+            .iter()
+            .find(|&&(_, u)| u == usage)
+            .map(|&(k, _)| k.to_owned())
+            .unwrap_or_else(|| format!("{usage:#04x}")),
+    }
+}
+
+/// Mouse buttons by name, in bit order
+pub const BUTTON_NAMES: &[(&str, u8)] = &[
+    ("left", BUTTON_LEFT),
+    ("middle", BUTTON_MIDDLE),
+    ("right", BUTTON_RIGHT),
+];
+
+/// Look up a mouse button bit by name: "left", "middle" or "right". Case-insensitive.
+pub fn button_from_name(name: &str) -> Option<u8> {
+    BUTTON_NAMES
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name.trim()))
+        .map(|&(_, bit)| bit)
+}
+
+// Consumer page controls; the first name listed for a usage is the one `consumer_name` reports
+const CONSUMER_NAMES: &[(&str, u16)] = &[
+    ("power", 0x30),
+    ("menu", 0x40),
+    ("brightness_up", 0x6F),
+    ("brightness_down", 0x70),
+    ("next_track", 0xB5),
+    ("next", 0xB5),
+    ("prev_track", 0xB6),
+    ("previous", 0xB6),
+    ("stop", 0xB7),
+    ("play_pause", 0xCD),
+    ("play", 0xCD),
+    ("mute", 0xE2),
+    ("volume_up", 0xE9),
+    ("vol_up", 0xE9),
+    ("volume_down", 0xEA),
+    ("vol_down", 0xEA),
+    ("home", 0x223),
+    ("back", 0x224),
+];
+
+/// Look up a consumer control by name, e.g. "volume_up", "play_pause", "home". Case-insensitive.
+pub fn consumer_from_name(name: &str) -> Option<u16> {
+    let n = name.trim().to_ascii_lowercase();
+    CONSUMER_NAMES
+        .iter()
+        .find(|(k, _)| *k == n)
+        .map(|&(_, u)| u)
+}
+
+/// Display name for a consumer usage; the inverse of `consumer_from_name`
+pub fn consumer_name(usage: u16) -> String {
+    CONSUMER_NAMES
+        .iter()
+        .find(|&&(_, u)| u == usage)
+        .map(|&(k, _)| k.to_owned())
+        .unwrap_or_else(|| format!("{usage:#05x}"))
+}
+
+/// Parse a chord like "ctrl+alt+t" into usages, in press order
+pub fn parse_chord(chord: &str) -> Option<Vec<u8>> {
+    let usages = chord
+        .split('+')
+        .map(usage_from_name)
+        .collect::<Option<Vec<u8>>>()?;
+    (!usages.is_empty()).then_some(usages)
+}
+
+pub fn build_mouse_report(buttons: u8, dx: i8, dy: i8, wheel: i8, pan: i8) -> [u8; 6] {
+    [
+        RID_MOUSE,
+        buttons,
+        dx as u8,
+        dy as u8,
+        wheel as u8,
+        pan as u8,
+    ]
 }
 
 pub fn build_keyboard_report(mods: u8, pressed: &BTreeSet<u8>) -> [u8; 9] {
@@ -154,8 +484,37 @@ pub fn build_keyboard_report(mods: u8, pressed: &BTreeSet<u8>) -> [u8; 9] {
     out
 }
 
-// Single Input Report characteristic carrying both mouse and keyboard via Report IDs
+/// Bitmap keyboard report: the modifier byte, then one bit per usage up to `NKRO_MAX_USAGE`.
+/// Higher usages are left out; they go in the 6KRO report.
+pub fn build_nkro_report(mods: u8, pressed: &BTreeSet<u8>) -> [u8; 18] {
+    let mut out = [0u8; 18];
+    out[0] = RID_NKRO;
+    out[1] = mods;
+    for &k in pressed.range(..=NKRO_MAX_USAGE) {
+        out[2 + (k / 8) as usize] |= 1 << (k % 8);
+    }
+    out
+}
+
+/// One consumer control held, or 0 for none
+pub fn build_consumer_report(usage: u16) -> [u8; 3] {
+    let [lo, hi] = usage.to_le_bytes();
+    [RID_CONSUMER, lo, hi]
+}
+
+/// Absolute pointer position, each axis 0..=`ABSOLUTE_MAX` across the host screen
+pub fn build_absolute_report(buttons: u8, x: u16, y: u16) -> [u8; 6] {
+    let [x0, x1] = x.min(ABSOLUTE_MAX).to_le_bytes();
+    let [y0, y1] = y.min(ABSOLUTE_MAX).to_le_bytes();
+    [RID_ABSOLUTE, buttons, x0, x1, y0, y1]
+}
+
+// Single Input Report characteristic carrying every report via Report IDs. All report types are
+// declared up front, so `[reports]` can switch them on and off without re-registering GATT.
+
 pub fn build_hid_service() -> (Service, Uuid) {
+    // One item per line; 3-byte items would otherwise make rustfmt put every byte on its own
+    #[rustfmt::skip]
     let report_map: Vec<u8> = vec![
         // ----- Mouse, Report ID 1 -----
         0x05, 0x01, // Usage Page (Generic Desktop)
@@ -184,6 +543,10 @@ pub fn build_hid_service() -> (Service, Uuid) {
         0x75, 0x08, //     Report Size (8)
         0x95, 0x03, //     Report Count (3)
         0x81, 0x06, //     Input (Data,Var,Rel)
+        0x05, 0x0C, //     Usage Page (Consumer)
+        0x0A, 0x38, 0x02, //     Usage (AC Pan)
+        0x95, 0x01, //     Report Count (1)
+        0x81, 0x06, //     Input (Data,Var,Rel)
         0xC0, //   End Collection
         0xC0, // End Collection
         // ----- Keyboard, Report ID 2 -----
@@ -206,13 +569,74 @@ pub fn build_hid_service() -> (Service, Uuid) {
         0x81, 0x03, //   Input (Const,Var,Abs)
         // 6 Keycode array
         0x15, 0x00, //   Logical Minimum (0)
-        0x25, 0x65, //   Logical Maximum (101)
+        0x26, 0xE7, 0x00, //   Logical Maximum (231), two bytes as one would read as -25
         0x19, 0x00, //   Usage Minimum (0)
-        0x29, 0x65, //   Usage Maximum (101)
+        0x29, 0xE7, //   Usage Maximum (231)
         0x75, 0x08, //   Report Size (8)
         0x95, 0x06, //   Report Count (6)
         0x81, 0x00, //   Input (Data,Array)
         0xC0, // End Collection
+        // ----- Consumer control, Report ID 3 -----
+        0x05, 0x0C, // Usage Page (Consumer)
+        0x09, 0x01, // Usage (Consumer Control)
+        0xA1, 0x01, // Collection (Application)
+        0x85, RID_CONSUMER, //   Report ID (3)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x26, 0xFF, 0x03, //   Logical Maximum (1023)
+        0x19, 0x00, //   Usage Minimum (0)
+        0x2A, 0xFF, 0x03, //   Usage Maximum (1023)
+        0x75, 0x10, //   Report Size (16)
+        0x95, 0x01, //   Report Count (1)
+        0x81, 0x00, //   Input (Data,Array,Abs)
+        0xC0, // End Collection
+        // ----- NKRO keyboard, Report ID 4 -----
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x09, 0x06, // Usage (Keyboard)
+        0xA1, 0x01, // Collection (Application)
+        0x85, RID_NKRO, //   Report ID (4)
+        0x05, 0x07, //   Usage Page (Keyboard/Keypad)
+        // Modifier byte
+        0x19, 0xE0, //   Usage Minimum (Left Ctrl)
+        0x29, 0xE7, //   Usage Maximum (Right GUI)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x25, 0x01, //   Logical Maximum (1)
+        0x75, 0x01, //   Report Size (1)
+        0x95, 0x08, //   Report Count (8)
+        0x81, 0x02, //   Input (Data,Var,Abs)
+        // One bit per key
+        0x19, 0x00, //   Usage Minimum (0)
+        0x29, NKRO_MAX_USAGE, //   Usage Maximum (127)
+        0x95, NKRO_MAX_USAGE + 1, //   Report Count (128)
+        0x81, 0x02, //   Input (Data,Var,Abs)
+        0xC0, // End Collection
+        // ----- Absolute pointer, Report ID 5 -----
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x09, 0x02, // Usage (Mouse)
+        0xA1, 0x01, // Collection (Application)
+        0x85, RID_ABSOLUTE, //   Report ID (5)
+        0x09, 0x01, //   Usage (Pointer)
+        0xA1, 0x00, //   Collection (Physical)
+        0x05, 0x09, //     Usage Page (Buttons)
+        0x19, 0x01, //     Usage Minimum (Button 1)
+        0x29, 0x03, //     Usage Maximum (Button 3)
+        0x15, 0x00, //     Logical Minimum (0)
+        0x25, 0x01, //     Logical Maximum (1)
+        0x95, 0x03, //     Report Count (3)
+        0x75, 0x01, //     Report Size (1)
+        0x81, 0x02, //     Input (Data,Var,Abs)
+        0x95, 0x01, //     Report Count (1)
+        0x75, 0x05, //     Report Size (5)
+        0x81, 0x03, //     Input (Const,Var,Abs)
+        0x05, 0x01, //     Usage Page (Generic Desktop)
+        0x09, 0x30, //     Usage (X)
+        0x09, 0x31, //     Usage (Y)
+        0x15, 0x00, //     Logical Minimum (0)
+        0x26, 0xFF, 0x7F, //     Logical Maximum (32767)
+        0x75, 0x10, //     Report Size (16)
+        0x95, 0x02, //     Report Count (2)
+        0x81, 0x02, //     Input (Data,Var,Abs)
+        0xC0, //   End Collection
+        0xC0, // End Collection
     ];
 
     let input_uuid = Uuid::from_short(UUID_HID_REPORT);
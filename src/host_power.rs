@@ -26,10 +26,19 @@ fn linux_sysfs_battery_percent() -> Option<u8> {
         let p = entry.path();
         // Filter to type == Battery if possible
         let type_path = p.join("type");
-        let ty = fs::read_to_string(&type_path).ok().map(|s| s.trim().to_owned());
+        let ty = fs::read_to_string(&type_path)
+            .ok()
+            .map(|s| s.trim().to_owned());
         if let Some(t) = ty {
-            if t != "Battery" { continue; }
-        } else if !p.file_name().and_then(|n| n.to_str()).map(|n| n.starts_with("BAT")).unwrap_or(false) {
+            if t != "Battery" {
+                continue;
+            }
+        } else if !p
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.starts_with("BAT"))
+            .unwrap_or(false)
+        {
             continue;
         }
         // capacity
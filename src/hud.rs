@@ -0,0 +1,116 @@
+// On-window status display: link state, host LEDs, held keys/buttons, battery, capture, profile
+
+use crate::canvas::Canvas;
+use crate::consts::*;
+use crate::hid::usage_name;
+use crate::status::Status;
+
+pub const BG: u32 = 0x0014_161A;
+pub const FG: u32 = 0x00D8_DEE9;
+pub const DIM: u32 = 0x0055_5B66;
+const LABEL: u32 = 0x0081_8A99;
+const OK: u32 = 0x004C_C38A;
+pub const WARN: u32 = 0x00E5_C07B;
+pub const HOT: u32 = 0x00E0_6C75;
+
+const SCALE: i32 = 2;
+const PAD: i32 = 12;
+const LINE: i32 = 11 * SCALE;
+const VALUE_X: i32 = PAD + 10 * 6 * SCALE;
+
+pub struct HudView<'a> {
+    pub status: &'a Status,
+    pub device_name: &'a str,
+    pub capture_enabled: bool,
+    pub captured: bool,
+    pub capture_release: &'a [u8],
+}
+
+/// Draw the HUD from the top of the canvas; returns the y just below it
+pub fn draw(c: &mut Canvas, v: &HudView) -> i32 {
+    let s = v.status;
+    let mut y = PAD;
+
+    let x = c.text(PAD, y, SCALE, FG, "BLUPER");
+    c.text(
+        x + 2 * Canvas::advance(SCALE),
+        y,
+        SCALE,
+        LABEL,
+        v.device_name,
+    );
+    y += LINE + SCALE * 4;
+
+    row(c, y, "Adapter");
+    let x = flag(c, VALUE_X, y, "powered", s.powered, OK);
+    let x = flag(c, x, y, "advertising", s.advertising, OK);
+    flag(c, x, y, "connected", s.connected, OK);
+    y += LINE;
+
+    row(c, y, "LEDs");
+    if let Some(leds) = s.leds {
+        let x = flag(c, VALUE_X, y, "NUM", leds & LED_NUM_LOCK != 0, WARN);
+        let x = flag(c, x, y, "CAPS", leds & LED_CAPS_LOCK != 0, WARN);
+        flag(c, x, y, "SCROLL", leds & LED_SCROLL_LOCK != 0, WARN);
+    } else {
+        c.text(VALUE_X, y, SCALE, DIM, "unavailable");
+    }
+    y += LINE;
+
+    row(c, y, "Keys");
+    let mut held: Vec<String> = (0..8)
+        .filter(|b| s.modifiers & (1 << b) != 0)
+        .map(|b| usage_name(0xE0 + b))
+        .collect();
+    held.extend(s.keys.iter().map(|&u| usage_name(u)));
+    if held.is_empty() {
+        c.text(VALUE_X, y, SCALE, DIM, "-");
+    } else {
+        c.text(VALUE_X, y, SCALE, HOT, &held.join(" "));
+    }
+    y += LINE;
+
+    row(c, y, "Buttons");
+    let x = flag(c, VALUE_X, y, "L", s.buttons & 0b001 != 0, HOT);
+    let x = flag(c, x, y, "M", s.buttons & 0b010 != 0, HOT);
+    flag(c, x, y, "R", s.buttons & 0b100 != 0, HOT);
+    y += LINE;
+
+    row(c, y, "Battery");
+    let color = if s.battery <= 15 { HOT } else { FG };
+    c.text(VALUE_X, y, SCALE, color, &format!("{}%", s.battery));
+    y += LINE;
+
+    row(c, y, "Capture");
+    if !v.capture_enabled {
+        c.text(VALUE_X, y, SCALE, DIM, "off");
+    } else if v.captured {
+        let chord: Vec<String> = v.capture_release.iter().map(|&u| usage_name(u)).collect();
+        let x = c.text(VALUE_X, y, SCALE, OK, "ON");
+        let hint = format!("{} releases", chord.join("+"));
+        c.text(x + Canvas::advance(SCALE), y, SCALE, LABEL, &hint);
+    } else {
+        c.text(VALUE_X, y, SCALE, FG, "click to grab");
+    }
+    y += LINE;
+
+    row(c, y, "Profile");
+    c.text(
+        VALUE_X,
+        y,
+        SCALE,
+        FG,
+        s.profile.as_deref().unwrap_or("default"),
+    );
+    y + LINE
+}
+
+fn row(c: &mut Canvas, y: i32, label: &str) {
+    c.text(PAD, y, SCALE, LABEL, label);
+}
+
+/// Word lit in `on_color` or dimmed; returns the x for the next word
+fn flag(c: &mut Canvas, x: i32, y: i32, word: &str, on: bool, on_color: u32) -> i32 {
+    let end = c.text(x, y, SCALE, if on { on_color } else { DIM }, word);
+    end + Canvas::advance(SCALE)
+}
@@ -0,0 +1,612 @@
+// Input path from local sources (window, poller, ...) to the BLE task.
+//
+// Key and button transitions plus control commands travel over an unbounded channel and are
+// never dropped. Pure pointer motion does not queue: it merges into a shared accumulator that
+// the BLE side drains whenever no priority command is waiting. An optional remapper on the
+// receiving end rewrites key events before the BLE task sees them, and an optional macro engine
+// after it turns trigger chords into played sequences. The remapper can be replaced while
+// running, e.g. when the config file changes.
+//
+// Each source sends its own button mask and key presses. The channel combines them, so a key or
+// button stays down on the host while any source holds it.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+#[cfg(any(unix, feature = "scripting"))]
+use std::time::Duration;
+
+use tokio::sync::{Notify, mpsc};
+use tokio::time::Instant;
+
+#[cfg(any(unix, feature = "scripting"))]
+use crate::consts::SCROLL_LIMIT;
+use crate::macros::{MacroEngine, Op};
+use crate::remap::Remapper;
+use crate::typing::KeyEvent;
+#[cfg(any(unix, feature = "scripting"))]
+use crate::typing::{Timing, Typed};
+use crate::ui::AppCmd;
+
+/// Counters shared by both ends, logged by the BLE task
+#[derive(Default)]
+pub struct InputStats {
+    /// Motion events folded into an already pending mouse report
+    pub merged: AtomicU64,
+    /// Commands that could not be delivered because the BLE task is gone
+    pub dropped: AtomicU64,
+}
+
+#[derive(Default)]
+struct MotionAccum {
+    buttons: u8,
+    dx: f32,
+    dy: f32,
+    wheel: i32,
+    pan: i32,
+}
+
+impl MotionAccum {
+    fn is_pending(&self) -> bool {
+        self.dx != 0.0 || self.dy != 0.0 || self.wheel != 0 || self.pan != 0
+    }
+
+    /// Take the pending motion; scrolling beyond i8 range stays pending for the next take
+    fn take(&mut self) -> Option<AppCmd> {
+        if !self.is_pending() {
+            return None;
+        }
+        let wheel = self.wheel.clamp(i8::MIN as i32, i8::MAX as i32);
+        let pan = self.pan.clamp(i8::MIN as i32, i8::MAX as i32);
+        self.wheel -= wheel;
+        self.pan -= pan;
+        Some(AppCmd::Mouse {
+            buttons: self.buttons,
+            dx: std::mem::take(&mut self.dx),
+            dy: std::mem::take(&mut self.dy),
+            wheel: wheel as i8,
+            pan: pan as i8,
+        })
+    }
+}
+
+/// What each input source holds. Only changes to the union of all sources are passed on, so
+/// one source letting go of a key or button doesn't release it while another still holds it.
+struct Holds<S> {
+    keys: BTreeMap<u8, BTreeSet<S>>,
+    buttons: BTreeMap<S, u8>,
+}
+
+impl<S> Default for Holds<S> {
+    fn default() -> Self {
+        Self {
+            keys: BTreeMap::new(),
+            buttons: BTreeMap::new(),
+        }
+    }
+}
+
+impl<S: Ord + Copy> Holds<S> {
+    /// `cmd` from `source` in terms of the union, or None if the union doesn't change
+    fn apply(&mut self, source: S, cmd: AppCmd) -> Option<AppCmd> {
+        match cmd {
+            // Repeated presses still go out; they are harmless and re-press a key the BLE
+            // task's lease watchdog let go of
+            AppCmd::KeyDown(usage) => {
+                self.keys.entry(usage).or_default().insert(source);
+                Some(cmd)
+            }
+            AppCmd::KeyUp(usage) => {
+                if let Some(holders) = self.keys.get_mut(&usage) {
+                    holders.remove(&source);
+                    if !holders.is_empty() {
+                        return None;
+                    }
+                    self.keys.remove(&usage);
+                }
+                Some(cmd)
+            }
+            AppCmd::Mouse {
+                buttons,
+                dx,
+                dy,
+                wheel,
+                pan,
+            } => {
+                if buttons == 0 {
+                    self.buttons.remove(&source);
+                } else {
+                    self.buttons.insert(source, buttons);
+                }
+                Some(AppCmd::Mouse {
+                    buttons: self.buttons.values().fold(0, |all, b| all | b),
+                    dx,
+                    dy,
+                    wheel,
+                    pan,
+                })
+            }
+            other => Some(other),
+        }
+    }
+}
+
+/// Where a mouse command leaving `InputRx` came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Origin {
+    Input,
+    Macros,
+}
+
+type KeyHook = Box<dyn Fn(KeyEvent) + Send + Sync>;
+
+struct Shared {
+    /// Per-source holds of everything sent through `InputTx::send`
+    holds: Mutex<Holds<u32>>,
+    next_source: AtomicU32,
+    motion: Mutex<MotionAccum>,
+    motion_ready: Notify,
+    stats: InputStats,
+    key_hook: OnceLock<KeyHook>,
+}
+
+#[derive(Debug)]
+pub struct InputClosed;
+
+impl std::fmt::Display for InputClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("input channel closed")
+    }
+}
+
+impl std::error::Error for InputClosed {}
+
+#[derive(Clone)]
+pub struct InputTx {
+    prio: mpsc::UnboundedSender<AppCmd>,
+    /// Commands that skip the key hook, remapping, macros and motion merging
+    processed: mpsc::UnboundedSender<AppCmd>,
+    shared: Arc<Shared>,
+    /// Whether key events sent here are shown to the key hook
+    hooked: bool,
+    /// Clones share it; `new_source` hands out another
+    source: u32,
+}
+
+pub struct InputRx {
+    prio: mpsc::UnboundedReceiver<AppCmd>,
+    processed: mpsc::UnboundedReceiver<AppCmd>,
+    shared: Arc<Shared>,
+    remap: Option<Remapper>,
+    /// Replacement remappers; None turns remapping off
+    remap_updates: Option<mpsc::UnboundedReceiver<Option<Remapper>>>,
+    /// Zero point of the remapper's and macro engine's clock
+    epoch: Instant,
+    macros: Option<MacroEngine>,
+    /// Remapped key events and macro output not yet handed out
+    pending: VecDeque<AppCmd>,
+    /// Buttons held by the input sources and by a playing macro. Only buttons: macros release
+    /// trigger keys the input side holds on purpose.
+    buttons: Holds<Origin>,
+}
+
+pub fn channel() -> (InputTx, InputRx) {
+    let (prio_tx, prio_rx) = mpsc::unbounded_channel();
+    let (processed_tx, processed_rx) = mpsc::unbounded_channel();
+    let shared = Arc::new(Shared {
+        holds: Mutex::new(Holds::default()),
+        next_source: AtomicU32::new(1),
+        motion: Mutex::new(MotionAccum::default()),
+        motion_ready: Notify::new(),
+        stats: InputStats::default(),
+        key_hook: OnceLock::new(),
+    });
+    (
+        InputTx {
+            prio: prio_tx,
+            processed: processed_tx,
+            shared: Arc::clone(&shared),
+            hooked: true,
+            source: 0,
+        },
+        InputRx {
+            prio: prio_rx,
+            processed: processed_rx,
+            shared,
+            remap: None,
+            remap_updates: None,
+            macros: None,
+            epoch: Instant::now(),
+            pending: VecDeque::new(),
+            buttons: Holds::default(),
+        },
+    )
+}
+
+impl InputTx {
+    /// Show every key event from the input sources to `hook`; only the first hook set counts
+    #[cfg(feature = "scripting")]
+    pub fn set_key_hook(&self, hook: impl Fn(KeyEvent) + Send + Sync + 'static) {
+        let _ = self.shared.key_hook.set(Box::new(hook));
+    }
+
+    /// A sender whose key events the key hook does not see, for whoever set the hook
+    #[cfg(feature = "scripting")]
+    pub fn unhooked(&self) -> Self {
+        Self {
+            hooked: false,
+            ..self.clone()
+        }
+    }
+
+    /// A sender for an independent source, whose keys and buttons are held separately from
+    /// those of the other sources
+    pub fn new_source(&self) -> Self {
+        Self {
+            source: self.shared.next_source.fetch_add(1, Ordering::Relaxed),
+            ..self.clone()
+        }
+    }
+
+    pub fn send(&self, cmd: AppCmd) -> Result<(), InputClosed> {
+        if self.prio.is_closed() {
+            self.shared.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(InputClosed);
+        }
+        if self.hooked
+            && let Some(hook) = self.shared.key_hook.get()
+        {
+            match cmd {
+                AppCmd::KeyDown(usage) => hook(KeyEvent::Down(usage)),
+                AppCmd::KeyUp(usage) => hook(KeyEvent::Up(usage)),
+                _ => {}
+            }
+        }
+        // Held until the command is queued, so sources racing each other can't queue an older
+        // union after a newer one
+        let mut holds = self.shared.holds.lock().unwrap();
+        let Some(cmd) = holds.apply(self.source, cmd) else {
+            return Ok(());
+        };
+        match cmd {
+            AppCmd::Mouse {
+                buttons,
+                dx,
+                dy,
+                wheel,
+                pan,
+            } => {
+                let mut acc = self.shared.motion.lock().unwrap();
+                if buttons != acc.buttons {
+                    // Button transition: flush pending motion ahead of it so ordering holds,
+                    // all on the lossless path
+                    while let Some(m) = acc.take() {
+                        self.send_prio(m)?;
+                    }
+                    acc.buttons = buttons;
+                    return self.send_prio(AppCmd::Mouse {
+                        buttons,
+                        dx,
+                        dy,
+                        wheel,
+                        pan,
+                    });
+                }
+                if dx == 0.0 && dy == 0.0 && wheel == 0 && pan == 0 {
+                    return Ok(());
+                }
+                if acc.is_pending() {
+                    self.shared.stats.merged.fetch_add(1, Ordering::Relaxed);
+                }
+                acc.dx += dx;
+                acc.dy += dy;
+                acc.wheel += wheel as i32;
+                acc.pan += pan as i32;
+                drop(acc);
+                self.shared.motion_ready.notify_one();
+                Ok(())
+            }
+            other => self.send_prio(other),
+        }
+    }
+
+    /// Send a command that already went through remapping, macros and the key hook, such as
+    /// one from a recording of what the BLE task received. It reaches the BLE task unchanged.
+    pub fn send_processed(&self, cmd: AppCmd) -> Result<(), InputClosed> {
+        self.processed.send(cmd).map_err(|_| {
+            self.shared.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            InputClosed
+        })
+    }
+
+    fn send_prio(&self, cmd: AppCmd) -> Result<(), InputClosed> {
+        self.prio.send(cmd).map_err(|_| {
+            self.shared.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            InputClosed
+        })
+    }
+}
+
+impl InputRx {
+    /// Pass key events through `remap` from now on
+    pub fn with_remap(mut self, remap: Remapper) -> Self {
+        self.remap = Some(remap);
+        self
+    }
+
+    /// Swap in the remappers sent on `updates` as they arrive
+    pub fn with_remap_updates(
+        mut self,
+        updates: mpsc::UnboundedReceiver<Option<Remapper>>,
+    ) -> Self {
+        self.remap_updates = Some(updates);
+        self
+    }
+
+    /// Run key events through `macros` after any remapping, and play what they trigger
+    pub fn with_macros(mut self, macros: MacroEngine) -> Self {
+        self.macros = Some(macros);
+        self
+    }
+
+    /// Next command, preferring key/button/control commands over merged motion.
+    /// Returns None once every sender is gone.
+    pub async fn recv(&mut self) -> Option<AppCmd> {
+        loop {
+            if let Some(cmd) = self.pending.pop_front() {
+                return Some(cmd);
+            }
+            if let Ok(cmd) = self.processed.try_recv() {
+                return Some(cmd);
+            }
+            // Tap-hold and combo decisions or macro steps may be due without any new input
+            let timer = [
+                self.remap.as_ref().and_then(|r| r.deadline()),
+                self.macros.as_ref().and_then(|m| m.deadline()),
+            ]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|d| self.epoch + d);
+            let updates = self.remap_updates.as_mut();
+            let has_updates = updates.is_some();
+            let cmd = tokio::select! {
+                cmd = recv_raw(&mut self.prio, &self.shared) => Some(cmd?),
+                Some(cmd) = self.processed.recv() => return Some(cmd),
+                _ = tokio::time::sleep_until(timer.unwrap_or_else(Instant::now)), if timer.is_some() => None,
+                update = async { updates.unwrap().recv().await }, if has_updates => {
+                    let Some(next) = update else {
+                        self.remap_updates = None;
+                        continue;
+                    };
+                    // Whatever the old layers pressed comes up before the new ones take over
+                    let released = self.remap.as_mut().map(Remapper::release_all);
+                    self.remap = next;
+                    self.pending.extend(released.into_iter().flatten().map(key_cmd));
+                    continue;
+                }
+            };
+            if self.remap.is_none() && self.macros.is_none() {
+                match cmd {
+                    Some(cmd) => return Some(cmd),
+                    None => continue,
+                }
+            }
+            let now = self.epoch.elapsed();
+            let events = match (&mut self.remap, cmd) {
+                (Some(remap), Some(AppCmd::KeyDown(usage))) => remap.key_down(usage, now),
+                (Some(remap), Some(AppCmd::KeyUp(usage))) => remap.key_up(usage, now),
+                (Some(remap), None) => remap.tick(now),
+                (None, Some(AppCmd::KeyDown(usage))) => vec![KeyEvent::Down(usage)],
+                (None, Some(AppCmd::KeyUp(usage))) => vec![KeyEvent::Up(usage)],
+                (None, None) => Vec::new(),
+                (_, Some(cmd)) => {
+                    if let (
+                        Some(macros),
+                        AppCmd::Mouse {
+                            buttons,
+                            dx,
+                            dy,
+                            wheel,
+                            pan,
+                        },
+                    ) = (&mut self.macros, &cmd)
+                    {
+                        macros.mouse(*buttons, *dx, *dy, *wheel, *pan, now);
+                    }
+                    self.pending.extend(self.buttons.apply(Origin::Input, cmd));
+                    Vec::new()
+                }
+            };
+            let Some(macros) = &mut self.macros else {
+                self.pending.extend(events.into_iter().map(key_cmd));
+                continue;
+            };
+            let recording = macros.is_recording();
+            let mut ops = Vec::new();
+            for ev in events {
+                ops.extend(macros.key(ev, now));
+            }
+            ops.extend(macros.tick(now));
+            match (recording, macros.is_recording()) {
+                (false, true) => tracing::info!("Recording macro"),
+                (true, false) => match (macros.take_record_error(), macros.recorded_toml()) {
+                    (Some(e), _) => tracing::warn!("Recorded macro discarded: {e:#}"),
+                    (None, Some(steps)) => tracing::info!("Recorded macro:\n{steps}"),
+                    (None, None) => tracing::warn!("Recorded macro cannot be played back"),
+                },
+                _ => {}
+            }
+            for op in ops {
+                match op {
+                    Op::Key(ev) => self.pending.push_back(key_cmd(ev)),
+                    Op::Mouse {
+                        buttons,
+                        dx,
+                        dy,
+                        wheel,
+                        pan,
+                    } => {
+                        let cmd = AppCmd::Mouse {
+                            buttons,
+                            dx,
+                            dy,
+                            wheel,
+                            pan,
+                        };
+                        self.pending.extend(self.buttons.apply(Origin::Macros, cmd));
+                    }
+                    Op::Wait(_) => {}
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> &InputStats {
+        &self.shared.stats
+    }
+}
+
+/// Keys and buttons one source pressed through `InputTx::send`, so it can let go of all of them
+/// at once. Control clients and scripts drive their input through it.
+#[cfg(any(unix, feature = "scripting"))]
+pub struct Presses {
+    input: InputTx,
+    held: BTreeSet<u8>,
+    buttons: u8,
+}
+
+#[cfg(any(unix, feature = "scripting"))]
+impl Presses {
+    pub fn new(input: InputTx) -> Self {
+        Self {
+            input,
+            held: BTreeSet::new(),
+            buttons: 0,
+        }
+    }
+
+    pub fn send(&self, cmd: AppCmd) -> Result<(), InputClosed> {
+        self.input.send(cmd)
+    }
+
+    pub fn key_down(&mut self, usage: u8) -> Result<(), InputClosed> {
+        if self.held.insert(usage) {
+            self.send(AppCmd::KeyDown(usage))?;
+        }
+        Ok(())
+    }
+
+    pub fn key_up(&mut self, usage: u8) -> Result<(), InputClosed> {
+        if self.held.remove(&usage) {
+            self.send(AppCmd::KeyUp(usage))?;
+        }
+        Ok(())
+    }
+
+    pub fn key(&mut self, event: KeyEvent) -> Result<(), InputClosed> {
+        match event {
+            KeyEvent::Down(usage) => self.key_down(usage),
+            KeyEvent::Up(usage) => self.key_up(usage),
+        }
+    }
+
+    /// Press in order, release in reverse; keys already held stay held
+    pub fn tap(&mut self, chord: &[u8]) -> Result<(), InputClosed> {
+        let fresh: Vec<u8> = chord
+            .iter()
+            .copied()
+            .filter(|u| !self.held.contains(u))
+            .collect();
+        for &u in &fresh {
+            self.key_down(u)?;
+        }
+        for &u in fresh.iter().rev() {
+            self.key_up(u)?;
+        }
+        Ok(())
+    }
+
+    /// The events that type `typed`, each with the wait after it. Keys already held (e.g. a
+    /// modifier) stay as they are, so their events come out as None.
+    pub fn typing(&self, typed: &Typed, timing: &Timing) -> Vec<(Option<KeyEvent>, Duration)> {
+        typed
+            .events(timing)
+            .into_iter()
+            .map(|(ev, wait)| {
+                let (KeyEvent::Down(usage) | KeyEvent::Up(usage)) = ev;
+                (Some(ev).filter(|_| !self.held.contains(&usage)), wait)
+            })
+            .collect()
+    }
+
+    pub fn button_down(&mut self, bit: u8) -> Result<(), InputClosed> {
+        self.buttons |= bit;
+        self.mouse(0.0, 0.0, 0, 0)
+    }
+
+    pub fn button_up(&mut self, bit: u8) -> Result<(), InputClosed> {
+        self.buttons &= !bit;
+        self.mouse(0.0, 0.0, 0, 0)
+    }
+
+    /// Motion and scrolling with the buttons held here. Scrolling is capped at `SCROLL_LIMIT`
+    /// steps each way, and steps beyond one report's range are split across reports.
+    pub fn mouse(&self, dx: f32, dy: f32, wheel: i32, pan: i32) -> Result<(), InputClosed> {
+        let bound = |steps: i32| steps.clamp(-SCROLL_LIMIT, SCROLL_LIMIT);
+        let (mut dx, mut dy, mut wheel, mut pan) = (dx, dy, bound(wheel), bound(pan));
+        loop {
+            let step = |rest: &mut i32| {
+                let step = (*rest).clamp(i8::MIN as i32, i8::MAX as i32);
+                *rest -= step;
+                step as i8
+            };
+            self.send(AppCmd::Mouse {
+                buttons: self.buttons,
+                dx,
+                dy,
+                wheel: step(&mut wheel),
+                pan: step(&mut pan),
+            })?;
+            if wheel == 0 && pan == 0 {
+                return Ok(());
+            }
+            (dx, dy) = (0.0, 0.0);
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        for usage in std::mem::take(&mut self.held) {
+            let _ = self.send(AppCmd::KeyUp(usage));
+        }
+        if self.buttons != 0 {
+            self.buttons = 0;
+            let _ = self.mouse(0.0, 0.0, 0, 0);
+        }
+    }
+}
+
+async fn recv_raw(prio: &mut mpsc::UnboundedReceiver<AppCmd>, shared: &Shared) -> Option<AppCmd> {
+    loop {
+        match prio.try_recv() {
+            Ok(cmd) => return Some(cmd),
+            Err(mpsc::error::TryRecvError::Disconnected) => return None,
+            Err(mpsc::error::TryRecvError::Empty) => {}
+        }
+        if let Some(cmd) = shared.motion.lock().unwrap().take() {
+            return Some(cmd);
+        }
+        tokio::select! {
+            biased;
+            cmd = prio.recv() => return cmd,
+            _ = shared.motion_ready.notified() => {}
+        }
+    }
+}
+
+fn key_cmd(ev: KeyEvent) -> AppCmd {
+    match ev {
+        KeyEvent::Down(usage) => AppCmd::KeyDown(usage),
+        KeyEvent::Up(usage) => AppCmd::KeyUp(usage),
+    }
+}
@@ -1,2 +1,8 @@
+pub mod config;
 pub mod consts;
+pub mod ducky;
 pub mod hid;
+pub mod macros;
+pub mod mouse;
+pub mod remap;
+pub mod typing;
@@ -0,0 +1,548 @@
+// User macros: timed key, text and mouse sequences bound to trigger chords
+//
+// Definitions come from TOML:
+//
+//     [macros.signature]
+//     trigger = "hyper+1"
+//     steps = [{ text = "Best regards,\nAda" }]
+//
+//     [macros.double-click-drag]
+//     trigger = "hyper+m"
+//     steps = [{ click = "left" }, { move = [120, 0] }, { click = "left" }]
+//
+// Steps compile into ops ahead of time, so a bad key name or untypeable text fails at load.
+// The engine sits after remapping: it swallows trigger presses, plays ops on a clock, and can
+// record live input into a macro bound to the `play` chord.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::hid::{BUTTON_NAMES, button_from_name, usage_from_name, usage_name};
+use crate::typing::{KeyEvent, Keymap, Timing};
+
+/// One step of a macro definition
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    /// Press a chord in order and release it in reverse
+    Tap(String),
+    Down(String),
+    Up(String),
+    /// Typed with the instance's layout and Unicode fallback
+    Text(String),
+    DelayMs(u64),
+    Click(String),
+    ButtonDown(String),
+    ButtonUp(String),
+    Move([f32; 2]),
+    Scroll(i32),
+    /// Horizontal scroll, positive is right
+    Pan(i32),
+}
+
+/// Compiled macro output; the engine never hands out `Wait`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Key(KeyEvent),
+    /// Full button state plus motion, as in a mouse report
+    Mouse {
+        buttons: u8,
+        dx: f32,
+        dy: f32,
+        wheel: i8,
+        pan: i8,
+    },
+    Wait(Duration),
+}
+
+#[derive(Deserialize)]
+struct MacroFile {
+    #[serde(default)]
+    settings: MacroSettings,
+    #[serde(default)]
+    macros: BTreeMap<String, MacroDef>,
+}
+
+#[derive(Deserialize)]
+struct MacroDef {
+    trigger: String,
+    steps: Vec<Step>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct MacroSettings {
+    /// Starts and stops recording live input
+    record: Option<String>,
+    /// Plays the last recording
+    play: Option<String>,
+    /// Stops a running macro
+    cancel: String,
+}
+
+impl Default for MacroSettings {
+    fn default() -> Self {
+        Self {
+            record: None,
+            play: None,
+            cancel: "escape".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub name: String,
+    pub trigger: Vec<u8>,
+    pub ops: Vec<Op>,
+}
+
+/// Loaded definitions plus what is needed to compile recordings later
+pub struct MacroSet {
+    macros: Vec<Macro>,
+    record: Option<Vec<u8>>,
+    play: Option<Vec<u8>>,
+    cancel: Vec<u8>,
+    keymap: Keymap,
+    timing: Timing,
+}
+
+impl MacroSet {
+    /// `keymap` and `timing` decide how `text` and `tap` steps are typed
+    pub fn from_toml(text: &str, keymap: Keymap, timing: Timing) -> anyhow::Result<Self> {
+        let file: MacroFile = toml::from_str(text)?;
+        let mut set = Self {
+            macros: Vec::new(),
+            record: file
+                .settings
+                .record
+                .as_deref()
+                .map(parse_trigger)
+                .transpose()
+                .context("[settings] record")?,
+            play: file
+                .settings
+                .play
+                .as_deref()
+                .map(parse_trigger)
+                .transpose()
+                .context("[settings] play")?,
+            cancel: parse_trigger(&file.settings.cancel).context("[settings] cancel")?,
+            keymap,
+            timing,
+        };
+        for (name, def) in file.macros {
+            let trigger =
+                parse_trigger(&def.trigger).with_context(|| format!("[macros.{name}]"))?;
+            let ops = set
+                .compile(&def.steps)
+                .with_context(|| format!("[macros.{name}]"))?;
+            set.macros.push(Macro { name, trigger, ops });
+        }
+        Ok(set)
+    }
+
+    pub fn macros(&self) -> &[Macro] {
+        &self.macros
+    }
+
+    pub fn compile(&self, steps: &[Step]) -> anyhow::Result<Vec<Op>> {
+        let t = &self.timing;
+        let mut ops = Vec::new();
+        let mut buttons = 0u8;
+        let mouse = |buttons: u8, dx: f32, dy: f32, wheel: i8, pan: i8| Op::Mouse {
+            buttons,
+            dx,
+            dy,
+            wheel,
+            pan,
+        };
+        for step in steps {
+            match step {
+                Step::Tap(chord) => {
+                    let keys = parse_trigger(chord)?;
+                    ops.extend(keys.iter().map(|&k| Op::Key(KeyEvent::Down(k))));
+                    ops.push(Op::Wait(t.hold));
+                    ops.extend(keys.iter().rev().map(|&k| Op::Key(KeyEvent::Up(k))));
+                    ops.push(Op::Wait(t.gap));
+                }
+                Step::Down(keys) => {
+                    let keys = parse_trigger(keys)?;
+                    ops.extend(keys.into_iter().map(|k| Op::Key(KeyEvent::Down(k))));
+                }
+                Step::Up(keys) => {
+                    let keys = parse_trigger(keys)?;
+                    ops.extend(keys.into_iter().rev().map(|k| Op::Key(KeyEvent::Up(k))));
+                }
+                Step::Text(text) => {
+                    let typed = self.keymap.type_text(text);
+                    if !typed.unmappable.is_empty() {
+                        let chars: String = typed.unmappable.iter().map(|&(_, c)| c).collect();
+                        anyhow::bail!("cannot type {chars:?} with this layout");
+                    }
+                    for (ev, wait) in typed.events(t) {
+                        ops.push(Op::Key(ev));
+                        if !wait.is_zero() {
+                            ops.push(Op::Wait(wait));
+                        }
+                    }
+                }
+                Step::DelayMs(ms) => ops.push(Op::Wait(Duration::from_millis(*ms))),
+                Step::Click(name) => {
+                    let bit = button_bit(name)?;
+                    ops.push(mouse(buttons | bit, 0.0, 0.0, 0, 0));
+                    ops.push(Op::Wait(t.hold));
+                    ops.push(mouse(buttons, 0.0, 0.0, 0, 0));
+                    ops.push(Op::Wait(t.gap));
+                }
+                Step::ButtonDown(name) => {
+                    buttons |= button_bit(name)?;
+                    ops.push(mouse(buttons, 0.0, 0.0, 0, 0));
+                }
+                Step::ButtonUp(name) => {
+                    buttons &= !button_bit(name)?;
+                    ops.push(mouse(buttons, 0.0, 0.0, 0, 0));
+                }
+                Step::Move([dx, dy]) => ops.push(mouse(buttons, *dx, *dy, 0, 0)),
+                Step::Scroll(amount) => {
+                    let mut rest = *amount;
+                    while rest != 0 {
+                        let step = rest.clamp(i8::MIN as i32, i8::MAX as i32);
+                        rest -= step;
+                        ops.push(mouse(buttons, 0.0, 0.0, step as i8, 0));
+                    }
+                }
+                Step::Pan(amount) => {
+                    let mut rest = *amount;
+                    while rest != 0 {
+                        let step = rest.clamp(i8::MIN as i32, i8::MAX as i32);
+                        rest -= step;
+                        ops.push(mouse(buttons, 0.0, 0.0, 0, step as i8));
+                    }
+                }
+            }
+        }
+        Ok(ops)
+    }
+}
+
+/// A chord like `parse_chord`, plus `hyper` (Ctrl+Shift+Alt+GUI) and `meh` (Ctrl+Shift+Alt)
+pub fn parse_trigger(chord: &str) -> anyhow::Result<Vec<u8>> {
+    let mut keys = Vec::new();
+    for name in chord.split('+') {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "hyper" => keys.extend([0xE0, 0xE1, 0xE2, 0xE3]),
+            "meh" => keys.extend([0xE0, 0xE1, 0xE2]),
+            _ => keys.push(usage_from_name(name).with_context(|| format!("unknown key '{name}'"))?),
+        }
+    }
+    Ok(keys)
+}
+
+fn button_bit(name: &str) -> anyhow::Result<u8> {
+    button_from_name(name).with_context(|| format!("unknown button '{name}'"))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Binding {
+    Macro(usize),
+    Record,
+    Play,
+    Cancel,
+}
+
+/// A macro being played
+struct Run {
+    ops: VecDeque<Op>,
+    /// Ops run once the clock reaches this
+    resume_at: Duration,
+    keys: BTreeSet<u8>,
+    buttons: u8,
+}
+
+/// Live input captured since the record chord
+struct Recording {
+    steps: Vec<Step>,
+    last: Duration,
+    buttons: u8,
+}
+
+impl Recording {
+    fn push(&mut self, step: Step, now: Duration) {
+        let gap = now.saturating_sub(self.last).as_millis() as u64;
+        if gap > 0 && !self.steps.is_empty() {
+            self.steps.push(Step::DelayMs(gap));
+        }
+        self.last = now;
+        self.steps.push(step);
+    }
+}
+
+/// Trigger matching, playback and recording over the key stream to the BLE task
+pub struct MacroEngine {
+    set: MacroSet,
+    /// Keys passed through to the host and still down
+    held: BTreeSet<u8>,
+    /// Keys whose release must not reach the host: triggers and modifiers lifted for a macro
+    swallowed: BTreeSet<u8>,
+    run: Option<Run>,
+    recording: Option<Recording>,
+    recorded: Option<(Vec<Step>, Vec<Op>)>,
+    /// Why the last recording could not be kept
+    record_error: Option<anyhow::Error>,
+}
+
+impl MacroEngine {
+    pub fn new(set: MacroSet) -> Self {
+        Self {
+            set,
+            held: BTreeSet::new(),
+            swallowed: BTreeSet::new(),
+            run: None,
+            recording: None,
+            recorded: None,
+            record_error: None,
+        }
+    }
+
+    /// A key event on its way to the host; returns what to send instead
+    pub fn key(&mut self, ev: KeyEvent, now: Duration) -> Vec<Op> {
+        let mut out = Vec::new();
+        match ev {
+            KeyEvent::Down(usage) => {
+                if let Some((trigger, binding)) = self.binding_for(usage) {
+                    // The host must not see the trigger, and held modifiers would garble output
+                    for k in trigger {
+                        if self.held.remove(&k) {
+                            out.push(Op::Key(KeyEvent::Up(k)));
+                            if let Some(rec) = &mut self.recording {
+                                rec.push(Step::Up(usage_name(k)), now);
+                            }
+                        }
+                        self.swallowed.insert(k);
+                    }
+                    self.fire(binding, now, &mut out);
+                    return out;
+                }
+                self.held.insert(usage);
+            }
+            KeyEvent::Up(usage) => {
+                if self.swallowed.remove(&usage) {
+                    return out;
+                }
+                self.held.remove(&usage);
+            }
+        }
+        if let Some(rec) = &mut self.recording {
+            let step = match ev {
+                KeyEvent::Down(u) => Step::Down(usage_name(u)),
+                KeyEvent::Up(u) => Step::Up(usage_name(u)),
+            };
+            rec.push(step, now);
+        }
+        out.push(Op::Key(ev));
+        out
+    }
+
+    /// Mouse input passes through untouched; it is only watched for recording
+    pub fn mouse(&mut self, buttons: u8, dx: f32, dy: f32, wheel: i8, pan: i8, now: Duration) {
+        let Some(rec) = &mut self.recording else {
+            return;
+        };
+        for &(name, bit) in BUTTON_NAMES {
+            let name = name.to_string();
+            match (rec.buttons & bit != 0, buttons & bit != 0) {
+                (false, true) => rec.push(Step::ButtonDown(name), now),
+                (true, false) => rec.push(Step::ButtonUp(name), now),
+                _ => {}
+            }
+        }
+        rec.buttons = buttons;
+        if dx != 0.0 || dy != 0.0 {
+            rec.push(Step::Move([dx, dy]), now);
+        }
+        if wheel != 0 {
+            rec.push(Step::Scroll(wheel as i32), now);
+        }
+        if pan != 0 {
+            rec.push(Step::Pan(pan as i32), now);
+        }
+    }
+
+    /// Play ops that are due by `now`
+    pub fn tick(&mut self, now: Duration) -> Vec<Op> {
+        let mut out = Vec::new();
+        let Some(run) = &mut self.run else {
+            return out;
+        };
+        while run.resume_at <= now {
+            match run.ops.pop_front() {
+                Some(Op::Wait(d)) => run.resume_at += d,
+                Some(op) => {
+                    match op {
+                        Op::Key(KeyEvent::Down(k)) => {
+                            run.keys.insert(k);
+                        }
+                        Op::Key(KeyEvent::Up(k)) => {
+                            run.keys.remove(&k);
+                        }
+                        Op::Mouse { buttons, .. } => run.buttons = buttons,
+                        Op::Wait(_) => {}
+                    }
+                    out.push(op);
+                }
+                None => {
+                    self.stop(&mut out);
+                    break;
+                }
+            }
+        }
+        out
+    }
+
+    /// When `tick` next has something to do
+    pub fn deadline(&self) -> Option<Duration> {
+        self.run.as_ref().map(|r| r.resume_at)
+    }
+
+    /// Stop a running macro, releasing whatever it holds
+    pub fn cancel(&mut self) -> Vec<Op> {
+        let mut out = Vec::new();
+        self.stop(&mut out);
+        out
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.run.is_some()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Why the recording that just finished was discarded, if it was
+    pub fn take_record_error(&mut self) -> Option<anyhow::Error> {
+        self.record_error.take()
+    }
+
+    /// The last recording as a `[macros.recorded]` definition, ready to paste into the macro file
+    pub fn recorded_toml(&self) -> Option<String> {
+        #[derive(Serialize)]
+        struct Def<'a> {
+            trigger: String,
+            steps: &'a [Step],
+        }
+        #[derive(Serialize)]
+        struct File<'a> {
+            macros: BTreeMap<&'a str, Def<'a>>,
+        }
+        let (steps, _) = self.recorded.as_ref()?;
+        let trigger = self.set.play.iter().flatten().map(|&k| usage_name(k));
+        let def = Def {
+            trigger: trigger.collect::<Vec<_>>().join("+"),
+            steps,
+        };
+        let file = File {
+            macros: BTreeMap::from([("recorded", def)]),
+        };
+        toml::to_string(&file).ok()
+    }
+
+    fn binding_for(&self, usage: u8) -> Option<(Vec<u8>, Binding)> {
+        let down = |k: &u8| *k == usage || self.held.contains(k) || self.swallowed.contains(k);
+        let candidates = self
+            .set
+            .macros
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (&m.trigger, Binding::Macro(i)))
+            .chain(self.set.record.iter().map(|t| (t, Binding::Record)))
+            .chain(self.set.play.iter().map(|t| (t, Binding::Play)))
+            .chain(
+                self.run
+                    .is_some()
+                    .then_some((&self.set.cancel, Binding::Cancel)),
+            );
+        // The most specific trigger wins, so hyper+1 beats a bare 1
+        candidates
+            .filter(|(t, _)| t.contains(&usage) && t.iter().all(down))
+            .max_by_key(|(t, _)| t.len())
+            .map(|(t, b)| (t.clone(), b))
+    }
+
+    fn fire(&mut self, binding: Binding, now: Duration, out: &mut Vec<Op>) {
+        match binding {
+            Binding::Macro(i) => {
+                let ops = self.set.macros[i].ops.clone();
+                self.start(ops, now, out);
+            }
+            Binding::Play => {
+                if let Some((_, ops)) = &self.recorded {
+                    let ops = ops.clone();
+                    self.start(ops, now, out);
+                }
+            }
+            Binding::Record => match self.recording.take() {
+                Some(rec) => {
+                    // A recording that leaves something down would leave it down on every play
+                    let mut steps = rec.steps;
+                    for &k in &self.held {
+                        steps.push(Step::Up(usage_name(k)));
+                    }
+                    for &(name, bit) in BUTTON_NAMES {
+                        if rec.buttons & bit != 0 {
+                            steps.push(Step::ButtonUp(name.into()));
+                        }
+                    }
+                    match self.set.compile(&steps) {
+                        Ok(ops) => self.recorded = Some((steps, ops)),
+                        Err(e) => {
+                            self.recorded = None;
+                            self.record_error = Some(e);
+                        }
+                    }
+                }
+                None => {
+                    self.recording = Some(Recording {
+                        steps: Vec::new(),
+                        last: now,
+                        buttons: 0,
+                    });
+                }
+            },
+            Binding::Cancel => self.stop(out),
+        }
+    }
+
+    fn start(&mut self, ops: Vec<Op>, now: Duration, out: &mut Vec<Op>) {
+        self.stop(out);
+        self.run = Some(Run {
+            ops: ops.into(),
+            resume_at: now,
+            keys: BTreeSet::new(),
+            buttons: 0,
+        });
+        out.extend(self.tick(now));
+    }
+
+    fn stop(&mut self, out: &mut Vec<Op>) {
+        let Some(run) = self.run.take() else {
+            return;
+        };
+        for k in run.keys.into_iter().rev() {
+            out.push(Op::Key(KeyEvent::Up(k)));
+        }
+        if run.buttons != 0 {
+            out.push(Op::Mouse {
+                buttons: 0,
+                dx: 0.0,
+                dy: 0.0,
+                wheel: 0,
+                pan: 0,
+            });
+        }
+    }
+}
@@ -1,74 +1,507 @@
 mod ble;
+mod canvas;
+#[cfg(unix)]
+mod client;
+pub mod config;
 mod consts;
+#[cfg(unix)]
+mod control;
+pub mod ducky;
+#[cfg(target_os = "linux")]
+mod evdev_input;
 pub mod hid;
 mod host_power;
+mod hud;
+mod input;
+pub mod macros;
+mod mouse;
+mod osk;
+mod record;
+pub mod remap;
+#[cfg(feature = "scripting")]
+mod script;
+mod settings;
+mod shutdown;
+mod status;
+mod tty;
+pub mod typing;
 mod ui;
 
+use anyhow::Context;
 use ble_peripheral_rust::gatt::peripheral_event::PeripheralEvent;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use winit::event_loop;
 
-use clap::Parser;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
 use tracing_subscriber::{EnvFilter, fmt};
 
-use crate::ble::ble_owner_task;
-use crate::ui::{App, AppCmd};
+use crate::ble::{BleOptions, ble_owner_task};
+use crate::status::Status;
+use crate::ui::{App, AppCmd, UiEvent, UiOptions};
 
 #[derive(Debug, Parser)]
-#[command(name = "bluper", version, about = "BLE HID K+M peripheral")]
+#[command(
+    name = "bluper",
+    version,
+    about = "BLE HID K+M peripheral",
+    args_conflicts_with_subcommands = true
+)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Options for the peripheral when no subcommand is given
+    #[command(flatten)]
+    run: RunArgs,
+    /// JSON-RPC control socket path (default: $XDG_RUNTIME_DIR/bluper.sock)
+    #[cfg(unix)]
+    #[arg(long, global = true)]
+    control_socket: Option<std::path::PathBuf>,
+    /// Config file (default: $XDG_CONFIG_HOME/bluper/config.toml, if it exists)
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<std::path::PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Start the peripheral (the default without a subcommand)
+    Run(Box<RunArgs>),
+    /// Type text on the host through the running instance
+    Type {
+        text: String,
+        /// Host layout, if different from the instance's --layout
+        #[arg(long)]
+        layout: Option<typing::Layout>,
+        /// Key hold time and gap, if different from the instance's --type-delay-ms
+        #[arg(long)]
+        delay_ms: Option<u64>,
+        /// Code point entry for characters the layout lacks, if different from --unicode
+        #[arg(long)]
+        unicode: Option<typing::UnicodeInput>,
+    },
+    /// Tap a key or chord, e.g. ctrl+alt+t
+    Key { chord: String },
+    /// Click a mouse button
+    Click {
+        #[arg(value_enum, default_value_t = Button::Left)]
+        button: Button,
+        #[arg(long, default_value_t = 1)]
+        count: u32,
+    },
+    /// Move the pointer by a relative amount
+    Move {
+        #[arg(allow_negative_numbers = true)]
+        dx: f32,
+        #[arg(allow_negative_numbers = true)]
+        dy: f32,
+    },
+    /// Show link state, LEDs, held keys and battery
+    Status {
+        /// Print the raw JSON status
+        #[arg(long)]
+        json: bool,
+    },
+    /// List hosts currently connected to the peripheral
+    Hosts,
+    /// Run a DuckyScript payload on the host through the running instance
+    Ducky {
+        file: std::path::PathBuf,
+        /// Print the keyboard reports the payload produces instead of sending it
+        #[arg(long)]
+        dry_run: bool,
+        /// Host layout for STRING lines
+        #[arg(long, default_value = "us")]
+        layout: typing::Layout,
+        /// Code point entry for characters the layout lacks
+        #[arg(long, default_value = "off")]
+        unicode: typing::UnicodeInput,
+        /// Key hold time and gap between keystrokes
+        #[arg(long, default_value_t = 8)]
+        delay_ms: u64,
+    },
+}
+
+/// Mouse button for `click`; outside `client` so the CLI parses on every platform
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Button {
+    Left,
+    Middle,
+    Right,
+}
+
+#[derive(Debug, Clone, Args)]
+struct RunArgs {
     #[arg(long, default_value = "Bluper")]
     name: String,
+    /// BLE appearance, decimal or 0x-prefixed hex
+    #[arg(long, default_value_t = consts::PERIPHERAL_APPEARANCE, value_parser = parse_u16_arg)]
+    appearance: u16,
     #[arg(long, default_value = "info")]
     log_level: String,
+    /// Do not create a window; run BLE only and take input from non-window sources
+    #[arg(long)]
+    headless: bool,
+    /// Auto-release keys held longer than this many milliseconds (disabled if unset)
+    #[arg(long)]
+    key_lease_ms: Option<u64>,
+    /// Pointer report interval in milliseconds; match the connection interval (e.g. 7.5 or 15)
+    #[arg(long, default_value_t = 15.0)]
+    mouse_interval_ms: f32,
+    /// Multiplier on window pointer motion; acceleration curves and per-axis gains are set in the config file
+    #[arg(long, default_value_t = 1.0, value_parser = |s: &str| parse_speed_arg(s).map(|v| v as f32))]
+    pointer_speed: f32,
+    /// Measure window pointer motion in logical pixels, so HiDPI scaling doesn't speed it up
+    #[arg(long)]
+    dpi_aware: bool,
+    /// Click the window to grab the pointer and forward raw motion
+    #[arg(long)]
+    capture: bool,
+    /// Chord that releases pointer capture
+    #[arg(long, default_value = "ctrl+alt+escape", value_parser = parse_chord_arg)]
+    capture_release: Chord,
+    /// Show a clickable on-screen keyboard and touchpad
+    #[arg(long)]
+    osk: bool,
+    /// Forward attached keyboards/mice whose name contains this, or `vvvv:pppp` USB IDs, or `*` (repeatable)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "MATCH")]
+    evdev: Vec<String>,
+    /// Grab the --evdev devices exclusively so local consumers stop seeing their input
+    #[cfg(target_os = "linux")]
+    #[arg(long, requires = "evdev")]
+    evdev_grab: bool,
+    /// Type into the host from this terminal (raw mode; uses the kitty keyboard protocol if available)
+    #[arg(long)]
+    tty: bool,
+    /// Chord that ends a --tty session and shuts down
+    #[arg(long, default_value = "ctrl+alt+q", value_parser = parse_chord_arg)]
+    tty_exit: Chord,
+    /// Do not open the control socket
+    #[cfg(unix)]
+    #[arg(long)]
+    no_control: bool,
+    /// Keyboard layout the host uses, for typing text: us, uk, de, fr, es, nordic, dvorak, colemak
+    #[arg(long, default_value = "us")]
+    layout: typing::Layout,
+    /// Key hold time and gap between keystrokes when typing text
+    #[arg(long, default_value_t = 8)]
+    type_delay_ms: u64,
+    /// How the host enters characters missing from the layout: off, linux, windows-alt, windows-hex, macos
+    #[arg(long, default_value = "off")]
+    unicode: typing::UnicodeInput,
+    /// Key remapping and layers, as a TOML file
+    #[arg(long, value_name = "FILE")]
+    remap: Option<std::path::PathBuf>,
+    /// Macros bound to trigger chords, as a TOML file
+    #[arg(long, value_name = "FILE")]
+    macros: Option<std::path::PathBuf>,
+    /// Run the Rhai scripts in this directory, reloading them when they change
+    #[cfg(feature = "scripting")]
+    #[arg(long, value_name = "DIR")]
+    scripts: Option<std::path::PathBuf>,
+    /// Write every command the BLE side receives to this file (JSON lines; binary for .bin)
+    #[arg(long, value_name = "FILE")]
+    record: Option<std::path::PathBuf>,
+    /// Play a recording into the host once a host subscribes to input reports
+    #[arg(long, value_name = "FILE")]
+    replay: Option<std::path::PathBuf>,
+    /// Playback rate for --replay; 2 is twice as fast
+    #[arg(long, default_value_t = 1.0, value_parser = parse_speed_arg, requires = "replay")]
+    replay_speed: f64,
+    /// Send replayed commands back to back instead of with their recorded timing
+    #[arg(long, requires = "replay", conflicts_with = "replay_speed")]
+    replay_collapse: bool,
+    /// Start the replay over when it reaches the end; needs recorded timing, as a collapsed
+    /// loop would flood the host
+    #[arg(
+        long = "replay-loop",
+        requires = "replay",
+        conflicts_with = "replay_collapse"
+    )]
+    replay_loop: bool,
+}
+
+// Alias keeps clap from treating the chord as a multi-value argument
+type Chord = Vec<u8>;
+
+fn parse_chord_arg(s: &str) -> Result<Chord, String> {
+    hid::parse_chord(s).ok_or_else(|| format!("unknown key in chord '{s}'"))
+}
+
+fn parse_speed_arg(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v.is_finite() && v > 0.0 => Ok(v),
+        _ => Err(format!("'{s}' is not a positive speed factor")),
+    }
+}
+
+fn parse_u16_arg(s: &str) -> Result<u16, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("{e}"))
 }
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    // An explicitly named config file has to exist; the default one is optional
+    let config_path = cli.config.clone().or_else(config::default_path);
+    let file_config = match &config_path {
+        Some(path) if cli.config.is_some() || path.exists() => config::Config::load(path),
+        _ => Ok(config::Config::default()),
+    };
+    #[cfg(unix)]
+    let socket = cli
+        .control_socket
+        .clone()
+        .or_else(|| file_config.as_ref().ok()?.control.socket.clone())
+        .unwrap_or_else(control::default_socket_path);
+    let settings = match cli.command {
+        None => settings::Settings::new(cli.run, &matches),
+        Some(Command::Run(args)) => {
+            let (_, run_matches) = matches.subcommand().expect("run subcommand");
+            settings::Settings::new(*args, run_matches)
+        }
+        // Client subcommands talk to the running instance and never start a peripheral
+        #[cfg(unix)]
+        Some(cmd) => {
+            // A broken config must not cut the user off from the instance that is running
+            if let Err(e) = &file_config {
+                eprintln!("warning: ignoring {e:#}");
+            }
+            std::process::exit(client::run(cmd, &socket))
+        }
+        #[cfg(not(unix))]
+        Some(_) => anyhow::bail!("client subcommands need the Unix control socket"),
+    };
+    let (config, cli) = settings.resolve(file_config?);
 
     // Init tracing with env override, else CLI level
     let env_filter = std::env::var("RUST_LOG").unwrap_or_else(|_| cli.log_level.clone());
-    fmt().with_env_filter(EnvFilter::new(env_filter)).init();
+    let logs = fmt().with_env_filter(EnvFilter::new(env_filter));
+    if cli.tty {
+        logs.with_writer(|| tty::CrlfStdout).init();
+    } else {
+        logs.init();
+    }
 
-    let (cmd_tx, cmd_rx) = mpsc::channel::<AppCmd>(512);
+    if let Some(path) = config_path.as_ref().filter(|p| p.exists()) {
+        tracing::info!(path = %path.display(), "Config loaded");
+    }
+
+    let (input_tx, mut input_rx) = input::channel();
+    let type_delay = std::time::Duration::from_millis(cli.type_delay_ms);
+    let type_timing = typing::Timing {
+        hold: type_delay,
+        gap: type_delay,
+    };
+    if let Some(path) = &cli.remap {
+        input_rx = input_rx.with_remap(settings::load_remap(path)?);
+    }
+    if let Some(path) = &cli.macros {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read macro file {}", path.display()))?;
+        let keymap = typing::Keymap::new(cli.layout).with_unicode(cli.unicode);
+        let set = macros::MacroSet::from_toml(&text, keymap, type_timing)
+            .with_context(|| format!("macro file {}", path.display()))?;
+        let names: Vec<&str> = set.macros().iter().map(|m| m.name.as_str()).collect();
+        tracing::info!(path = %path.display(), macros = %names.join(","), "Macros loaded");
+        input_rx = input_rx.with_macros(macros::MacroEngine::new(set));
+    }
     let (evt_tx, evt_rx) = mpsc::channel::<PeripheralEvent>(512);
+    let (status_tx, status_rx) = watch::channel(Status::default());
+
+    let (live_tx, live_rx) = watch::channel(settings::LiveSettings::new(&cli, &config));
+    let (typing_tx, typing_rx) = watch::channel(settings::TypingDefaults {
+        layout: cli.layout,
+        unicode: cli.unicode,
+        timing: type_timing,
+    });
+    if let Some(path) = config_path {
+        let (remap_tx, remap_rx) = mpsc::unbounded_channel();
+        input_rx = input_rx.with_remap_updates(remap_rx);
+        settings::watch(settings::Watch {
+            path,
+            settings,
+            config: config.clone(),
+            live: live_tx,
+            remap: remap_tx,
+            typing: typing_tx,
+            status: status_rx.clone(),
+        });
+    }
 
-    // Spawn periodic battery poller
+    // Spawn periodic battery poller; the source can change with the config
     {
-        let cmd = cmd_tx.clone();
+        let input = input_tx.clone();
+        let mut live = live_rx.clone();
         tokio::spawn(async move {
             let mut last_sent: Option<u8> = None;
-            let mut tick = tokio::time::interval(std::time::Duration::from_secs(30));
             loop {
-                tick.tick().await;
-                if let Some(p) = crate::host_power::get_battery_percent() {
-                    if last_sent != Some(p) {
-                        if cmd.send(AppCmd::Battery(p)).await.is_err() {
-                            break;
-                        }
-                        last_sent = Some(p);
-                        tracing::debug!(%p, "Battery polled");
+                let battery = live.borrow_and_update().battery.clone();
+                let level = match battery.source {
+                    config::BatterySource::Host => crate::host_power::get_battery_percent(),
+                    config::BatterySource::Fixed => Some(battery.level),
+                };
+                if let Some(p) = level
+                    && last_sent != Some(p)
+                {
+                    if input.send(AppCmd::Battery(p)).is_err() {
+                        break;
                     }
+                    last_sent = Some(p);
+                    tracing::debug!(%p, "Battery polled");
+                }
+                let poll = std::time::Duration::from_secs(battery.poll_secs);
+                tokio::select! {
+                    _ = tokio::time::sleep(poll) => {}
+                    Ok(()) = live.changed() => {}
                 }
             }
         });
     }
 
-    let name = cli.name.clone();
-    let appearance = Some(consts::PERIPHERAL_APPEARANCE);
+    #[cfg(feature = "scripting")]
+    if let Some(dir) = &cli.scripts {
+        let opts = script::ScriptOptions {
+            dir: dir.clone(),
+            layout: cli.layout,
+            unicode: cli.unicode,
+            timing: type_timing,
+        };
+        script::spawn(opts, &input_tx, status_rx.clone())?;
+    }
+
+    let opts = BleOptions {
+        settings: live_rx.clone(),
+        device_info: settings::device_info(&cli, &config),
+        record: cli
+            .record
+            .as_deref()
+            .map(record::Recorder::create)
+            .transpose()?,
+    };
 
     let ble_handle = tokio::spawn(async move {
-        if let Err(e) = ble_owner_task(cmd_rx, evt_rx, evt_tx, name, appearance).await {
+        if let Err(e) = ble_owner_task(input_rx, evt_rx, evt_tx, status_tx, opts).await {
             tracing::error!(error = %format!("{e:#}"), "BLE task error");
         }
     });
 
-    let mut app = App::new(cmd_tx.clone());
-    let event_loop = event_loop::EventLoop::new()?;
-    event_loop.run_app(&mut app)?;
+    #[cfg(unix)]
+    if !cli.no_control {
+        let (input, status) = (input_tx.clone(), status_rx.clone());
+        let typing = typing_rx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = control::serve(socket, input, status, typing).await {
+                tracing::error!(error = %format!("{e:#}"), "Control socket error");
+            }
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    if !cli.evdev.is_empty() {
+        let opts = evdev_input::EvdevOptions {
+            filters: cli
+                .evdev
+                .iter()
+                .map(|f| evdev_input::DeviceFilter::parse(f))
+                .collect(),
+            grab: cli.evdev_grab,
+            speed: settings::ProfileSpeed::new(live_rx.clone(), status_rx.clone()),
+        };
+        tokio::spawn(evdev_input::run(input_tx.clone(), opts));
+    }
+
+    let replay = cli.replay.clone().map(|path| {
+        let opts = record::ReplayOptions {
+            path,
+            speed: cli.replay_speed,
+            collapse: cli.replay_collapse,
+            repeat: cli.replay_loop,
+        };
+        tokio::spawn(record::replay(input_tx.clone(), status_rx.clone(), opts))
+    });
 
-    drop(cmd_tx);
-    let _ = ble_handle.await;
+    // The source restores the terminal when dropped at the end of main
+    let (_tty, tty_exit) = if cli.tty {
+        let opts = tty::TtyOptions {
+            exit_chord: cli.tty_exit.clone(),
+            layout: cli.layout,
+        };
+        let (source, exit) = tty::TtySource::start(input_tx.new_source(), opts)?;
+        (Some(source), Some(exit))
+    } else {
+        (None, None)
+    };
+    let stop = async move {
+        let why = match tty_exit {
+            Some(exit) => tokio::select! {
+                sig = shutdown::signal() => sig,
+                _ = exit => "tty exit chord",
+            },
+            None => shutdown::signal().await,
+        };
+        // An interrupted replay releases what it holds before Exit reaches the BLE task
+        if let Some(replay) = replay {
+            replay.abort();
+            let _ = replay.await;
+        }
+        why
+    };
+
+    if cli.headless {
+        // Input comes only from non-window sources sharing `input_tx`
+        tracing::info!("Headless mode, stop with Ctrl+C, SIGTERM or SIGHUP");
+        let why = stop.await;
+        tracing::info!(%why, "Shutting down");
+        let _ = input_tx.send(AppCmd::Exit);
+    } else {
+        let ui_opts = UiOptions {
+            device_name: cli.name.clone(),
+            capture: cli.capture,
+            capture_release: cli.capture_release.clone(),
+            osk: cli.osk,
+            settings: live_rx.clone(),
+        };
+        let mut app = App::new(input_tx.clone(), status_rx.clone(), ui_opts);
+        let event_loop = event_loop::EventLoop::<UiEvent>::with_user_event().build()?;
+
+        // Redraw the HUD whenever the BLE task publishes new state
+        {
+            let proxy = event_loop.create_proxy();
+            let mut status_rx = status_rx;
+            tokio::spawn(async move {
+                while status_rx.changed().await.is_ok() {
+                    if proxy.send_event(UiEvent::StatusChanged).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        // Signals (and the tty exit chord) close the window the same way the user would
+        {
+            let proxy = event_loop.create_proxy();
+            let input = input_tx.clone();
+            tokio::spawn(async move {
+                let why = stop.await;
+                tracing::info!(%why, "Shutting down");
+                let _ = input.send(AppCmd::Exit);
+                let _ = proxy.send_event(UiEvent::Shutdown);
+            });
+        }
+
+        event_loop.run_app(&mut app)?;
+    }
+
+    drop(input_tx);
+    // The BLE task releases keys and stops advertising on Exit; don't hang on a stuck adapter
+    let timeout = std::time::Duration::from_millis(consts::SHUTDOWN_TIMEOUT_MS);
+    if tokio::time::timeout(timeout, ble_handle).await.is_err() {
+        tracing::warn!(?timeout, "BLE shutdown timed out");
+    }
     Ok(())
 }
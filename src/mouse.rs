@@ -0,0 +1,364 @@
+// Pointer report shaping between input sources and the BLE notify path, and the scaling,
+// drag-scrolling and kinetic scrolling applied to local input before it is sent
+
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::hid::{build_mouse_report, usage_from_name};
+
+/// Sums pointer deltas between fixed-rate report slots.
+///
+/// Fractional and out-of-range remainders carry over to the next report, so slow movement does
+/// not drift and fast movement is not clipped. Button changes are reported immediately.
+#[derive(Debug, Default)]
+pub struct MouseScheduler {
+    buttons: u8,
+    dx: f32,
+    dy: f32,
+    wheel: i32,
+    pan: i32,
+}
+
+impl MouseScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add motion. Returns the reports that must go out right away because the button state
+    /// changed: pending motion under the old buttons first, then the new button state.
+    pub fn push(&mut self, buttons: u8, dx: f32, dy: f32, wheel: i32, pan: i32) -> Vec<[u8; 6]> {
+        let mut urgent = Vec::new();
+        if buttons != self.buttons {
+            while let Some(r) = self.take_report() {
+                urgent.push(r);
+            }
+            self.buttons = buttons;
+            self.add(dx, dy, wheel, pan);
+            urgent.push(
+                self.take_report()
+                    .unwrap_or_else(|| self.report(0, 0, 0, 0)),
+            );
+        } else {
+            self.add(dx, dy, wheel, pan);
+        }
+        urgent
+    }
+
+    fn add(&mut self, dx: f32, dy: f32, wheel: i32, pan: i32) {
+        self.dx += dx;
+        self.dy += dy;
+        self.wheel += wheel;
+        self.pan += pan;
+    }
+
+    /// True if a report slot would carry whole counts of motion
+    pub fn has_pending(&self) -> bool {
+        self.dx.abs() >= 1.0 || self.dy.abs() >= 1.0 || self.wheel != 0 || self.pan != 0
+    }
+
+    /// Report for the next slot, or None if less than one count has accumulated
+    pub fn take_report(&mut self) -> Option<[u8; 6]> {
+        if !self.has_pending() {
+            return None;
+        }
+        let dx = self.dx.trunc().clamp(i8::MIN as f32, i8::MAX as f32);
+        let dy = self.dy.trunc().clamp(i8::MIN as f32, i8::MAX as f32);
+        let wheel = self.wheel.clamp(i8::MIN as i32, i8::MAX as i32);
+        let pan = self.pan.clamp(i8::MIN as i32, i8::MAX as i32);
+        self.dx -= dx;
+        self.dy -= dy;
+        self.wheel -= wheel;
+        self.pan -= pan;
+        Some(self.report(dx as i8, dy as i8, wheel as i8, pan as i8))
+    }
+
+    /// Drop pending motion and button state, e.g. after a release-all
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn report(&self, dx: i8, dy: i8, wheel: i8, pan: i8) -> [u8; 6] {
+        build_mouse_report(self.buttons, dx, dy, wheel, pan)
+    }
+}
+
+/// Scaling for local pointer motion: speed times per-axis gain times the acceleration curve,
+/// slowed further while the precision key is held
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PointerSettings {
+    pub speed: f32,
+    pub x_gain: f32,
+    pub y_gain: f32,
+    /// Acceleration as `[counts per second, gain]` points in increasing speed order. The gain
+    /// is interpolated between points and held flat beyond the first and last; no points means
+    /// no acceleration.
+    pub curve: Vec<[f32; 2]>,
+    /// Measure window motion in logical pixels, dividing out the window's scale factor
+    pub dpi_aware: bool,
+    /// Extra multiplier while `precision_key` is held
+    pub precision: f32,
+    /// Key that slows the pointer while held; it is not sent to the host
+    pub precision_key: Option<String>,
+    /// Key that turns pointer motion into scrolling while held; it is not sent to the host
+    pub scroll_key: Option<String>,
+    /// Holding the middle button scrolls too; a middle click that doesn't scroll still clicks
+    pub scroll_button: bool,
+    /// Counts of motion per wheel or pan step in scroll mode
+    pub scroll_step: f32,
+    /// Keep scrolling after a trackpad flick, slowing down until it stops
+    pub kinetic_scroll: bool,
+    /// How fast kinetic scrolling slows: the speed falls by a factor of e every `1 / friction`
+    /// seconds
+    pub kinetic_friction: f32,
+}
+
+impl Default for PointerSettings {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            x_gain: 1.0,
+            y_gain: 1.0,
+            curve: Vec::new(),
+            dpi_aware: false,
+            precision: 0.25,
+            precision_key: None,
+            scroll_key: None,
+            scroll_button: false,
+            scroll_step: 20.0,
+            kinetic_scroll: false,
+            kinetic_friction: 4.0,
+        }
+    }
+}
+
+impl PointerSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let positive = |v: f32| v.is_finite() && v > 0.0;
+        for (name, v) in [
+            ("speed", self.speed),
+            ("x_gain", self.x_gain),
+            ("y_gain", self.y_gain),
+            ("precision", self.precision),
+            ("scroll_step", self.scroll_step),
+            ("kinetic_friction", self.kinetic_friction),
+        ] {
+            anyhow::ensure!(positive(v), "{name} must be positive");
+        }
+        for pair in self.curve.windows(2) {
+            anyhow::ensure!(
+                pair[0][0] < pair[1][0],
+                "curve speeds must increase: {} then {}",
+                pair[0][0],
+                pair[1][0]
+            );
+        }
+        for &[speed, gain] in &self.curve {
+            anyhow::ensure!(
+                speed.is_finite() && speed >= 0.0 && positive(gain),
+                "curve point [{speed}, {gain}] needs a speed of 0 or more and a positive gain"
+            );
+        }
+        for (name, key) in [
+            ("precision_key", &self.precision_key),
+            ("scroll_key", &self.scroll_key),
+        ] {
+            if let Some(key) = key {
+                anyhow::ensure!(usage_from_name(key).is_some(), "unknown {name} '{key}'");
+            }
+        }
+        anyhow::ensure!(
+            self.scroll_usage().is_none() || self.scroll_usage() != self.precision_usage(),
+            "scroll_key and precision_key must differ"
+        );
+        Ok(())
+    }
+
+    pub fn precision_usage(&self) -> Option<u8> {
+        self.precision_key.as_deref().and_then(usage_from_name)
+    }
+
+    pub fn scroll_usage(&self) -> Option<u8> {
+        self.scroll_key.as_deref().and_then(usage_from_name)
+    }
+
+    /// Acceleration gain at `speed` counts per second
+    pub fn gain_at(&self, speed: f32) -> f32 {
+        let (Some(first), Some(last)) = (self.curve.first(), self.curve.last()) else {
+            return 1.0;
+        };
+        if speed <= first[0] {
+            return first[1];
+        }
+        if speed >= last[0] {
+            return last[1];
+        }
+        let i = self.curve.iter().position(|p| p[0] > speed).unwrap();
+        let ([s0, g0], [s1, g1]) = (self.curve[i - 1], self.curve[i]);
+        g0 + (g1 - g0) * (speed - s0) / (s1 - s0)
+    }
+}
+
+/// Motion events further apart than this count as starting from rest
+const ACCEL_WINDOW: Duration = Duration::from_millis(50);
+
+/// Applies `PointerSettings` to a stream of deltas, timing them to find the pointer's speed
+#[derive(Debug, Default)]
+pub struct PointerScaler {
+    settings: PointerSettings,
+    last: Option<Duration>,
+}
+
+impl PointerScaler {
+    pub fn new(settings: PointerSettings) -> Self {
+        Self {
+            settings,
+            last: None,
+        }
+    }
+
+    pub fn settings(&self) -> &PointerSettings {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: PointerSettings) {
+        self.settings = settings;
+    }
+
+    /// Scale one delta. `now` is any monotonic clock, as long as every call uses the same one;
+    /// `precise` while the precision key is held.
+    pub fn scale(&mut self, dx: f32, dy: f32, now: Duration, precise: bool) -> (f32, f32) {
+        let s = &self.settings;
+        let dt = self
+            .last
+            .replace(now)
+            .map_or(ACCEL_WINDOW, |last| now.saturating_sub(last))
+            .clamp(Duration::from_millis(1), ACCEL_WINDOW);
+        let speed = dx.hypot(dy) / dt.as_secs_f32();
+        let mut k = s.speed * s.gain_at(speed);
+        if precise {
+            k *= s.precision;
+        }
+        (dx * k * s.x_gain, dy * k * s.y_gain)
+    }
+}
+
+/// Turns pointer motion into scroll steps while scroll mode is held, like trackball drag-scroll
+#[derive(Debug, Default)]
+pub struct DragScroll {
+    pan: f32,
+    wheel: f32,
+    scrolled: bool,
+}
+
+impl DragScroll {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Motion in, `(wheel, pan)` steps out, one per `step` counts. Moving up scrolls up and
+    /// moving right scrolls right; the remainder carries over.
+    pub fn motion(&mut self, dx: f32, dy: f32, step: f32) -> (i32, i32) {
+        self.pan += dx;
+        self.wheel -= dy;
+        let pan = (self.pan / step).trunc();
+        let wheel = (self.wheel / step).trunc();
+        self.pan -= pan * step;
+        self.wheel -= wheel * step;
+        self.scrolled |= pan != 0.0 || wheel != 0.0;
+        (wheel as i32, pan as i32)
+    }
+
+    /// True once a step has come out since the last reset
+    pub fn scrolled(&self) -> bool {
+        self.scrolled
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Slower than this, in pixels per second, a flick doesn't coast and coasting stops
+const COAST_MIN_SPEED: f32 = 60.0;
+
+/// A gesture held still for longer than this before lifting doesn't coast
+const COAST_MAX_PAUSE: Duration = Duration::from_millis(60);
+
+/// Momentum for trackpad scrolling: follows a gesture's speed, then keeps it going with friction
+/// after the fingers lift
+#[derive(Debug, Default)]
+pub struct Inertia {
+    /// Pixels per second, x then y
+    velocity: (f32, f32),
+    last: Option<Duration>,
+    coasting: bool,
+}
+
+impl Inertia {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A scroll delta from a gesture still in progress
+    pub fn track(&mut self, dx: f32, dy: f32, now: Duration) {
+        self.coasting = false;
+        let Some(last) = self.last.replace(now) else {
+            return;
+        };
+        let dt = now
+            .saturating_sub(last)
+            .max(Duration::from_millis(1))
+            .as_secs_f32();
+        // Smoothed, so one uneven event doesn't decide the flick
+        let (vx, vy) = self.velocity;
+        self.velocity = ((vx + dx / dt) / 2.0, (vy + dy / dt) / 2.0);
+    }
+
+    /// The fingers lifted. Returns true if the gesture was fast enough to coast.
+    pub fn release(&mut self, now: Duration) -> bool {
+        let recent = self
+            .last
+            .is_some_and(|last| now.saturating_sub(last) <= COAST_MAX_PAUSE);
+        self.coasting = recent && self.speed() >= COAST_MIN_SPEED;
+        if self.coasting {
+            self.last = Some(now);
+        } else {
+            self.cancel();
+        }
+        self.coasting
+    }
+
+    /// Stop coasting and forget the gesture
+    pub fn cancel(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn is_coasting(&self) -> bool {
+        self.coasting
+    }
+
+    /// Distance coasted since the last call, in pixels. Coasting ends once the speed has decayed
+    /// below the minimum.
+    pub fn coast(&mut self, now: Duration, friction: f32) -> (f32, f32) {
+        if !self.coasting {
+            return (0.0, 0.0);
+        }
+        let last = self.last.replace(now).unwrap_or(now);
+        let dt = now.saturating_sub(last).as_secs_f32();
+        // Exact integral of v·e^(-friction·t) over the step
+        let decay = (-friction * dt).exp();
+        let travel = (1.0 - decay) / friction;
+        let (vx, vy) = self.velocity;
+        self.velocity = (vx * decay, vy * decay);
+        if self.speed() < COAST_MIN_SPEED {
+            self.cancel();
+        }
+        (vx * travel, vy * travel)
+    }
+
+    fn speed(&self) -> f32 {
+        self.velocity.0.hypot(self.velocity.1)
+    }
+}
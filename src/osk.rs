@@ -0,0 +1,247 @@
+// Clickable on-screen keyboard and touchpad for driving the host without physical input devices
+
+use std::collections::BTreeSet;
+
+use crate::canvas::{Canvas, Rect};
+use crate::hid::keyboard_usage_to_modifier;
+use crate::hud::{DIM, FG, HOT, WARN};
+use crate::ui::AppCmd;
+
+const KEY_BG: u32 = 0x002A_2E36;
+const KEY_ACTIVE: u32 = 0x0044_4B58;
+const PAD_BG: u32 = 0x001E_2127;
+const INK: u32 = 0x0014_161A;
+
+const MARGIN: i32 = 12;
+const GAP: i32 = 3;
+const BUTTON_H: i32 = 28;
+/// Pad contacts that travel less than this many pixels count as a tap (left click)
+const TAP_SLOP: f64 = 4.0;
+
+// (label, usage, width in quarter key units); every row is 60 quarters wide
+#[rustfmt::skip]
+const ROWS: &[&[(&str, u8, u8)]] = &[
+    &[("Esc", 0x29, 4), ("F1", 0x3A, 4), ("F2", 0x3B, 4), ("F3", 0x3C, 4), ("F4", 0x3D, 4),
+      ("F5", 0x3E, 4), ("F6", 0x3F, 4), ("F7", 0x40, 4), ("F8", 0x41, 4), ("F9", 0x42, 4),
+      ("F10", 0x43, 4), ("F11", 0x44, 4), ("F12", 0x45, 4), ("Del", 0x4C, 8)],
+    &[("`", 0x35, 4), ("1", 0x1E, 4), ("2", 0x1F, 4), ("3", 0x20, 4), ("4", 0x21, 4),
+      ("5", 0x22, 4), ("6", 0x23, 4), ("7", 0x24, 4), ("8", 0x25, 4), ("9", 0x26, 4),
+      ("0", 0x27, 4), ("-", 0x2D, 4), ("=", 0x2E, 4), ("Bksp", 0x2A, 8)],
+    &[("Tab", 0x2B, 6), ("Q", 0x14, 4), ("W", 0x1A, 4), ("E", 0x08, 4), ("R", 0x15, 4),
+      ("T", 0x17, 4), ("Y", 0x1C, 4), ("U", 0x18, 4), ("I", 0x0C, 4), ("O", 0x12, 4),
+      ("P", 0x13, 4), ("[", 0x2F, 4), ("]", 0x30, 4), ("\\", 0x31, 6)],
+    &[("Caps", 0x39, 7), ("A", 0x04, 4), ("S", 0x16, 4), ("D", 0x07, 4), ("F", 0x09, 4),
+      ("G", 0x0A, 4), ("H", 0x0B, 4), ("J", 0x0D, 4), ("K", 0x0E, 4), ("L", 0x0F, 4),
+      (";", 0x33, 4), ("'", 0x34, 4), ("Enter", 0x28, 9)],
+    &[("Shift", 0xE1, 9), ("Z", 0x1D, 4), ("X", 0x1B, 4), ("C", 0x06, 4), ("V", 0x19, 4),
+      ("B", 0x05, 4), ("N", 0x11, 4), ("M", 0x10, 4), (",", 0x36, 4), (".", 0x37, 4),
+      ("/", 0x38, 4), ("Shift", 0xE5, 11)],
+    &[("Ctrl", 0xE0, 5), ("Gui", 0xE3, 5), ("Alt", 0xE2, 5), ("Space", 0x2C, 24),
+      ("AltGr", 0xE6, 5), ("<", 0x50, 4), ("v", 0x51, 4), ("^", 0x52, 4), (">", 0x4F, 4)],
+];
+const ROW_QUARTERS: i32 = 60;
+
+enum Active {
+    Key(u8),
+    Pad { last: (f64, f64), travel: f64 },
+    Button(u8),
+}
+
+pub struct Osk {
+    keys: Vec<(Rect, &'static str, u8)>,
+    pad: Rect,
+    pad_buttons: [Rect; 2],
+    /// Modifiers clicked once; held on the host until the next regular key is released
+    latched: BTreeSet<u8>,
+    active: Option<Active>,
+    buttons: u8,
+}
+
+impl Osk {
+    pub fn new() -> Self {
+        Self {
+            keys: Vec::new(),
+            pad: Rect::default(),
+            pad_buttons: [Rect::default(); 2],
+            latched: BTreeSet::new(),
+            active: None,
+            buttons: 0,
+        }
+    }
+
+    /// Lay the keyboard out across `area` with the touchpad in whatever height remains
+    pub fn layout(&mut self, area: Rect) {
+        self.keys.clear();
+        let kb_w = area.w - 2 * MARGIN;
+        let quarter = kb_w as f32 / ROW_QUARTERS as f32;
+        let avail_h = area.h - 2 * MARGIN;
+        let row_h = ((quarter * 4.0) as i32).min(avail_h * 11 / 20 / ROWS.len() as i32);
+        if kb_w <= 0 || row_h <= GAP {
+            self.pad = Rect::default();
+            self.pad_buttons = [Rect::default(); 2];
+            return;
+        }
+
+        let mut y = area.y + MARGIN;
+        for row in ROWS {
+            let mut q = 0i32;
+            for &(label, usage, width) in row.iter() {
+                let x0 = area.x + MARGIN + (q as f32 * quarter) as i32;
+                let x1 = area.x + MARGIN + ((q + width as i32) as f32 * quarter) as i32;
+                self.keys
+                    .push((Rect::new(x0, y, x1 - x0 - GAP, row_h - GAP), label, usage));
+                q += width as i32;
+            }
+            y += row_h;
+        }
+
+        let pad_top = y + MARGIN;
+        let pad_h = area.y + area.h - MARGIN - BUTTON_H - GAP - pad_top;
+        if pad_h < 40 {
+            self.pad = Rect::default();
+            self.pad_buttons = [Rect::default(); 2];
+            return;
+        }
+        let pad_x = area.x + MARGIN;
+        self.pad = Rect::new(pad_x, pad_top, kb_w, pad_h);
+        let half = kb_w / 2;
+        let by = pad_top + pad_h + GAP;
+        self.pad_buttons = [
+            Rect::new(pad_x, by, half - GAP, BUTTON_H),
+            Rect::new(pad_x + half, by, kb_w - half, BUTTON_H),
+        ];
+    }
+
+    /// Draw keys (highlighting what the host currently has held) and the touchpad
+    pub fn draw(&self, c: &mut Canvas, held_mods: u8, held_keys: &[u8]) {
+        for &(r, label, usage) in &self.keys {
+            let held = match keyboard_usage_to_modifier(usage) {
+                Some(m) => held_mods & m != 0,
+                None => held_keys.contains(&usage),
+            };
+            let (bg, ink) = if self.latched.contains(&usage) {
+                (WARN, INK)
+            } else if held {
+                (HOT, INK)
+            } else if matches!(self.active, Some(Active::Key(u)) if u == usage) {
+                (KEY_ACTIVE, FG)
+            } else {
+                (KEY_BG, FG)
+            };
+            c.fill_rect(r.x, r.y, r.w, r.h, bg);
+            let scale = if Canvas::text_width(label, 2) <= r.w - 6 && r.h >= 20 {
+                2
+            } else {
+                1
+            };
+            c.text_centered(r, scale, ink, label);
+        }
+
+        if self.pad.w > 0 {
+            let p = self.pad;
+            c.fill_rect(p.x, p.y, p.w, p.h, PAD_BG);
+            c.stroke_rect(p, DIM);
+            c.text_centered(p, 2, DIM, "touchpad");
+            for (i, r) in self.pad_buttons.iter().enumerate() {
+                let on = self.buttons & (1 << (i * 2)) != 0;
+                c.fill_rect(r.x, r.y, r.w, r.h, if on { HOT } else { KEY_BG });
+                c.text_centered(*r, 2, if on { INK } else { FG }, ["L", "R"][i]);
+            }
+        }
+    }
+
+    pub fn press(&mut self, x: f64, y: f64) -> Vec<AppCmd> {
+        let mut out = Vec::new();
+        if self.active.is_some() {
+            return out;
+        }
+        if let Some(&(_, _, usage)) = self.keys.iter().find(|(r, _, _)| r.contains(x, y)) {
+            if keyboard_usage_to_modifier(usage).is_some() {
+                // Modifiers toggle a latch instead of acting on press/release
+                if self.latched.remove(&usage) {
+                    out.push(AppCmd::KeyUp(usage));
+                } else {
+                    self.latched.insert(usage);
+                    out.push(AppCmd::KeyDown(usage));
+                }
+            } else {
+                out.push(AppCmd::KeyDown(usage));
+                self.active = Some(Active::Key(usage));
+            }
+        } else if self.pad.contains(x, y) {
+            self.active = Some(Active::Pad {
+                last: (x, y),
+                travel: 0.0,
+            });
+        } else if let Some(i) = self.pad_buttons.iter().position(|r| r.contains(x, y)) {
+            // Left and right map to button bits 0 and 2
+            let bit = 1 << (i * 2);
+            self.buttons |= bit;
+            self.active = Some(Active::Button(bit));
+            out.push(self.mouse(0.0, 0.0));
+        }
+        out
+    }
+
+    pub fn moved(&mut self, x: f64, y: f64) -> Vec<AppCmd> {
+        let Some(Active::Pad { last, travel }) = self.active.as_mut() else {
+            return Vec::new();
+        };
+        let (dx, dy) = (x - last.0, y - last.1);
+        *last = (x, y);
+        *travel += dx.abs() + dy.abs();
+        vec![self.mouse(dx as f32, dy as f32)]
+    }
+
+    pub fn release(&mut self) -> Vec<AppCmd> {
+        let mut out = Vec::new();
+        match self.active.take() {
+            Some(Active::Key(usage)) => {
+                out.push(AppCmd::KeyUp(usage));
+                // A regular key consumes the latched modifiers
+                for m in std::mem::take(&mut self.latched) {
+                    out.push(AppCmd::KeyUp(m));
+                }
+            }
+            Some(Active::Pad { travel, .. }) if travel < TAP_SLOP => {
+                self.buttons |= 0b001;
+                out.push(self.mouse(0.0, 0.0));
+                self.buttons &= !0b001;
+                out.push(self.mouse(0.0, 0.0));
+            }
+            Some(Active::Button(bit)) => {
+                self.buttons &= !bit;
+                out.push(self.mouse(0.0, 0.0));
+            }
+            Some(Active::Pad { .. }) | None => {}
+        }
+        out
+    }
+
+    /// Release anything the keyboard or pad is holding, e.g. on focus loss
+    pub fn reset(&mut self) -> Vec<AppCmd> {
+        // An interrupted pad contact is not a tap
+        if matches!(self.active, Some(Active::Pad { .. })) {
+            self.active = None;
+        }
+        let mut out = self.release();
+        for m in std::mem::take(&mut self.latched) {
+            out.push(AppCmd::KeyUp(m));
+        }
+        if self.buttons != 0 {
+            self.buttons = 0;
+            out.push(self.mouse(0.0, 0.0));
+        }
+        out
+    }
+
+    fn mouse(&self, dx: f32, dy: f32) -> AppCmd {
+        AppCmd::Mouse {
+            buttons: self.buttons,
+            dx,
+            dy,
+            wheel: 0,
+            pan: 0,
+        }
+    }
+}
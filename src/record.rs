@@ -0,0 +1,426 @@
+// Recording and replay of the command stream reaching the BLE task, for reproducing host bugs
+//
+// Recordings are JSON lines (`{"t_us":..,"cmd":..}`) or, for `.bin` paths, a compact binary
+// stream behind a `BLPR` header. Timestamps are microseconds since recording started.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::input::{InputClosed, InputTx};
+use crate::status::Status;
+use crate::ui::AppCmd;
+
+const MAGIC: &[u8; 4] = b"BLPR";
+const VERSION: u8 = 1;
+/// How often the recorder flushes what it has written
+const FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+
+// Binary record tags
+const TAG_EXIT: u8 = 0;
+const TAG_MOUSE: u8 = 1;
+const TAG_KEY_DOWN: u8 = 2;
+const TAG_KEY_UP: u8 = 3;
+const TAG_BATTERY: u8 = 4;
+// A mouse record with a trailing pan byte; pan-free motion keeps the older `TAG_MOUSE` layout
+const TAG_MOUSE_PAN: u8 = 5;
+const TAG_CONSUMER_DOWN: u8 = 6;
+const TAG_CONSUMER_UP: u8 = 7;
+const TAG_MOUSE_TO: u8 = 8;
+
+#[derive(Serialize, Deserialize)]
+struct Entry<C> {
+    t_us: u64,
+    cmd: C,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Binary,
+}
+
+impl Format {
+    fn for_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("bin") => Format::Binary,
+            _ => Format::Json,
+        }
+    }
+}
+
+/// Appends every received command to a file, stamped with a monotonic clock. The file is
+/// written on a thread of its own, so a slow disk never holds up the BLE task.
+pub struct Recorder {
+    tx: Option<mpsc::Sender<(u64, AppCmd)>>,
+    writer: Option<JoinHandle<()>>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+        let format = Format::for_path(path);
+        let mut out = BufWriter::new(file);
+        if format == Format::Binary {
+            out.write_all(MAGIC)?;
+            out.write_all(&[VERSION])?;
+            out.flush()?;
+        }
+        let (tx, rx) = mpsc::channel();
+        let writer = std::thread::Builder::new()
+            .name("recorder".into())
+            .spawn(move || {
+                if let Err(e) = write_all(rx, out, format) {
+                    tracing::warn!(error = %e, "Recording failed, stopping it");
+                }
+            })
+            .context("start recorder thread")?;
+        tracing::info!(path = %path.display(), ?format, "Recording commands");
+        Ok(Self {
+            tx: Some(tx),
+            writer: Some(writer),
+            start: Instant::now(),
+        })
+    }
+
+    /// Queue `cmd` for the file; false once the writer has given up
+    pub fn write(&self, cmd: &AppCmd) -> bool {
+        let t_us = self.start.elapsed().as_micros() as u64;
+        self.tx
+            .as_ref()
+            .is_some_and(|tx| tx.send((t_us, cmd.clone())).is_ok())
+    }
+}
+
+impl Drop for Recorder {
+    /// Close the queue and wait for the writer to flush what is left
+    fn drop(&mut self) {
+        self.tx = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Write queued commands until the queue closes. Flushed on a timer rather than per command,
+/// so a crash loses at most the last `FLUSH_INTERVAL` of them.
+fn write_all(
+    rx: mpsc::Receiver<(u64, AppCmd)>,
+    mut out: BufWriter<File>,
+    format: Format,
+) -> io::Result<()> {
+    let mut dirty = false;
+    loop {
+        match rx.recv_timeout(FLUSH_INTERVAL) {
+            Ok((t_us, cmd)) => {
+                match format {
+                    Format::Json => {
+                        serde_json::to_writer(&mut out, &Entry { t_us, cmd })?;
+                        out.write_all(b"\n")?;
+                    }
+                    Format::Binary => {
+                        out.write_all(&t_us.to_le_bytes())?;
+                        encode(&cmd, &mut out)?;
+                    }
+                }
+                dirty = true;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) if dirty => {
+                out.flush()?;
+                dirty = false;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return out.flush(),
+        }
+    }
+}
+
+fn encode(cmd: &AppCmd, out: &mut impl Write) -> io::Result<()> {
+    match *cmd {
+        AppCmd::Exit => out.write_all(&[TAG_EXIT]),
+        AppCmd::Mouse {
+            buttons,
+            dx,
+            dy,
+            wheel,
+            pan,
+        } => {
+            let tag = if pan == 0 { TAG_MOUSE } else { TAG_MOUSE_PAN };
+            out.write_all(&[tag, buttons])?;
+            out.write_all(&dx.to_le_bytes())?;
+            out.write_all(&dy.to_le_bytes())?;
+            out.write_all(&wheel.to_le_bytes())?;
+            if pan != 0 {
+                out.write_all(&pan.to_le_bytes())?;
+            }
+            Ok(())
+        }
+        AppCmd::KeyDown(usage) => out.write_all(&[TAG_KEY_DOWN, usage]),
+        AppCmd::KeyUp(usage) => out.write_all(&[TAG_KEY_UP, usage]),
+        AppCmd::ConsumerDown(usage) => {
+            out.write_all(&[TAG_CONSUMER_DOWN])?;
+            out.write_all(&usage.to_le_bytes())
+        }
+        AppCmd::ConsumerUp(usage) => {
+            out.write_all(&[TAG_CONSUMER_UP])?;
+            out.write_all(&usage.to_le_bytes())
+        }
+        AppCmd::MouseTo { x, y } => {
+            out.write_all(&[TAG_MOUSE_TO])?;
+            out.write_all(&x.to_le_bytes())?;
+            out.write_all(&y.to_le_bytes())
+        }
+        AppCmd::Battery(level) => out.write_all(&[TAG_BATTERY, level]),
+    }
+}
+
+/// Next binary record, or None at a clean end of file
+fn decode(r: &mut impl Read) -> io::Result<Option<(u64, AppCmd)>> {
+    let mut t = [0u8; 8];
+    match r.read_exact(&mut t) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut byte = || -> io::Result<u8> {
+        let mut b = [0u8; 1];
+        r.read_exact(&mut b)?;
+        Ok(b[0])
+    };
+    let cmd = match byte()? {
+        TAG_EXIT => AppCmd::Exit,
+        tag @ (TAG_MOUSE | TAG_MOUSE_PAN) => {
+            let buttons = byte()?;
+            let mut f = [0u8; 10];
+            let len = if tag == TAG_MOUSE { 9 } else { 10 };
+            r.read_exact(&mut f[..len])?;
+            AppCmd::Mouse {
+                buttons,
+                dx: f32::from_le_bytes(f[0..4].try_into().unwrap()),
+                dy: f32::from_le_bytes(f[4..8].try_into().unwrap()),
+                wheel: f[8] as i8,
+                pan: f[9] as i8,
+            }
+        }
+        TAG_KEY_DOWN => AppCmd::KeyDown(byte()?),
+        TAG_KEY_UP => AppCmd::KeyUp(byte()?),
+        TAG_BATTERY => AppCmd::Battery(byte()?),
+        TAG_CONSUMER_DOWN => AppCmd::ConsumerDown(u16::from_le_bytes([byte()?, byte()?])),
+        TAG_CONSUMER_UP => AppCmd::ConsumerUp(u16::from_le_bytes([byte()?, byte()?])),
+        TAG_MOUSE_TO => AppCmd::MouseTo {
+            x: u16::from_le_bytes([byte()?, byte()?]),
+            y: u16::from_le_bytes([byte()?, byte()?]),
+        },
+        tag => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown record tag {tag}"),
+            ));
+        }
+    };
+    Ok(Some((u64::from_le_bytes(t), cmd)))
+}
+
+/// Read a recording in either format, with times relative to its first command
+fn load(path: &Path) -> anyhow::Result<Vec<(Duration, AppCmd)>> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut entries = Vec::new();
+    if reader.fill_buf()?.starts_with(MAGIC) {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+        anyhow::ensure!(
+            header[4] == VERSION,
+            "unsupported recording version {}",
+            header[4]
+        );
+        while let Some(entry) = decode(&mut reader)? {
+            entries.push(entry);
+        }
+    } else {
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let e: Entry<AppCmd> =
+                serde_json::from_str(&line).with_context(|| format!("line {}", n + 1))?;
+            entries.push((e.t_us, e.cmd));
+        }
+    }
+    let first = entries.first().map_or(0, |&(t, _)| t);
+    Ok(entries
+        .into_iter()
+        .map(|(t, cmd)| (Duration::from_micros(t.saturating_sub(first)), cmd))
+        .collect())
+}
+
+pub struct ReplayOptions {
+    pub path: PathBuf,
+    /// Playback rate; 1.0 keeps the recorded timing
+    pub speed: f64,
+    /// Send commands back to back, ignoring the recorded gaps
+    pub collapse: bool,
+    /// Start over after the last command
+    pub repeat: bool,
+}
+
+/// Play a recording into the input channel once a host is subscribed, since the BLE task drops
+/// input until then. The recording already went through remapping, macros and script hooks, so
+/// it is played in after them. Aborting the task releases whatever it holds.
+pub async fn replay(input: InputTx, mut status: watch::Receiver<Status>, opts: ReplayOptions) {
+    let entries = match load(&opts.path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::error!(error = %format!("{e:#}"), "Cannot load recording");
+            return;
+        }
+    };
+    tracing::info!(
+        path = %opts.path.display(),
+        commands = entries.len(),
+        speed = opts.speed,
+        collapse = opts.collapse,
+        repeat = opts.repeat,
+        "Replaying recording"
+    );
+    if !status.borrow().connected {
+        tracing::info!("Replay waiting for a host to connect");
+        if status.wait_for(|s| s.connected).await.is_err() {
+            return;
+        }
+    }
+    let mut player = Player {
+        input,
+        held: BTreeSet::new(),
+        consumer: BTreeSet::new(),
+        buttons: 0,
+    };
+    loop {
+        let start = tokio::time::Instant::now();
+        for (at, cmd) in &entries {
+            if opts.collapse {
+                // Nothing else awaits on this path; without a yield the task could not be aborted
+                tokio::task::yield_now().await;
+            } else {
+                tokio::time::sleep_until(start + at.div_f64(opts.speed)).await;
+            }
+            if player.play(cmd).is_err() {
+                return;
+            }
+        }
+        // A recording cut off mid-press must not leave keys down between passes
+        player.release_all();
+        if !opts.repeat || entries.is_empty() {
+            break;
+        }
+    }
+    tracing::info!("Replay finished");
+}
+
+/// Replayed state on the host, so an interrupted replay can let go of it
+struct Player {
+    input: InputTx,
+    held: BTreeSet<u8>,
+    consumer: BTreeSet<u16>,
+    buttons: u8,
+}
+
+impl Player {
+    fn play(&mut self, cmd: &AppCmd) -> Result<(), InputClosed> {
+        match *cmd {
+            AppCmd::KeyDown(usage) => {
+                self.held.insert(usage);
+            }
+            AppCmd::KeyUp(usage) => {
+                self.held.remove(&usage);
+            }
+            AppCmd::ConsumerDown(usage) => {
+                self.consumer.insert(usage);
+            }
+            AppCmd::ConsumerUp(usage) => {
+                self.consumer.remove(&usage);
+            }
+            AppCmd::Mouse { buttons, .. } => self.buttons = buttons,
+            AppCmd::MouseTo { .. } => {}
+            // Shutdown and battery level belong to the recording session, not the host input
+            AppCmd::Exit | AppCmd::Battery(_) => return Ok(()),
+        }
+        self.input.send_processed(cmd.clone())
+    }
+
+    fn release_all(&mut self) {
+        let keys = std::mem::take(&mut self.held);
+        for &usage in &keys {
+            let _ = self.input.send_processed(AppCmd::KeyUp(usage));
+        }
+        for usage in std::mem::take(&mut self.consumer) {
+            let _ = self.input.send_processed(AppCmd::ConsumerUp(usage));
+        }
+        let buttons = std::mem::take(&mut self.buttons);
+        if buttons != 0 {
+            let _ = self.input.send_processed(AppCmd::Mouse {
+                buttons: 0,
+                dx: 0.0,
+                dy: 0.0,
+                wheel: 0,
+                pan: 0,
+            });
+        }
+        if !keys.is_empty() || buttons != 0 {
+            tracing::info!(?keys, buttons = %format!("{buttons:#05b}"), "Replay released held input");
+        }
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn collapsed_looping_replay_can_be_aborted() {
+        let path = std::env::temp_dir().join(format!("bluper-replay-{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            "{\"t_us\":0,\"cmd\":{\"key_down\":4}}\n{\"t_us\":10,\"cmd\":{\"key_up\":4}}\n",
+        )
+        .unwrap();
+        let (input, _rx) = crate::input::channel();
+        let (_status_tx, status) = watch::channel(Status {
+            connected: true,
+            ..Default::default()
+        });
+        let opts = ReplayOptions {
+            path: path.clone(),
+            speed: 1.0,
+            collapse: true,
+            repeat: true,
+        };
+        let replay = tokio::spawn(replay(input, status, opts));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        replay.abort();
+        let joined = tokio::time::timeout(Duration::from_secs(1), replay).await;
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            joined
+                .expect("replay ignored abort")
+                .unwrap_err()
+                .is_cancelled()
+        );
+    }
+}
@@ -0,0 +1,637 @@
+// Key remapping and layers between the input sources and the BLE task
+//
+// A remap config is a base layer plus named layers, loaded from TOML:
+//
+//     [base]
+//     capslock = "ctrl"
+//     menu = "mo(fn)"
+//     a = "th(a, ctrl)"
+//
+//     [layers.fn]
+//     h = "left"
+//
+//     [combos]
+//     "j+k" = "esc"
+//
+// Keys a layer does not mention fall through to the layers below it, then to the base layer,
+// then to themselves.
+//
+// Tap-hold keys and combos are only decided some time after they go down, so the engine is a
+// timed state machine: raw events queue up in an inbox while a decision is pending, and the
+// caller reports the time with every event and calls `tick` at `deadline`.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::hid::{parse_chord, usage_from_name};
+use crate::typing::KeyEvent;
+
+/// What a key does while pressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Key(u8),
+    /// `mo(name)`: the layer is active while the key is held
+    Momentary(usize),
+    /// `tg(name)`: each press switches the layer on or off
+    Toggle(usize),
+    /// `th(tap, hold)`: `tap` on a quick tap, `hold` once held past the tapping term
+    TapHold {
+        tap: u8,
+        hold: Hold,
+    },
+    /// `os(mod)`: a tap applies the modifier to the next key only; holding it acts normally
+    OneShot(u8),
+    /// `none`: the key is swallowed
+    Disabled,
+}
+
+/// Hold side of a tap-hold key: a key (usually a modifier) or `mo(layer)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hold {
+    Key(u8),
+    Layer(usize),
+}
+
+/// Timing for tap-hold keys, combos and one-shot modifiers
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RemapSettings {
+    /// A tap-hold key held this long becomes its hold action
+    pub tapping_term_ms: u64,
+    /// Another key pressed and released inside a tap-hold key's press makes it a hold
+    pub permissive_hold: bool,
+    /// Combo keys must all go down within this window
+    pub combo_term_ms: u64,
+    /// An unused one-shot modifier lifts after this long (0 keeps it until the next key)
+    pub oneshot_timeout_ms: u64,
+}
+
+impl Default for RemapSettings {
+    fn default() -> Self {
+        Self {
+            tapping_term_ms: 200,
+            permissive_hold: true,
+            combo_term_ms: 50,
+            oneshot_timeout_ms: 3000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RemapFile {
+    #[serde(default)]
+    settings: RemapSettings,
+    #[serde(default)]
+    base: BTreeMap<String, String>,
+    #[serde(default)]
+    layers: BTreeMap<String, BTreeMap<String, String>>,
+    #[serde(default)]
+    combos: BTreeMap<String, String>,
+}
+
+/// Parsed remap config: per-layer actions keyed by physical usage
+#[derive(Debug, Clone, Default)]
+pub struct RemapConfig {
+    pub settings: RemapSettings,
+    base: HashMap<u8, Action>,
+    layers: Vec<(String, HashMap<u8, Action>)>,
+    combos: Vec<(Vec<u8>, Action)>,
+}
+
+impl RemapConfig {
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        let file: RemapFile = toml::from_str(text)?;
+        let names: Vec<String> = file.layers.keys().cloned().collect();
+        let parse_layer = |entries: &BTreeMap<String, String>| -> anyhow::Result<_> {
+            entries
+                .iter()
+                .map(|(from, to)| {
+                    let usage =
+                        usage_from_name(from).with_context(|| format!("unknown key '{from}'"))?;
+                    let action = parse_action(to, &names)
+                        .with_context(|| format!("bad action '{to}' for '{from}'"))?;
+                    Ok((usage, action))
+                })
+                .collect::<anyhow::Result<HashMap<_, _>>>()
+        };
+        let base = parse_layer(&file.base).context("[base]")?;
+        let layers = file
+            .layers
+            .iter()
+            .map(|(name, entries)| {
+                let layer = parse_layer(entries).with_context(|| format!("[layers.{name}]"))?;
+                Ok((name.clone(), layer))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let combos = file
+            .combos
+            .iter()
+            .map(|(keys, to)| {
+                let chord = parse_chord(keys)
+                    .filter(|c| c.len() >= 2)
+                    .with_context(|| format!("[combos] '{keys}' needs two or more known keys"))?;
+                let action = parse_action(to, &names)
+                    .with_context(|| format!("[combos] bad action '{to}' for '{keys}'"))?;
+                anyhow::ensure!(
+                    !matches!(action, Action::TapHold { .. }),
+                    "[combos] '{keys}' cannot be a tap-hold"
+                );
+                Ok((chord, action))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            settings: file.settings,
+            base,
+            layers,
+            combos,
+        })
+    }
+
+    pub fn layer_names(&self) -> impl Iterator<Item = &str> {
+        self.layers.iter().map(|(name, _)| name.as_str())
+    }
+}
+
+fn parse_action(s: &str, layers: &[String]) -> anyhow::Result<Action> {
+    let s = s.trim();
+    let layer = |name: &str| {
+        layers
+            .iter()
+            .position(|l| l == name.trim())
+            .with_context(|| format!("no layer named '{}'", name.trim()))
+    };
+    let key = |name: &str| usage_from_name(name).with_context(|| format!("unknown key '{name}'"));
+    let call = |f: &str| s.strip_prefix(f).and_then(|r| r.strip_suffix(')'));
+    if let Some(name) = call("mo(") {
+        return Ok(Action::Momentary(layer(name)?));
+    }
+    if let Some(name) = call("tg(") {
+        return Ok(Action::Toggle(layer(name)?));
+    }
+    if let Some(name) = call("os(") {
+        return Ok(Action::OneShot(key(name)?));
+    }
+    if let Some(args) = call("th(") {
+        let (tap, hold) = args
+            .split_once(',')
+            .context("th() takes a tap key and a hold action")?;
+        let hold = match hold
+            .trim()
+            .strip_prefix("mo(")
+            .and_then(|r| r.strip_suffix(')'))
+        {
+            Some(name) => Hold::Layer(layer(name)?),
+            None => Hold::Key(key(hold)?),
+        };
+        return Ok(Action::TapHold {
+            tap: key(tap)?,
+            hold,
+        });
+    }
+    if s.eq_ignore_ascii_case("none") {
+        return Ok(Action::Disabled);
+    }
+    Ok(Action::Key(key(s)?))
+}
+
+/// What a held physical key resolved to when it went down
+#[derive(Debug, Clone, Copy)]
+enum Held {
+    Key(u8),
+    Layer(usize),
+    /// Whether another key went down while it was held, making it a plain modifier
+    OneShot {
+        usage: u8,
+        used: bool,
+    },
+    /// Shares the output of a fired combo; the first member released lets it go
+    Combo(u64),
+    Nothing,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Input {
+    usage: u8,
+    down: bool,
+    at: Duration,
+    /// False once a combo attempt has passed this press over
+    combo: bool,
+}
+
+/// A decision waiting on later events or the clock
+#[derive(Debug, Clone)]
+enum Pending {
+    TapHold {
+        usage: u8,
+        tap: u8,
+        hold: Hold,
+        since: Duration,
+    },
+    Combo {
+        keys: Vec<(u8, Duration)>,
+    },
+}
+
+/// Running remap state: active layers, pending decisions and what every held key resolved to
+pub struct Remapper {
+    config: RemapConfig,
+    /// Per layer: momentary keys holding it plus whether it is toggled on
+    momentary: Vec<u32>,
+    toggled: Vec<bool>,
+    /// Layers in activation order; the last one wins
+    stack: Vec<usize>,
+    held: HashMap<u8, Held>,
+    /// Output usages and how many physical keys currently produce each
+    out: HashMap<u8, u32>,
+    pending: Option<Pending>,
+    /// Raw events not yet consumed, oldest first
+    inbox: VecDeque<Input>,
+    /// One-shot modifiers waiting for the next key, with when they were armed
+    armed: Vec<(u8, Duration)>,
+    combos_down: HashMap<u64, Held>,
+    next_combo: u64,
+}
+
+impl Remapper {
+    pub fn new(config: RemapConfig) -> Self {
+        let n = config.layers.len();
+        Self {
+            config,
+            momentary: vec![0; n],
+            toggled: vec![false; n],
+            stack: Vec::new(),
+            held: HashMap::new(),
+            out: HashMap::new(),
+            pending: None,
+            inbox: VecDeque::new(),
+            armed: Vec::new(),
+            combos_down: HashMap::new(),
+            next_combo: 0,
+        }
+    }
+
+    /// Action for a physical key under the currently active layers
+    pub fn resolve(&self, usage: u8) -> Action {
+        self.stack
+            .iter()
+            .rev()
+            .find_map(|&l| self.config.layers[l].1.get(&usage))
+            .or_else(|| self.config.base.get(&usage))
+            .copied()
+            .unwrap_or(Action::Key(usage))
+    }
+
+    /// `now` is any monotonic clock, as long as every call uses the same one
+    pub fn key_down(&mut self, usage: u8, now: Duration) -> Vec<KeyEvent> {
+        self.push(usage, true, now)
+    }
+
+    pub fn key_up(&mut self, usage: u8, now: Duration) -> Vec<KeyEvent> {
+        self.push(usage, false, now)
+    }
+
+    /// Let timers that expired by `now` take effect
+    pub fn tick(&mut self, now: Duration) -> Vec<KeyEvent> {
+        let mut events = Vec::new();
+        self.run(now, &mut events);
+        events
+    }
+
+    /// When `tick` next has something to do
+    pub fn deadline(&self) -> Option<Duration> {
+        let s = &self.config.settings;
+        let pending = self.pending.as_ref().map(|p| match p {
+            Pending::TapHold { since, .. } => *since + Duration::from_millis(s.tapping_term_ms),
+            Pending::Combo { keys } => keys[0].1 + Duration::from_millis(s.combo_term_ms),
+        });
+        let oneshot = (s.oneshot_timeout_ms > 0)
+            .then(|| self.armed.iter().map(|&(_, at)| at).min())
+            .flatten()
+            .map(|at| at + Duration::from_millis(s.oneshot_timeout_ms));
+        pending.into_iter().chain(oneshot).min()
+    }
+
+    /// Release every output still down and forget all state, e.g. before the remapper is
+    /// replaced; keys still physically held are ignored when they come up
+    pub fn release_all(&mut self) -> Vec<KeyEvent> {
+        let mut outs: Vec<u8> = self.out.keys().copied().collect();
+        outs.sort_unstable();
+        *self = Self::new(std::mem::take(&mut self.config));
+        outs.into_iter().map(KeyEvent::Up).collect()
+    }
+
+    /// Names of the active layers, bottom to top
+    pub fn active_layers(&self) -> Vec<&str> {
+        self.stack
+            .iter()
+            .map(|&l| self.config.layers[l].0.as_str())
+            .collect()
+    }
+
+    fn push(&mut self, usage: u8, down: bool, now: Duration) -> Vec<KeyEvent> {
+        self.inbox.push_back(Input {
+            usage,
+            down,
+            at: now,
+            combo: true,
+        });
+        let mut events = Vec::new();
+        self.run(now, &mut events);
+        events
+    }
+
+    /// Consume the inbox until it is empty or a decision has to wait for more input
+    fn run(&mut self, now: Duration, events: &mut Vec<KeyEvent>) {
+        loop {
+            let progressed = match self.pending.clone() {
+                None => match self.inbox.pop_front() {
+                    Some(input) => {
+                        self.handle(input, events);
+                        true
+                    }
+                    None => false,
+                },
+                Some(Pending::TapHold {
+                    usage,
+                    tap,
+                    hold,
+                    since,
+                }) => self.decide_tap_hold(usage, tap, hold, since, now, events),
+                Some(Pending::Combo { keys }) => self.decide_combo(keys, now, events),
+            };
+            if !progressed {
+                break;
+            }
+        }
+        self.expire_oneshots(now, events);
+    }
+
+    fn handle(&mut self, input: Input, events: &mut Vec<KeyEvent>) {
+        let usage = input.usage;
+        if !input.down {
+            if let Some(held) = self.held.remove(&usage) {
+                self.apply_up(held, input.at, events);
+            }
+            return;
+        }
+        if self.held.contains_key(&usage) {
+            return;
+        }
+        if input.combo
+            && self
+                .config
+                .combos
+                .iter()
+                .any(|(keys, _)| keys.contains(&usage))
+        {
+            self.pending = Some(Pending::Combo {
+                keys: vec![(usage, input.at)],
+            });
+            return;
+        }
+        match self.resolve(usage) {
+            Action::TapHold { tap, hold } => {
+                self.pending = Some(Pending::TapHold {
+                    usage,
+                    tap,
+                    hold,
+                    since: input.at,
+                });
+            }
+            action => {
+                let held = self.apply_down(action, events);
+                self.held.insert(usage, held);
+            }
+        }
+    }
+
+    /// Settle a tap-hold from the events after its press; false if it has to keep waiting
+    fn decide_tap_hold(
+        &mut self,
+        usage: u8,
+        tap: u8,
+        hold: Hold,
+        since: Duration,
+        now: Duration,
+        events: &mut Vec<KeyEvent>,
+    ) -> bool {
+        let deadline = since + Duration::from_millis(self.config.settings.tapping_term_ms);
+        let mut is_hold = deadline <= now;
+        let mut released_at = None;
+        for (i, e) in self.inbox.iter().enumerate() {
+            if e.at >= deadline {
+                // The timer fired before this event arrived
+                is_hold = true;
+                break;
+            }
+            if !e.down && e.usage == usage {
+                is_hold = false;
+                released_at = Some(i);
+                break;
+            }
+            let nested_tap = !e.down
+                && self
+                    .inbox
+                    .iter()
+                    .take(i)
+                    .any(|d| d.down && d.usage == e.usage);
+            if self.config.settings.permissive_hold && nested_tap {
+                is_hold = true;
+                break;
+            }
+        }
+        if let Some(i) = released_at {
+            self.pending = None;
+            self.inbox.remove(i);
+            let held = self.apply_down(Action::Key(tap), events);
+            self.apply_up(held, since, events);
+            return true;
+        }
+        if !is_hold {
+            return false;
+        }
+        self.pending = None;
+        let action = match hold {
+            Hold::Key(k) => Action::Key(k),
+            Hold::Layer(l) => Action::Momentary(l),
+        };
+        let held = self.apply_down(action, events);
+        self.held.insert(usage, held);
+        true
+    }
+
+    /// Fire, extend or give up on a combo from the next event; false if it has to keep waiting
+    fn decide_combo(
+        &mut self,
+        mut keys: Vec<(u8, Duration)>,
+        now: Duration,
+        events: &mut Vec<KeyEvent>,
+    ) -> bool {
+        let deadline = keys[0].1 + Duration::from_millis(self.config.settings.combo_term_ms);
+        let next = self.inbox.front().copied().filter(|e| e.at < deadline);
+        match next {
+            Some(e) if e.down && !keys.iter().any(|&(k, _)| k == e.usage) => {
+                self.inbox.pop_front();
+                keys.push((e.usage, e.at));
+                let usages: Vec<u8> = keys.iter().map(|&(k, _)| k).collect();
+                let is = |c: &[u8]| c.len() == usages.len() && usages.iter().all(|u| c.contains(u));
+                let within = |c: &[u8]| usages.iter().all(|u| c.contains(u));
+                if let Some(&(_, action)) = self.config.combos.iter().find(|(c, _)| is(c)) {
+                    self.pending = None;
+                    let held = self.apply_down(action, events);
+                    let id = self.next_combo;
+                    self.next_combo += 1;
+                    self.combos_down.insert(id, held);
+                    for u in usages {
+                        self.held.insert(u, Held::Combo(id));
+                    }
+                } else if self.config.combos.iter().any(|(c, _)| within(c)) {
+                    self.pending = Some(Pending::Combo { keys });
+                } else {
+                    // Not a combo after all: the last press goes back behind the others
+                    let (last, at) = keys.pop().unwrap();
+                    self.inbox.push_front(Input {
+                        usage: last,
+                        down: true,
+                        at,
+                        combo: true,
+                    });
+                    self.flush_combo(keys);
+                }
+                true
+            }
+            None if deadline > now => false,
+            _ => {
+                self.flush_combo(keys);
+                true
+            }
+        }
+    }
+
+    /// Replay presses a combo swallowed as ordinary ones
+    fn flush_combo(&mut self, keys: Vec<(u8, Duration)>) {
+        self.pending = None;
+        for &(usage, at) in keys.iter().rev() {
+            self.inbox.push_front(Input {
+                usage,
+                down: true,
+                at,
+                combo: false,
+            });
+        }
+    }
+
+    fn apply_down(&mut self, action: Action, events: &mut Vec<KeyEvent>) -> Held {
+        match action {
+            Action::Key(out) => {
+                self.press(out, events);
+                if !is_modifier(out) {
+                    for held in self.held.values_mut() {
+                        if let Held::OneShot { used, .. } = held {
+                            *used = true;
+                        }
+                    }
+                    for (usage, _) in std::mem::take(&mut self.armed) {
+                        self.release(usage, events);
+                    }
+                }
+                Held::Key(out)
+            }
+            Action::Momentary(layer) => {
+                self.momentary[layer] += 1;
+                self.refresh(layer);
+                Held::Layer(layer)
+            }
+            Action::Toggle(layer) => {
+                self.toggled[layer] = !self.toggled[layer];
+                self.refresh(layer);
+                Held::Nothing
+            }
+            Action::OneShot(usage) => {
+                // Tapping an armed one-shot again cancels it
+                if let Some(i) = self.armed.iter().position(|&(u, _)| u == usage) {
+                    self.armed.remove(i);
+                    self.release(usage, events);
+                    return Held::Nothing;
+                }
+                self.press(usage, events);
+                Held::OneShot { usage, used: false }
+            }
+            // Tap-holds are decided before they get here; combos cannot be tap-holds
+            Action::TapHold { .. } | Action::Disabled => Held::Nothing,
+        }
+    }
+
+    fn apply_up(&mut self, held: Held, at: Duration, events: &mut Vec<KeyEvent>) {
+        match held {
+            Held::Key(out)
+            | Held::OneShot {
+                usage: out,
+                used: true,
+            } => self.release(out, events),
+            Held::OneShot { usage, used: false } => self.armed.push((usage, at)),
+            Held::Layer(layer) => {
+                self.momentary[layer] -= 1;
+                self.refresh(layer);
+            }
+            Held::Combo(id) => {
+                if let Some(held) = self.combos_down.remove(&id) {
+                    self.apply_up(held, at, events);
+                }
+            }
+            Held::Nothing => {}
+        }
+    }
+
+    fn expire_oneshots(&mut self, now: Duration, events: &mut Vec<KeyEvent>) {
+        let timeout = self.config.settings.oneshot_timeout_ms;
+        if timeout == 0 {
+            return;
+        }
+        let timeout = Duration::from_millis(timeout);
+        let (expired, armed) = std::mem::take(&mut self.armed)
+            .into_iter()
+            .partition(|&(_, at)| at + timeout <= now);
+        self.armed = armed;
+        for (usage, _) in expired {
+            self.release(usage, events);
+        }
+    }
+
+    fn press(&mut self, out: u8, events: &mut Vec<KeyEvent>) {
+        let n = self.out.entry(out).or_insert(0);
+        *n += 1;
+        if *n == 1 {
+            events.push(KeyEvent::Down(out));
+        }
+    }
+
+    fn release(&mut self, out: u8, events: &mut Vec<KeyEvent>) {
+        if let Some(n) = self.out.get_mut(&out) {
+            *n -= 1;
+            if *n == 0 {
+                self.out.remove(&out);
+                events.push(KeyEvent::Up(out));
+            }
+        }
+    }
+
+    fn refresh(&mut self, layer: usize) {
+        let on = self.momentary[layer] > 0 || self.toggled[layer];
+        let pos = self.stack.iter().position(|&l| l == layer);
+        match (on, pos) {
+            (true, None) => self.stack.push(layer),
+            (false, Some(i)) => {
+                self.stack.remove(i);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_modifier(usage: u8) -> bool {
+    (0xE0..=0xE7).contains(&usage)
+}
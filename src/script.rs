@@ -0,0 +1,449 @@
+// Rhai automation scripts: a sandboxed engine with input, typing and host status bindings
+//
+// Every `*.rhai` file in the script directory is compiled at start. Its top level runs once,
+// then these functions, if defined, run on events:
+//
+//     fn on_connect(host) {}      a host subscribed to input reports
+//     fn on_disconnect(host) {}
+//     fn on_key(key, down) {}     a key from any input source except scripts
+//
+// Scripts share one thread, so a hook that sleeps delays the events behind it. Changing any
+// script reloads them all, stopping whatever runs and releasing what scripts hold.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use rhai::{AST, Array, CallFnOptions, Dynamic, Engine, EvalAltResult, Scope};
+use tokio::sync::{mpsc, watch};
+
+use crate::consts::{LED_CAPS_LOCK, LED_NUM_LOCK, LED_SCROLL_LOCK};
+use crate::hid::{button_from_name, parse_chord, usage_from_name, usage_name};
+use crate::input::{InputClosed, InputTx, Presses};
+use crate::status::Status;
+use crate::typing::{KeyEvent, Keymap, Layout, Timing, UnicodeInput};
+
+/// How often the script directory is checked for changes
+const RELOAD_POLL: Duration = Duration::from_secs(1);
+/// Longest a script sleeps before checking whether it should stop
+const SLEEP_SLICE: Duration = Duration::from_millis(50);
+/// Most operations one hook call or top-level run may take, so a runaway loop cannot hold the
+/// script thread. Sleeping and typing cost one call each, however long they take.
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+pub struct ScriptOptions {
+    pub dir: PathBuf,
+    pub layout: Layout,
+    pub unicode: UnicodeInput,
+    pub timing: Timing,
+}
+
+enum Event {
+    Connect(String),
+    Disconnect(String),
+    Key(KeyEvent),
+    Reload,
+}
+
+/// Load the scripts and keep running them on their own thread for the life of the process
+pub fn spawn(
+    opts: ScriptOptions,
+    input: &InputTx,
+    mut status: watch::Receiver<Status>,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        opts.dir.is_dir(),
+        "script directory {} does not exist",
+        opts.dir.display()
+    );
+    let (tx, rx) = mpsc::unbounded_channel();
+    let abort = Arc::new(AtomicBool::new(false));
+
+    let keys = tx.clone();
+    input.set_key_hook(move |ev| {
+        let _ = keys.send(Event::Key(ev));
+    });
+
+    let hosts_tx = tx.clone();
+    let thread_status = status.clone();
+    tokio::spawn(async move {
+        let mut hosts = status.borrow_and_update().hosts.clone();
+        while status.changed().await.is_ok() {
+            let now = status.borrow_and_update().hosts.clone();
+            for host in now.iter().filter(|h| !hosts.contains(h)) {
+                let _ = hosts_tx.send(Event::Connect(host.clone()));
+            }
+            for host in hosts.iter().filter(|h| !now.contains(h)) {
+                let _ = hosts_tx.send(Event::Disconnect(host.clone()));
+            }
+            hosts = now;
+        }
+    });
+
+    let dir = opts.dir.clone();
+    let stop = Arc::clone(&abort);
+    tokio::spawn(async move {
+        let mut seen = fingerprint(&dir);
+        let mut tick = tokio::time::interval(RELOAD_POLL);
+        loop {
+            tick.tick().await;
+            let now = fingerprint(&dir);
+            if now != seen {
+                seen = now;
+                // Interrupt a running script now rather than after it finishes
+                stop.store(true, Ordering::Relaxed);
+                if tx.send(Event::Reload).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    // Its own source, so the buttons and keys a script holds don't replace the user's
+    let input = input.unhooked().new_source();
+    std::thread::Builder::new()
+        .name("scripts".into())
+        .spawn(move || Runner::new(opts, input, thread_status, abort).run(rx))
+        .context("start script thread")?;
+    Ok(())
+}
+
+/// Script files with their modification times, compared to notice edits
+fn fingerprint(dir: &Path) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+    let mut files: Vec<_> = script_paths(dir)
+        .into_iter()
+        .map(|path| {
+            let meta = std::fs::metadata(&path).ok();
+            let modified = meta.as_ref().and_then(|m| m.modified().ok());
+            let len = meta.map_or(0, |m| m.len());
+            (path, modified, len)
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+fn script_paths(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "rhai") && p.is_file())
+        .collect();
+    paths.sort();
+    paths
+}
+
+struct Script {
+    name: String,
+    ast: AST,
+    scope: Scope<'static>,
+}
+
+struct Runner {
+    engine: Engine,
+    scripts: Vec<Script>,
+    actions: Rc<RefCell<Actions>>,
+    dir: PathBuf,
+    abort: Arc<AtomicBool>,
+}
+
+impl Runner {
+    fn new(
+        opts: ScriptOptions,
+        input: InputTx,
+        status: watch::Receiver<Status>,
+        abort: Arc<AtomicBool>,
+    ) -> Self {
+        let actions = Rc::new(RefCell::new(Actions {
+            presses: Presses::new(input),
+            status,
+            keymap: Keymap::new(opts.layout).with_unicode(opts.unicode),
+            timing: opts.timing,
+            abort: Arc::clone(&abort),
+        }));
+        Self {
+            engine: engine(&actions, &abort),
+            scripts: Vec::new(),
+            actions,
+            dir: opts.dir,
+            abort,
+        }
+    }
+
+    fn run(mut self, mut events: mpsc::UnboundedReceiver<Event>) {
+        self.load();
+        // Events that arrived between a reload being flagged and it being loaded; the new
+        // scripts get them rather than hooks that would stop straight away
+        let mut deferred = VecDeque::new();
+        loop {
+            let reloading = self.abort.load(Ordering::Relaxed);
+            let next = if reloading {
+                None
+            } else {
+                deferred.pop_front()
+            };
+            let event = match next.or_else(|| events.blocking_recv()) {
+                Some(event) => event,
+                None => break,
+            };
+            if reloading && !matches!(event, Event::Reload) {
+                deferred.push_back(event);
+                continue;
+            }
+            match event {
+                Event::Connect(host) => self.hook("on_connect", vec![host.into()]),
+                Event::Disconnect(host) => self.hook("on_disconnect", vec![host.into()]),
+                Event::Key(ev) => {
+                    let (usage, down) = match ev {
+                        KeyEvent::Down(u) => (u, true),
+                        KeyEvent::Up(u) => (u, false),
+                    };
+                    self.hook("on_key", vec![usage_name(usage).into(), down.into()]);
+                }
+                Event::Reload => self.load(),
+            }
+        }
+    }
+
+    /// (Re)compile every script and run its top level
+    fn load(&mut self) {
+        self.actions.borrow_mut().presses.release_all();
+        self.abort.store(false, Ordering::Relaxed);
+        self.scripts.clear();
+        for path in script_paths(&self.dir) {
+            let name = path
+                .file_stem()
+                .map_or_else(String::new, |s| s.to_string_lossy().into_owned());
+            match self.engine.compile_file(path) {
+                Ok(ast) => self.scripts.push(Script {
+                    name,
+                    ast,
+                    scope: Scope::new(),
+                }),
+                Err(e) => tracing::warn!(script = %name, error = %e, "Script does not compile"),
+            }
+        }
+        let names: Vec<&str> = self.scripts.iter().map(|s| s.name.as_str()).collect();
+        tracing::info!(dir = %self.dir.display(), scripts = %names.join(","), "Scripts loaded");
+        for s in &mut self.scripts {
+            let result = self.engine.run_ast_with_scope(&mut s.scope, &s.ast);
+            self.actions.borrow_mut().report(&s.name, result);
+        }
+    }
+
+    /// Call `name` in every script that defines it with this many parameters
+    fn hook(&mut self, name: &str, args: Vec<Dynamic>) {
+        for s in &mut self.scripts {
+            let defined = s
+                .ast
+                .iter_functions()
+                .any(|f| f.name == name && f.params.len() == args.len());
+            if !defined {
+                continue;
+            }
+            // Only the hook runs; the top level ran once at load
+            let opts = CallFnOptions::new().eval_ast(false).rewind_scope(false);
+            let result = self
+                .engine
+                .call_fn_with_options::<Dynamic>(opts, &mut s.scope, &s.ast, name, args.clone())
+                .map(|_| ());
+            self.actions.borrow_mut().report(&s.name, result);
+        }
+    }
+}
+
+/// A sandboxed engine: no modules, no `eval`, bounded data, and stoppable mid-run
+fn engine(actions: &Rc<RefCell<Actions>>, abort: &Arc<AtomicBool>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new());
+    engine.disable_symbol("eval");
+    engine.set_max_call_levels(64);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(1 << 20);
+    engine.set_max_array_size(10_000);
+    engine.set_max_map_size(10_000);
+    engine.set_max_operations(MAX_OPERATIONS);
+    let stop = Arc::clone(abort);
+    engine.on_progress(move |_| {
+        stop.load(Ordering::Relaxed)
+            .then(|| Dynamic::from("stopped for reload"))
+    });
+    engine.on_print(|s| tracing::info!(target: "bluper::script", "{s}"));
+    engine.on_debug(|s, src, pos| tracing::debug!(target: "bluper::script", ?src, %pos, "{s}"));
+
+    let a = Rc::clone(actions);
+    engine.register_fn("key_down", move |name: &str| -> ScriptResult<()> {
+        let usage = key_usage(name)?;
+        a.borrow_mut().presses.key_down(usage).map_err(closed)
+    });
+    let a = Rc::clone(actions);
+    engine.register_fn("key_up", move |name: &str| -> ScriptResult<()> {
+        let usage = key_usage(name)?;
+        a.borrow_mut().presses.key_up(usage).map_err(closed)
+    });
+    let a = Rc::clone(actions);
+    engine.register_fn("tap", move |chord: &str| -> ScriptResult<()> {
+        let keys = parse_chord(chord).ok_or_else(|| format!("unknown key in '{chord}'"))?;
+        a.borrow_mut().presses.tap(&keys).map_err(closed)
+    });
+    let a = Rc::clone(actions);
+    engine.register_fn("type_text", move |text: &str| -> ScriptResult<i64> {
+        a.borrow_mut().type_text(text)
+    });
+    let a = Rc::clone(actions);
+    engine.register_fn("click", move |button: &str| -> ScriptResult<()> {
+        let bit = button_bit(button)?;
+        let mut a = a.borrow_mut();
+        a.presses.button_down(bit).map_err(closed)?;
+        a.presses.button_up(bit).map_err(closed)
+    });
+    let a = Rc::clone(actions);
+    engine.register_fn("button_down", move |button: &str| -> ScriptResult<()> {
+        let bit = button_bit(button)?;
+        a.borrow_mut().presses.button_down(bit).map_err(closed)
+    });
+    let a = Rc::clone(actions);
+    engine.register_fn("button_up", move |button: &str| -> ScriptResult<()> {
+        let bit = button_bit(button)?;
+        a.borrow_mut().presses.button_up(bit).map_err(closed)
+    });
+    let a = Rc::clone(actions);
+    engine.register_fn("move_mouse", move |dx: i64, dy: i64| -> ScriptResult<()> {
+        a.borrow()
+            .presses
+            .mouse(dx as f32, dy as f32, 0, 0)
+            .map_err(closed)
+    });
+    let a = Rc::clone(actions);
+    engine.register_fn("scroll", move |amount: i64| -> ScriptResult<()> {
+        let amount = amount.clamp(i32::MIN.into(), i32::MAX.into()) as i32;
+        a.borrow()
+            .presses
+            .mouse(0.0, 0.0, amount, 0)
+            .map_err(closed)
+    });
+    let a = Rc::clone(actions);
+    engine.register_fn("pan", move |amount: i64| -> ScriptResult<()> {
+        let amount = amount.clamp(i32::MIN.into(), i32::MAX.into()) as i32;
+        a.borrow()
+            .presses
+            .mouse(0.0, 0.0, 0, amount)
+            .map_err(closed)
+    });
+    let a = Rc::clone(actions);
+    engine.register_fn("release_all", move || a.borrow_mut().presses.release_all());
+    let a = Rc::clone(actions);
+    engine.register_fn("sleep", move |ms: i64| -> ScriptResult<()> {
+        a.borrow().pause(Duration::from_millis(ms.max(0) as u64))
+    });
+
+    let a = Rc::clone(actions);
+    engine.register_fn("connected", move || a.borrow().status.borrow().connected);
+    let a = Rc::clone(actions);
+    engine.register_fn("hosts", move || -> Array {
+        let hosts = a.borrow().status.borrow().hosts.clone();
+        hosts.into_iter().map(Dynamic::from).collect()
+    });
+    let a = Rc::clone(actions);
+    engine.register_fn("battery", move || a.borrow().status.borrow().battery as i64);
+    for (name, led) in [
+        ("caps_lock", LED_CAPS_LOCK),
+        ("num_lock", LED_NUM_LOCK),
+        ("scroll_lock", LED_SCROLL_LOCK),
+    ] {
+        let a = Rc::clone(actions);
+        // `()` while the host's LED state is unknown, which is always for now
+        engine.register_fn(name, move || -> Dynamic {
+            let leds = a.borrow().status.borrow().leds;
+            leds.map_or(Dynamic::UNIT, |leds| (leds & led != 0).into())
+        });
+    }
+    engine
+}
+
+/// Input sent on behalf of scripts, tracked so it can be released
+struct Actions {
+    presses: Presses,
+    status: watch::Receiver<Status>,
+    keymap: Keymap,
+    timing: Timing,
+    abort: Arc<AtomicBool>,
+}
+
+impl Actions {
+    /// Returns how many characters the layout could not produce
+    fn type_text(&mut self, text: &str) -> ScriptResult<i64> {
+        let typed = self.keymap.type_text(text);
+        for (ev, wait) in self.presses.typing(&typed, &self.timing) {
+            if let Some(ev) = ev {
+                self.presses.key(ev).map_err(closed)?;
+            }
+            self.pause(wait)?;
+        }
+        Ok(typed.unmappable.len() as i64)
+    }
+
+    /// Sleep in slices so a reload does not wait for a long sleep to end
+    fn pause(&self, d: Duration) -> ScriptResult<()> {
+        let until = Instant::now() + d;
+        loop {
+            if self.abort.load(Ordering::Relaxed) {
+                return Err("stopped for reload".into());
+            }
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(());
+            }
+            std::thread::sleep(left.min(SLEEP_SLICE));
+        }
+    }
+
+    /// Log a failed run; a script that stops early lets go of what it held
+    fn report(&mut self, script: &str, result: ScriptResult<()>) {
+        let Err(e) = result else {
+            return;
+        };
+        if self.abort.load(Ordering::Relaxed) {
+            tracing::debug!(script, "Script stopped for reload");
+        } else if over_budget(&e) {
+            tracing::warn!(
+                script,
+                limit = MAX_OPERATIONS,
+                "Script stopped: it ran past its operation budget"
+            );
+        } else {
+            tracing::warn!(script, error = %e, "Script failed");
+        }
+        self.presses.release_all();
+    }
+}
+
+fn key_usage(name: &str) -> ScriptResult<u8> {
+    usage_from_name(name).ok_or_else(|| format!("unknown key '{name}'").into())
+}
+
+fn button_bit(name: &str) -> ScriptResult<u8> {
+    button_from_name(name).ok_or_else(|| format!("unknown button '{name}'").into())
+}
+
+/// Whether `e` is the operation budget running out, however deep in called functions
+fn over_budget(e: &EvalAltResult) -> bool {
+    match e {
+        EvalAltResult::ErrorTooManyOperations(_) => true,
+        EvalAltResult::ErrorInFunctionCall(_, _, inner, _) => over_budget(inner),
+        _ => false,
+    }
+}
+
+fn closed(e: InputClosed) -> Box<EvalAltResult> {
+    e.to_string().into()
+}
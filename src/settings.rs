@@ -0,0 +1,466 @@
+// Config file merged under the command line, settings the BLE task applies while running, and
+// the watcher that reloads the file
+//
+// Flags given on the command line are written into every version of the config before it is
+// used, so they keep winning after a reload and edits to the same setting in the file are not
+// reported as changes.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use clap::parser::ValueSource;
+use tokio::sync::{mpsc, watch};
+
+use crate::RunArgs;
+use crate::config::{self, Apply, Config};
+use crate::consts::*;
+use crate::mouse::PointerSettings;
+use crate::remap::{RemapConfig, Remapper};
+use crate::status::Status;
+use crate::typing::{Layout, Timing, UnicodeInput};
+
+/// How often the config file and the remap file it names are checked for changes
+const RELOAD_POLL: Duration = Duration::from_secs(1);
+
+/// What the BLE task and battery poller pick up without a restart
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveSettings {
+    pub name: String,
+    pub appearance: u16,
+    pub advertise: bool,
+    pub keyboard: bool,
+    pub mouse: bool,
+    pub nkro: bool,
+    pub consumer: bool,
+    pub absolute: bool,
+    /// Auto-release keys held longer than this
+    pub key_lease: Option<Duration>,
+    /// Pointer report slot; ideally a multiple of the connection interval
+    pub mouse_interval: Duration,
+    pub battery: config::Battery,
+    pub profiles: config::Profiles,
+    /// Scaling of window pointer motion
+    pub pointer: PointerSettings,
+}
+
+/// What reaches the host under the active profile
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Output {
+    pub keyboard: bool,
+    pub mouse: bool,
+    pub nkro: bool,
+    pub consumer: bool,
+    pub absolute: bool,
+}
+
+impl LiveSettings {
+    pub fn new(args: &RunArgs, config: &Config) -> Self {
+        Self {
+            name: args.name.clone(),
+            appearance: args.appearance,
+            advertise: config.advertising.enabled,
+            keyboard: config.reports.keyboard,
+            mouse: config.reports.mouse,
+            nkro: config.reports.nkro,
+            consumer: config.reports.consumer,
+            absolute: config.reports.absolute,
+            key_lease: args.key_lease_ms.map(Duration::from_millis),
+            mouse_interval: Duration::from_secs_f32(args.mouse_interval_ms.max(1.0) / 1000.0),
+            battery: config.battery.clone(),
+            profiles: config.profiles.clone(),
+            pointer: config.pointer.clone(),
+        }
+    }
+
+    /// Output settings with `profile`'s overrides, if it still exists
+    pub fn output(&self, profile: Option<&str>) -> Output {
+        let profile = profile.and_then(|p| self.profiles.get(p));
+        let reports = profile.and_then(|p| p.reports.as_ref());
+        Output {
+            keyboard: reports.map_or(self.keyboard, |r| r.keyboard),
+            mouse: reports.map_or(self.mouse, |r| r.mouse),
+            nkro: reports.map_or(self.nkro, |r| r.nkro),
+            consumer: reports.map_or(self.consumer, |r| r.consumer),
+            absolute: reports.map_or(self.absolute, |r| r.absolute),
+        }
+    }
+
+    /// `profile`'s multiplier on local pointer motion
+    pub fn pointer_speed(&self, profile: Option<&str>) -> f32 {
+        profile
+            .and_then(|p| self.profiles.get(p))
+            .and_then(|p| p.pointer_speed)
+            .unwrap_or(1.0)
+    }
+}
+
+/// The active profile's pointer speed, for the window and evdev devices. Remote sources, macros
+/// and replays send motion as meant for the host and are not scaled.
+#[derive(Clone)]
+pub struct ProfileSpeed {
+    settings: watch::Receiver<LiveSettings>,
+    status: watch::Receiver<Status>,
+}
+
+impl ProfileSpeed {
+    pub fn new(settings: watch::Receiver<LiveSettings>, status: watch::Receiver<Status>) -> Self {
+        Self { settings, status }
+    }
+
+    pub fn get(&self) -> f32 {
+        let profile = self.status.borrow().profile.clone();
+        self.settings.borrow().pointer_speed(profile.as_deref())
+    }
+}
+
+/// Typing settings for requests that don't bring their own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TypingDefaults {
+    pub layout: Layout,
+    pub unicode: UnicodeInput,
+    pub timing: Timing,
+}
+
+/// Command line arguments of `bluper run` together with which of them were actually given
+#[derive(Clone)]
+pub struct Settings {
+    args: RunArgs,
+    explicit: HashSet<String>,
+}
+
+impl Settings {
+    pub fn new(args: RunArgs, matches: &clap::ArgMatches) -> Self {
+        let explicit = matches
+            .ids()
+            .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+            .map(|id| id.to_string())
+            .collect();
+        Self { args, explicit }
+    }
+
+    /// The config with the command line written over it, and the arguments it amounts to
+    pub fn resolve(&self, mut config: Config) -> (Config, RunArgs) {
+        self.overlay(&mut config);
+        let mut args = self.args.clone();
+        merge(&mut args, &config);
+        (config, args)
+    }
+
+    fn given(&self, id: &str) -> bool {
+        self.explicit.contains(id)
+    }
+
+    fn overlay(&self, config: &mut Config) {
+        let a = &self.args;
+        let input = &mut config.input;
+        if self.given("name") {
+            config.identity.name = Some(a.name.clone());
+        }
+        if self.given("appearance") {
+            config.identity.appearance = Some(a.appearance);
+        }
+        input.headless |= a.headless;
+        input.capture |= a.capture;
+        input.osk |= a.osk;
+        input.tty |= a.tty;
+        #[cfg(target_os = "linux")]
+        {
+            if !a.evdev.is_empty() {
+                input.evdev = a.evdev.clone();
+            }
+            input.evdev_grab |= a.evdev_grab;
+        }
+        if a.key_lease_ms.is_some() {
+            input.key_lease_ms = a.key_lease_ms;
+        }
+        if self.given("mouse_interval_ms") {
+            input.mouse_interval_ms = Some(a.mouse_interval_ms);
+        }
+        if self.given("pointer_speed") {
+            config.pointer.speed = a.pointer_speed;
+        }
+        config.pointer.dpi_aware |= a.dpi_aware;
+        if self.given("layout") {
+            input.layout = Some(a.layout);
+        }
+        if self.given("unicode") {
+            input.unicode = Some(a.unicode);
+        }
+        if self.given("type_delay_ms") {
+            input.type_delay_ms = Some(a.type_delay_ms);
+        }
+        if a.remap.is_some() {
+            input.remap = a.remap.clone();
+        }
+        if a.macros.is_some() {
+            input.macros = a.macros.clone();
+        }
+        #[cfg(feature = "scripting")]
+        if a.scripts.is_some() {
+            input.scripts = a.scripts.clone();
+        }
+        #[cfg(unix)]
+        if a.no_control {
+            config.control.enabled = false;
+        }
+    }
+}
+
+/// Take every setting `config` has into `args`
+fn merge(args: &mut RunArgs, config: &Config) {
+    let input = &config.input;
+    if let Some(name) = &config.identity.name {
+        args.name = name.clone();
+    }
+    if let Some(appearance) = config.identity.appearance {
+        args.appearance = appearance;
+    }
+    args.headless = input.headless;
+    args.capture = input.capture;
+    args.osk = input.osk;
+    args.tty = input.tty;
+    #[cfg(target_os = "linux")]
+    {
+        args.evdev = input.evdev.clone();
+        args.evdev_grab = input.evdev_grab;
+    }
+    args.key_lease_ms = input.key_lease_ms;
+    if let Some(ms) = input.mouse_interval_ms {
+        args.mouse_interval_ms = ms;
+    }
+    args.pointer_speed = config.pointer.speed;
+    args.dpi_aware = config.pointer.dpi_aware;
+    if let Some(layout) = input.layout {
+        args.layout = layout;
+    }
+    if let Some(unicode) = input.unicode {
+        args.unicode = unicode;
+    }
+    if let Some(ms) = input.type_delay_ms {
+        args.type_delay_ms = ms;
+    }
+    args.remap = input.remap.clone();
+    args.macros = input.macros.clone();
+    #[cfg(feature = "scripting")]
+    {
+        args.scripts = input.scripts.clone();
+    }
+    #[cfg(unix)]
+    {
+        args.no_control = !config.control.enabled;
+    }
+}
+
+/// Device Information Service characteristics and their values
+pub fn device_info(args: &RunArgs, config: &Config) -> Vec<(u16, Vec<u8>)> {
+    let info = &config.device_info;
+    let name = || args.name.clone();
+    let mut chars = vec![
+        (
+            UUID_MFG_NAME,
+            info.manufacturer.clone().unwrap_or_else(name).into_bytes(),
+        ),
+        (
+            UUID_MODEL_NUM,
+            info.model.clone().unwrap_or_else(name).into_bytes(),
+        ),
+    ];
+    for (uuid, value) in [
+        (UUID_SERIAL_NUM, &info.serial),
+        (UUID_HW_REV, &info.hardware_revision),
+        (UUID_FW_REV, &info.firmware_revision),
+        (UUID_SW_REV, &info.software_revision),
+    ] {
+        if let Some(value) = value {
+            chars.push((uuid, value.clone().into_bytes()));
+        }
+    }
+    if let Some(pnp) = info.pnp_id {
+        chars.push((UUID_PNP_ID, pnp.to_bytes().to_vec()));
+    }
+    chars
+}
+
+pub fn load_remap(path: &Path) -> anyhow::Result<Remapper> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("read remap file {}", path.display()))?;
+    let config =
+        RemapConfig::from_toml(&text).with_context(|| format!("remap file {}", path.display()))?;
+    let layers: Vec<&str> = config.layer_names().collect();
+    tracing::info!(path = %path.display(), layers = %layers.join(","), "Key remapping active");
+    Ok(Remapper::new(config))
+}
+
+/// Where the running instance sends what a reload or profile switch changed
+pub struct Watch {
+    pub path: PathBuf,
+    pub settings: Settings,
+    /// The config as currently applied, command line included
+    pub config: Config,
+    pub live: watch::Sender<LiveSettings>,
+    /// A replacement remapper, or None to stop remapping
+    pub remap: mpsc::UnboundedSender<Option<Remapper>>,
+    /// Typing defaults from the command line; the active profile may override them
+    pub typing: watch::Sender<TypingDefaults>,
+    /// Where the BLE task announces the active profile
+    pub status: watch::Receiver<Status>,
+}
+
+/// Reload the config whenever it (or the remap file in use) changes, and follow the profile
+/// the BLE task selects, for the life of the process. A missing file counts as empty, so one
+/// created later is picked up too.
+pub fn watch(w: Watch) {
+    let base_typing = *w.typing.borrow();
+    let remap_path = w.settings.resolve(w.config.clone()).1.remap;
+    let mut watcher = Watcher {
+        stamp: stamp(&w.path),
+        remap_stamp: remap_path.as_deref().and_then(stamp),
+        remap_path,
+        path: w.path,
+        settings: w.settings,
+        config: w.config,
+        profile: None,
+        base_typing,
+        live: w.live,
+        remap: w.remap,
+        typing: w.typing,
+    };
+    let mut status = w.status;
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(RELOAD_POLL);
+        loop {
+            tokio::select! {
+                _ = tick.tick() => watcher.reload(),
+                Ok(()) = status.changed() => {
+                    watcher.profile = status.borrow_and_update().profile.clone();
+                }
+            }
+            if !watcher.follow_profile() {
+                break;
+            }
+        }
+    });
+}
+
+struct Watcher {
+    path: PathBuf,
+    settings: Settings,
+    config: Config,
+    stamp: Option<(SystemTime, u64)>,
+    /// Remap file currently loaded, from the active profile or the top level
+    remap_path: Option<PathBuf>,
+    remap_stamp: Option<(SystemTime, u64)>,
+    profile: Option<String>,
+    base_typing: TypingDefaults,
+    live: watch::Sender<LiveSettings>,
+    remap: mpsc::UnboundedSender<Option<Remapper>>,
+    typing: watch::Sender<TypingDefaults>,
+}
+
+impl Watcher {
+    /// Re-read the config file if it changed
+    fn reload(&mut self) {
+        let now = stamp(&self.path);
+        if now == self.stamp {
+            return;
+        }
+        self.stamp = now;
+        let file = match now {
+            Some(_) => Config::load(&self.path),
+            None => Ok(Config::default()),
+        };
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                tracing::warn!(error = %format!("{e:#}"), "Config not reloaded");
+                return;
+            }
+        };
+        let (next, args) = self.settings.resolve(file);
+        report(&self.config.changes(&next));
+        self.live.send_if_modified(|s| {
+            let next = LiveSettings::new(&args, &next);
+            std::mem::replace(s, next.clone()) != next
+        });
+        self.config = next;
+    }
+
+    /// Bring typing defaults and the remapper in line with the active profile and config;
+    /// false once the input side is gone
+    fn follow_profile(&mut self) -> bool {
+        let profile = self
+            .profile
+            .as_deref()
+            .and_then(|p| self.config.profiles.get(p));
+        let typing = TypingDefaults {
+            layout: profile
+                .and_then(|p| p.layout)
+                .unwrap_or(self.base_typing.layout),
+            unicode: profile
+                .and_then(|p| p.unicode)
+                .unwrap_or(self.base_typing.unicode),
+            timing: self.base_typing.timing,
+        };
+        self.typing
+            .send_if_modified(|t| std::mem::replace(t, typing) != typing);
+
+        let path = profile
+            .and_then(|p| p.remap.clone())
+            .or_else(|| self.settings.resolve(self.config.clone()).1.remap);
+        let now = path.as_deref().and_then(stamp);
+        if path == self.remap_path && now == self.remap_stamp {
+            return true;
+        }
+        self.remap_path = path;
+        self.remap_stamp = now;
+        let next = match &self.remap_path {
+            Some(path) => match load_remap(path) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    tracing::warn!(error = %format!("{e:#}"), "Remap not reloaded");
+                    return true;
+                }
+            },
+            None => {
+                tracing::info!("Key remapping off");
+                None
+            }
+        };
+        self.remap.send(next).is_ok()
+    }
+}
+
+fn report(changes: &[config::Change]) {
+    let fields = |apply| {
+        changes
+            .iter()
+            .filter(|c| c.apply == apply)
+            .map(|c| c.field)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let (live, restart, gatt) = (
+        fields(Apply::Live),
+        fields(Apply::Restart),
+        fields(Apply::Gatt),
+    );
+    if !live.is_empty() {
+        tracing::info!(%live, "Config reloaded");
+    }
+    if !restart.is_empty() {
+        tracing::warn!(%restart, "Config changes take effect after a restart");
+    }
+    if !gatt.is_empty() {
+        tracing::warn!(
+            %gatt,
+            "Config changes need the GATT services re-registered; restart, and re-pair hosts that cached them"
+        );
+    }
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
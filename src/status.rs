@@ -0,0 +1,21 @@
+// Peripheral state published by the BLE task for local display and control clients
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub powered: bool,
+    pub advertising: bool,
+    /// Host subscribed to input reports
+    pub connected: bool,
+    /// Addresses of hosts subscribed to input reports
+    pub hosts: Vec<String>,
+    /// Host LED output report (see `consts::LED_*`). Always `None`: there is no output report
+    /// to receive it, as the GATT layer cannot tell a second report characteristic apart
+    pub leds: Option<u8>,
+    pub modifiers: u8,
+    pub keys: Vec<u8>,
+    pub buttons: u8,
+    pub battery: u8,
+    pub profile: Option<String>,
+}
@@ -0,0 +1,272 @@
+// Terminal input source for SSH sessions: raw mode, kitty keyboard protocol when the terminal has it
+
+use std::collections::BTreeSet;
+use std::io::{self, IsTerminal, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    ModifierKeyCode, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::{execute, terminal};
+use tokio::sync::oneshot;
+
+use crate::hid::usage_name;
+use crate::input::InputTx;
+use crate::typing::{Keymap, Layout, MOD_SHIFT, mod_usages};
+use crate::ui::AppCmd;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct TtyOptions {
+    /// Pressing all of these ends the session instead of reaching the host
+    pub exit_chord: Vec<u8>,
+    /// Host layout, to find the key for a typed character
+    pub layout: Layout,
+}
+
+/// Running terminal source; dropping it stops the reader and restores the terminal
+pub struct TtySource {
+    stop: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
+    enhanced: bool,
+}
+
+impl TtySource {
+    /// Enter raw mode and start forwarding keys. The receiver fires when the exit chord is pressed.
+    pub fn start(
+        input: InputTx,
+        opts: TtyOptions,
+    ) -> anyhow::Result<(Self, oneshot::Receiver<()>)> {
+        anyhow::ensure!(io::stdin().is_terminal(), "--tty needs a terminal on stdin");
+        terminal::enable_raw_mode()?;
+        // Without the kitty protocol a terminal only reports presses, so releases get synthesized
+        let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false)
+            && execute!(
+                io::stdout(),
+                PushKeyboardEnhancementFlags(
+                    KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+                        | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+                        | KeyboardEnhancementFlags::REPORT_ALL_KEYS_AS_ESCAPE_CODES
+                )
+            )
+            .is_ok();
+        let exit: Vec<String> = opts.exit_chord.iter().map(|&u| usage_name(u)).collect();
+        tracing::info!(enhanced, exit = %exit.join("+"), "Terminal input active");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (exit_tx, exit_rx) = oneshot::channel();
+        let mut reader = Reader {
+            input,
+            exit_chord: opts.exit_chord,
+            keymap: Keymap::new(opts.layout),
+            enhanced,
+            held: BTreeSet::new(),
+        };
+        let flag = stop.clone();
+        let handle = std::thread::Builder::new()
+            .name("tty-input".into())
+            .spawn(move || {
+                if reader.run(&flag) {
+                    let _ = exit_tx.send(());
+                }
+                reader.release_all();
+            })?;
+
+        Ok((
+            Self {
+                stop,
+                reader: Some(handle),
+                enhanced,
+            },
+            exit_rx,
+        ))
+    }
+}
+
+impl Drop for TtySource {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+        if self.enhanced {
+            let _ = execute!(io::stdout(), PopKeyboardEnhancementFlags);
+        }
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+struct Reader {
+    input: InputTx,
+    exit_chord: Vec<u8>,
+    keymap: Keymap,
+    enhanced: bool,
+    /// Keys down on the host (enhanced mode only; fallback presses are released immediately)
+    held: BTreeSet<u8>,
+}
+
+impl Reader {
+    /// Forward keys until stopped; returns true if the exit chord ended it
+    fn run(&mut self, stop: &AtomicBool) -> bool {
+        while !stop.load(Ordering::Relaxed) {
+            let ev = match event::poll(POLL_INTERVAL) {
+                Ok(true) => event::read(),
+                Ok(false) => continue,
+                Err(e) => Err(e),
+            };
+            let key = match ev {
+                Ok(Event::Key(key)) => key,
+                Ok(_) => continue,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Terminal read failed");
+                    return false;
+                }
+            };
+            let done = if self.enhanced {
+                self.enhanced_key(key)
+            } else {
+                self.fallback_key(key)
+            };
+            match done {
+                Ok(false) => {}
+                Ok(true) => return true,
+                Err(_) => return false,
+            }
+        }
+        false
+    }
+
+    /// Real press/release from the kitty protocol; modifiers arrive as keys of their own
+    fn enhanced_key(&mut self, key: KeyEvent) -> anyhow::Result<bool> {
+        let Some((usage, _)) = key_usage(&self.keymap, key.code) else {
+            return Ok(false);
+        };
+        match key.kind {
+            KeyEventKind::Press => {
+                // The key completing the exit chord never reaches the host
+                if self
+                    .exit_chord
+                    .iter()
+                    .all(|u| *u == usage || self.held.contains(u))
+                {
+                    return Ok(true);
+                }
+                if self.held.insert(usage) {
+                    self.input.send(AppCmd::KeyDown(usage))?;
+                }
+            }
+            KeyEventKind::Release => {
+                if self.held.remove(&usage) {
+                    self.input.send(AppCmd::KeyUp(usage))?;
+                }
+            }
+            // The host runs its own autorepeat
+            KeyEventKind::Repeat => {}
+        }
+        Ok(false)
+    }
+
+    /// Legacy input: every event is a press; tap it with the modifiers it was typed with
+    fn fallback_key(&mut self, key: KeyEvent) -> anyhow::Result<bool> {
+        let Some((usage, mods)) = key_usage(&self.keymap, key.code) else {
+            return Ok(false);
+        };
+        let mut chord: Vec<u8> = mod_usages(mods).collect();
+        for (flag, m) in [
+            (KeyModifiers::CONTROL, 0xE0),
+            (KeyModifiers::SHIFT, 0xE1),
+            (KeyModifiers::ALT, 0xE2),
+            (KeyModifiers::SUPER, 0xE3),
+        ] {
+            if key.modifiers.contains(flag) && !chord.contains(&m) {
+                chord.push(m);
+            }
+        }
+        chord.push(usage);
+        if self.exit_chord.iter().all(|u| chord.contains(u)) {
+            return Ok(true);
+        }
+        for &u in &chord {
+            self.input.send(AppCmd::KeyDown(u))?;
+        }
+        for &u in chord.iter().rev() {
+            self.input.send(AppCmd::KeyUp(u))?;
+        }
+        Ok(false)
+    }
+
+    fn release_all(&mut self) {
+        for usage in std::mem::take(&mut self.held) {
+            let _ = self.input.send(AppCmd::KeyUp(usage));
+        }
+    }
+}
+
+/// HID usage for a decoded terminal key, and the modifiers its character needs on the host
+fn key_usage(keymap: &Keymap, code: KeyCode) -> Option<(u8, u8)> {
+    let usage = match code {
+        KeyCode::Char(c) => return keymap.key(c).map(|s| (s.usage, s.mods)),
+        KeyCode::BackTab => return Some((0x2B, MOD_SHIFT)),
+        KeyCode::Enter => 0x28,
+        KeyCode::Esc => 0x29,
+        KeyCode::Backspace => 0x2A,
+        KeyCode::Tab => 0x2B,
+        KeyCode::CapsLock => 0x39,
+        KeyCode::F(n @ 1..=12) => 0x3A + n - 1,
+        KeyCode::PrintScreen => 0x46,
+        KeyCode::ScrollLock => 0x47,
+        KeyCode::Pause => 0x48,
+        KeyCode::Insert => 0x49,
+        KeyCode::Home => 0x4A,
+        KeyCode::PageUp => 0x4B,
+        KeyCode::Delete => 0x4C,
+        KeyCode::End => 0x4D,
+        KeyCode::PageDown => 0x4E,
+        KeyCode::Right => 0x4F,
+        KeyCode::Left => 0x50,
+        KeyCode::Down => 0x51,
+        KeyCode::Up => 0x52,
+        KeyCode::NumLock => 0x53,
+        KeyCode::Menu => 0x65,
+        KeyCode::Modifier(m) => match m {
+            ModifierKeyCode::LeftControl => 0xE0,
+            ModifierKeyCode::LeftShift => 0xE1,
+            ModifierKeyCode::LeftAlt => 0xE2,
+            ModifierKeyCode::LeftSuper | ModifierKeyCode::LeftMeta => 0xE3,
+            ModifierKeyCode::RightControl => 0xE4,
+            ModifierKeyCode::RightShift => 0xE5,
+            ModifierKeyCode::RightAlt | ModifierKeyCode::IsoLevel3Shift => 0xE6,
+            ModifierKeyCode::RightSuper | ModifierKeyCode::RightMeta => 0xE7,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some((usage, 0))
+}
+
+/// Log sink for raw mode, where a bare `\n` no longer returns the cursor to column 0
+pub struct CrlfStdout;
+
+impl Write for CrlfStdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = io::stdout().lock();
+        for line in buf.split_inclusive(|&b| b == b'\n') {
+            match line.strip_suffix(b"\n") {
+                Some(body) => {
+                    out.write_all(body)?;
+                    out.write_all(b"\r\n")?;
+                }
+                None => out.write_all(line)?,
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
@@ -0,0 +1,549 @@
+// Text to keystrokes for a given host keyboard layout, including AltGr and dead-key sequences
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Keyboard layout the host is configured with; decides which keys produce which characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    #[default]
+    Us,
+    Uk,
+    De,
+    Fr,
+    Es,
+    Nordic,
+    Dvorak,
+    Colemak,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 8] = [
+        Layout::Us,
+        Layout::Uk,
+        Layout::De,
+        Layout::Fr,
+        Layout::Es,
+        Layout::Nordic,
+        Layout::Dvorak,
+        Layout::Colemak,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::De => "de",
+            Layout::Fr => "fr",
+            Layout::Es => "es",
+            Layout::Nordic => "nordic",
+            Layout::Dvorak => "dvorak",
+            Layout::Colemak => "colemak",
+        }
+    }
+
+    fn table(self) -> &'static Table {
+        match self {
+            Layout::Us => &US,
+            Layout::Uk => &UK,
+            Layout::De => &DE,
+            Layout::Fr => &FR,
+            Layout::Es => &ES,
+            Layout::Nordic => &NORDIC,
+            Layout::Dvorak => &DVORAK,
+            Layout::Colemak => &COLEMAK,
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let alias = match s.as_str() {
+            "gb" => "uk",
+            "se" | "fi" | "no" | "dk" => "nordic",
+            other => other,
+        };
+        Layout::ALL
+            .into_iter()
+            .find(|l| l.name() == alias)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Layout::ALL.iter().map(|l| l.name()).collect();
+                format!("unknown layout '{s}' (one of {})", names.join(", "))
+            })
+    }
+}
+
+// Modifier byte bits as they appear in the keyboard report
+pub const MOD_CTRL: u8 = 1 << 0;
+pub const MOD_SHIFT: u8 = 1 << 1;
+/// Left Alt, which is Option on macOS
+pub const MOD_ALT: u8 = 1 << 2;
+/// Right Alt, which layouts with a third level use as AltGr
+pub const MOD_ALTGR: u8 = 1 << 6;
+
+/// One key press with the modifiers held around it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stroke {
+    pub mods: u8,
+    pub usage: u8,
+    /// Leave the modifiers down for the next stroke, as Alt-code entry needs
+    pub hold: bool,
+}
+
+impl Stroke {
+    pub const fn new(mods: u8, usage: u8) -> Self {
+        Self {
+            mods,
+            usage,
+            hold: false,
+        }
+    }
+
+    const fn held(mods: u8, usage: u8) -> Self {
+        Self {
+            mods,
+            usage,
+            hold: true,
+        }
+    }
+}
+
+/// How the host OS accepts a code point typed as hex or decimal digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnicodeInput {
+    /// Characters missing from the layout are reported as unmappable
+    #[default]
+    Off,
+    /// Ctrl+Shift+U, hex digits, Space (IBus and GTK)
+    Linux,
+    /// Alt held while typing the decimal code on the numpad (RichEdit-based apps for >U+00FF)
+    WindowsAlt,
+    /// Alt held, numpad +, hex digits; needs `EnableHexNumpad` set in the registry
+    WindowsHex,
+    /// Option held while typing UTF-16 hex; needs the Unicode Hex Input source active
+    Macos,
+}
+
+impl UnicodeInput {
+    pub const ALL: [UnicodeInput; 5] = [
+        UnicodeInput::Off,
+        UnicodeInput::Linux,
+        UnicodeInput::WindowsAlt,
+        UnicodeInput::WindowsHex,
+        UnicodeInput::Macos,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            UnicodeInput::Off => "off",
+            UnicodeInput::Linux => "linux",
+            UnicodeInput::WindowsAlt => "windows-alt",
+            UnicodeInput::WindowsHex => "windows-hex",
+            UnicodeInput::Macos => "macos",
+        }
+    }
+}
+
+impl fmt::Display for UnicodeInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for UnicodeInput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let alias = match s.as_str() {
+            "none" => "off",
+            "ibus" => "linux",
+            "mac" => "macos",
+            other => other,
+        };
+        UnicodeInput::ALL
+            .into_iter()
+            .find(|u| u.name() == alias)
+            .ok_or_else(|| {
+                let names: Vec<&str> = UnicodeInput::ALL.iter().map(|u| u.name()).collect();
+                format!("unknown unicode input '{s}' (one of {})", names.join(", "))
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Down(u8),
+    Up(u8),
+}
+
+/// Delays applied between key events when a typed sequence is played back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    /// From a key's press to its release
+    pub hold: Duration,
+    /// From one keystroke's release to the next keystroke
+    pub gap: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            hold: Duration::from_millis(8),
+            gap: Duration::from_millis(8),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Typed {
+    pub strokes: Vec<Stroke>,
+    /// Characters the layout cannot produce, with their char index in the input
+    pub unmappable: Vec<(usize, char)>,
+}
+
+impl Typed {
+    /// Press/release sequence for all strokes, each paired with the delay to wait after it
+    pub fn events(&self, timing: &Timing) -> Vec<(KeyEvent, Duration)> {
+        let mut out = Vec::new();
+        // Modifiers still down from a stroke with `hold` set
+        let mut down = 0u8;
+        for s in &self.strokes {
+            for m in mod_usages(down & !s.mods).rev() {
+                out.push((KeyEvent::Up(m), Duration::ZERO));
+            }
+            for m in mod_usages(s.mods & !down) {
+                out.push((KeyEvent::Down(m), Duration::ZERO));
+            }
+            down = s.mods;
+            out.push((KeyEvent::Down(s.usage), timing.hold));
+            out.push((KeyEvent::Up(s.usage), Duration::ZERO));
+            if !s.hold {
+                for m in mod_usages(down).rev() {
+                    out.push((KeyEvent::Up(m), Duration::ZERO));
+                }
+                down = 0;
+            }
+            if let Some(last) = out.last_mut() {
+                last.1 = timing.gap;
+            }
+        }
+        // A trailing held stroke must not leave anything pressed
+        for m in mod_usages(down).rev() {
+            out.push((KeyEvent::Up(m), Duration::ZERO));
+        }
+        out
+    }
+}
+
+/// Usages of the modifier keys set in `mods`
+pub fn mod_usages(mods: u8) -> impl DoubleEndedIterator<Item = u8> {
+    (0..8)
+        .filter(move |b| mods & (1 << b) != 0)
+        .map(|b| 0xE0 + b)
+}
+
+/// Convert `text` into keystrokes for a host using `layout`
+pub fn type_text(text: &str, layout: Layout) -> Typed {
+    Keymap::new(layout).type_text(text)
+}
+
+/// Character lookup built from a layout table
+pub struct Keymap {
+    keys: HashMap<char, Stroke>,
+    /// Accent character to the dead key that starts it
+    dead: HashMap<char, Stroke>,
+    unicode: UnicodeInput,
+}
+
+const SPACE: Stroke = Stroke::new(0, 0x2C);
+
+impl Keymap {
+    pub fn new(layout: Layout) -> Self {
+        let table = layout.table();
+        let mut keys = HashMap::new();
+        let mut dead = HashMap::new();
+        // Lower levels first so each character gets the fewest modifiers
+        for (level, mods) in LEVEL_MODS.iter().enumerate() {
+            for (row, usages) in table.rows.iter().zip(ROW_USAGES) {
+                for (c, &usage) in row[level].chars().zip(usages) {
+                    if c == ' ' {
+                        continue;
+                    }
+                    let stroke = Stroke::new(*mods, usage);
+                    let map = if table.dead.contains(c) {
+                        &mut dead
+                    } else {
+                        &mut keys
+                    };
+                    map.entry(c).or_insert(stroke);
+                }
+            }
+        }
+        keys.insert(' ', SPACE);
+        keys.insert('\n', Stroke::new(0, 0x28));
+        keys.insert('\t', Stroke::new(0, 0x2B));
+        Self {
+            keys,
+            dead,
+            unicode: UnicodeInput::Off,
+        }
+    }
+
+    /// Fall back to the host's code point entry for characters the layout lacks
+    pub fn with_unicode(mut self, unicode: UnicodeInput) -> Self {
+        self.unicode = unicode;
+        self
+    }
+
+    /// The single key producing `c`, without dead keys or code point entry
+    pub fn key(&self, c: char) -> Option<Stroke> {
+        self.keys.get(&c).copied()
+    }
+
+    /// Strokes producing `c`, or None if the layout has no way to type it
+    pub fn lookup(&self, c: char) -> Option<Vec<Stroke>> {
+        if let Some(&s) = self.keys.get(&c) {
+            return Some(vec![s]);
+        }
+        // A dead key followed by space gives the bare accent
+        if let Some(&d) = self.dead.get(&c) {
+            return Some(vec![d, SPACE]);
+        }
+        if let Some((accent, base)) = decompose(c)
+            && let (Some(&d), Some(&b)) = (self.dead.get(&accent), self.keys.get(&base))
+        {
+            return Some(vec![d, b]);
+        }
+        self.unicode_entry(c)
+    }
+
+    /// Strokes entering `c` by code point with the configured host method
+    fn unicode_entry(&self, c: char) -> Option<Vec<Stroke>> {
+        let code = c as u32;
+        match self.unicode {
+            UnicodeInput::Off => None,
+            UnicodeInput::Linux => {
+                let u = self.keys.get(&'u')?;
+                let mut out = vec![Stroke::new(u.mods | MOD_CTRL | MOD_SHIFT, u.usage)];
+                for digit in format!("{code:x}").chars() {
+                    out.push(*self.keys.get(&digit)?);
+                }
+                out.push(SPACE);
+                Some(out)
+            }
+            UnicodeInput::WindowsAlt => {
+                // Codes below 256 go through the ANSI code page, which a leading zero selects
+                let digits = match code {
+                    0..=0xFF => format!("0{code}"),
+                    0x100..=0xFFFF => code.to_string(),
+                    _ => return None,
+                };
+                digits
+                    .chars()
+                    .map(|d| Some(Stroke::held(MOD_ALT, keypad_usage(d)?)))
+                    .collect::<Option<Vec<_>>>()
+                    .map(release_last)
+            }
+            UnicodeInput::WindowsHex => {
+                let mut out = vec![Stroke::held(MOD_ALT, KEYPAD_PLUS)];
+                for digit in format!("{code:x}").chars() {
+                    // Digits from the keypad; letters from wherever the layout has them
+                    let usage = match keypad_usage(digit) {
+                        Some(usage) => usage,
+                        None => self.keys.get(&digit).filter(|s| s.mods == 0)?.usage,
+                    };
+                    out.push(Stroke::held(MOD_ALT, usage));
+                }
+                Some(release_last(out))
+            }
+            UnicodeInput::Macos => {
+                // Unicode Hex Input is a US-based source, so digits sit at US positions
+                let mut units = [0u16; 2];
+                let out = c
+                    .encode_utf16(&mut units)
+                    .iter()
+                    .flat_map(|unit| format!("{unit:04x}").chars().collect::<Vec<_>>())
+                    .map(|d| Stroke::held(MOD_ALT, us_hex_usage(d)))
+                    .collect();
+                Some(release_last(out))
+            }
+        }
+    }
+
+    pub fn type_text(&self, text: &str) -> Typed {
+        let mut typed = Typed::default();
+        for (i, c) in text.chars().enumerate() {
+            match self.lookup(c) {
+                Some(strokes) => typed.strokes.extend(strokes),
+                None => typed.unmappable.push((i, c)),
+            }
+        }
+        typed
+    }
+}
+
+const KEYPAD_PLUS: u8 = 0x57;
+
+fn keypad_usage(digit: char) -> Option<u8> {
+    match digit {
+        '0' => Some(0x62),
+        '1'..='9' => Some(0x59 + (digit as u8 - b'1')),
+        _ => None,
+    }
+}
+
+fn us_hex_usage(digit: char) -> u8 {
+    match digit {
+        '0' => 0x27,
+        '1'..='9' => 0x1E + (digit as u8 - b'1'),
+        _ => 0x04 + (digit as u8 - b'a'),
+    }
+}
+
+/// End an Alt-held sequence by letting the modifier go after its last key
+fn release_last(mut strokes: Vec<Stroke>) -> Vec<Stroke> {
+    if let Some(last) = strokes.last_mut() {
+        last.hold = false;
+    }
+    strokes
+}
+
+fn decompose(c: char) -> Option<(char, char)> {
+    COMPOSE.iter().find_map(|&(accent, bases, composed)| {
+        composed
+            .chars()
+            .position(|x| x == c)
+            .and_then(|i| bases.chars().nth(i))
+            .map(|base| (accent, base))
+    })
+}
+
+// (dead accent, base letters, composed letters in the same order)
+const COMPOSE: &[(char, &str, &str)] = &[
+    ('`', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    ('´', "aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+    ('^', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    ('¨', "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+    ('~', "anoANO", "ãñõÃÑÕ"),
+];
+
+const LEVEL_MODS: [u8; 4] = [0, MOD_SHIFT, MOD_ALTGR, MOD_ALTGR | MOD_SHIFT];
+
+// Physical key positions per row, left to right. The last key of the home row is the ANSI
+// backslash / ISO hash key, and the first key of the bottom row is the ISO key left of Z.
+#[rustfmt::skip]
+const ROW_USAGES: [&[u8]; 4] = [
+    &[0x35, 0x1E, 0x1F, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x2D, 0x2E],
+    &[0x14, 0x1A, 0x08, 0x15, 0x17, 0x1C, 0x18, 0x0C, 0x12, 0x13, 0x2F, 0x30],
+    &[0x04, 0x16, 0x07, 0x09, 0x0A, 0x0B, 0x0D, 0x0E, 0x0F, 0x33, 0x34, 0x31],
+    &[0x64, 0x1D, 0x1B, 0x06, 0x19, 0x05, 0x11, 0x10, 0x36, 0x37, 0x38],
+];
+
+/// Characters per key position for each row at levels base, Shift, AltGr, Shift+AltGr.
+/// A space marks a position with nothing on that level.
+struct Table {
+    rows: [[&'static str; 4]; 4],
+    /// Table characters that are dead keys on this layout
+    dead: &'static str,
+}
+
+#[rustfmt::skip]
+const US: Table = Table {
+    rows: [
+        ["`1234567890-=", "~!@#$%^&*()_+", "", ""],
+        ["qwertyuiop[]", "QWERTYUIOP{}", "", ""],
+        ["asdfghjkl;'\\", "ASDFGHJKL:\"|", "", ""],
+        [" zxcvbnm,./", " ZXCVBNM<>?", "", ""],
+    ],
+    dead: "",
+};
+
+#[rustfmt::skip]
+const UK: Table = Table {
+    rows: [
+        ["`1234567890-=", "¬!\"£$%^&*()_+", "¦   €", ""],
+        ["qwertyuiop[]", "QWERTYUIOP{}", "  é   úíó", "  É   ÚÍÓ"],
+        ["asdfghjkl;'#", "ASDFGHJKL:@~", "á", "Á"],
+        ["\\zxcvbnm,./", "|ZXCVBNM<>?", "", ""],
+    ],
+    dead: "",
+};
+
+#[rustfmt::skip]
+const DE: Table = Table {
+    rows: [
+        ["^1234567890ß´", "°!\"§$%&/()=?`", "  ²³   {[]}\\", ""],
+        ["qwertzuiopü+", "QWERTZUIOPÜ*", "@ €        ~", ""],
+        ["asdfghjklöä#", "ASDFGHJKLÖÄ'", "", ""],
+        ["<yxcvbnm,.-", ">YXCVBNM;:_", "|      µ", ""],
+    ],
+    dead: "^´`",
+};
+
+#[rustfmt::skip]
+const FR: Table = Table {
+    rows: [
+        ["²&é\"'(-è_çà)=", " 1234567890°+", "  ~#{[|`\\^@]}", ""],
+        ["azertyuiop^$", "AZERTYUIOP¨£", "  €        ¤", ""],
+        ["qsdfghjklmù*", "QSDFGHJKLM%µ", "", ""],
+        ["<wxcvbn,;:!", ">WXCVBN?./§", "", ""],
+    ],
+    dead: "^¨~`",
+};
+
+#[rustfmt::skip]
+const ES: Table = Table {
+    rows: [
+        ["º1234567890'¡", "ª!\"·$%&/()=?¿", "\\|@#~€¬", ""],
+        ["qwertyuiop`+", "QWERTYUIOP^*", "  €       []", ""],
+        ["asdfghjklñ´ç", "ASDFGHJKLÑ¨Ç", "          {}", ""],
+        ["<zxcvbnm,.-", ">ZXCVBNM;:_", "", ""],
+    ],
+    dead: "`^´¨",
+};
+
+// Swedish/Finnish
+#[rustfmt::skip]
+const NORDIC: Table = Table {
+    rows: [
+        ["§1234567890+´", "½!\"#¤%&/()=?`", "  @£$€ {[]}\\", ""],
+        ["qwertyuiopå¨", "QWERTYUIOPÅ^", "  €        ~", ""],
+        ["asdfghjklöä'", "ASDFGHJKLÖÄ*", "", ""],
+        ["<zxcvbnm,.-", ">ZXCVBNM;:_", "|      µ", ""],
+    ],
+    dead: "´`¨^~",
+};
+
+#[rustfmt::skip]
+const DVORAK: Table = Table {
+    rows: [
+        ["`1234567890[]", "~!@#$%^&*(){}", "", ""],
+        ["',.pyfgcrl/=", "\"<>PYFGCRL?+", "", ""],
+        ["aoeuidhtns-\\", "AOEUIDHTNS_|", "", ""],
+        [" ;qjkxbmwvz", " :QJKXBMWVZ", "", ""],
+    ],
+    dead: "",
+};
+
+#[rustfmt::skip]
+const COLEMAK: Table = Table {
+    rows: [
+        ["`1234567890-=", "~!@#$%^&*()_+", "", ""],
+        ["qwfpgjluy;[]", "QWFPGJLUY:{}", "", ""],
+        ["arstdhneio'\\", "ARSTDHNEIO\"|", "", ""],
+        [" zxcvbkm,./", " ZXCVBKM<>?", "", ""],
+    ],
+    dead: "",
+};
@@ -1,69 +1,154 @@
 use std::collections::BTreeSet;
 use std::num::NonZeroU32;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+use serde::{Deserialize, Serialize};
 use softbuffer::{Context as SbContext, Surface as SbSurface};
-use tokio::sync::mpsc;
+use tokio::sync::watch;
 use winit::{
     application::ApplicationHandler,
     dpi::PhysicalSize,
-    event::{ElementState, MouseScrollDelta, WindowEvent},
+    event::{DeviceEvent, ElementState, MouseScrollDelta, TouchPhase, WindowEvent},
+    event_loop::ControlFlow,
     keyboard::{ModifiersState, PhysicalKey},
-    window::Window,
+    window::{CursorGrabMode, Window},
 };
 
-use crate::hid::keycode_to_hid;
+use crate::canvas::{Canvas, Rect};
+use crate::hid::{keycode_to_consumer, keycode_to_hid};
+use crate::hud::{self, HudView};
+use crate::input::InputTx;
+use crate::mouse::{DragScroll, Inertia, PointerScaler};
+use crate::osk::Osk;
+use crate::settings::{LiveSettings, ProfileSpeed};
+use crate::status::Status;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AppCmd {
     Exit,
+    /// Relative pointer motion in (possibly fractional) counts; the BLE side carries remainders
     Mouse {
         buttons: u8,
-        dx: i8,
-        dy: i8,
+        dx: f32,
+        dy: f32,
         wheel: i8,
+        /// Horizontal scroll, positive is right; absent in recordings made before it existed
+        #[serde(default)]
+        pan: i8,
     },
     KeyDown(u8),
     KeyUp(u8),
+    /// Media and system keys, by Consumer page usage
+    ConsumerDown(u16),
+    ConsumerUp(u16),
+    /// Absolute pointer position, each axis 0..=`ABSOLUTE_MAX` across the host screen
+    MouseTo {
+        x: u16,
+        y: u16,
+    },
     Battery(u8),
 }
 
+/// Wakeups delivered to the winit loop from other tasks
+#[derive(Debug)]
+pub enum UiEvent {
+    StatusChanged,
+    /// Close the window and stop the loop (signal received)
+    Shutdown,
+}
+
+pub struct UiOptions {
+    pub device_name: String,
+    /// Grab the pointer on click and forward raw motion until the release chord
+    pub capture: bool,
+    pub capture_release: Vec<u8>,
+    /// Show the clickable keyboard and touchpad; window clicks then go to it instead of the host
+    pub osk: bool,
+    /// Pointer scaling follows these
+    pub settings: watch::Receiver<LiveSettings>,
+}
+
 pub struct App {
     window: Option<Rc<Window>>,
     sb_ctx: Option<SbContext<Rc<Window>>>,
     sb_surface: Option<SbSurface<Rc<Window>, Rc<Window>>>,
-    cmd_tx: mpsc::Sender<AppCmd>,
+    input: InputTx,
     mouse_buttons: u8,
     cursor_last: Option<(f64, f64)>,
+    /// Trackpad pixels not yet worth a wheel and pan step
     wheel_px_accum: f64,
+    pan_px_accum: f64,
     mods_winit: ModifiersState,
     hid_mod_mask: u8,
     pressed_usages: BTreeSet<u8>,
+    pressed_consumer: BTreeSet<u16>,
     size: PhysicalSize<u32>,
     exiting: bool,
+    opts: UiOptions,
+    captured: bool,
+    status_rx: watch::Receiver<Status>,
+    osk: Option<Osk>,
+    cursor_pos: Option<(f64, f64)>,
+    touch_id: Option<u64>,
+    pointer: PointerScaler,
+    /// Applied on top of `pointer`
+    profile_speed: ProfileSpeed,
+    /// Zero point of the pointer scaler's clock
+    epoch: Instant,
+    precision_key: Option<u8>,
+    precision_held: bool,
+    scroll_key: Option<u8>,
+    /// Scroll mode holds: the scroll key, the middle button
+    scroll_held: (bool, bool),
+    drag: DragScroll,
+    inertia: Inertia,
 }
 
+/// Report cadence while kinetic scrolling coasts
+const COAST_TICK: Duration = Duration::from_millis(15);
+
 impl App {
-    pub fn new(cmd_tx: mpsc::Sender<AppCmd>) -> Self {
+    pub fn new(input: InputTx, status_rx: watch::Receiver<Status>, mut opts: UiOptions) -> Self {
+        let pointer = opts.settings.borrow_and_update().pointer.clone();
         Self {
             window: None,
             sb_ctx: None,
             sb_surface: None,
-            cmd_tx,
+            input,
             mouse_buttons: 0,
             cursor_last: None,
             wheel_px_accum: 0.0,
+            pan_px_accum: 0.0,
             mods_winit: ModifiersState::empty(),
             hid_mod_mask: 0,
             pressed_usages: BTreeSet::new(),
+            pressed_consumer: BTreeSet::new(),
             size: PhysicalSize::new(800, 600),
             exiting: false,
+            osk: opts.osk.then(Osk::new),
+            profile_speed: ProfileSpeed::new(opts.settings.clone(), status_rx.clone()),
+            opts,
+            captured: false,
+            status_rx,
+            cursor_pos: None,
+            touch_id: None,
+            precision_key: pointer.precision_usage(),
+            scroll_key: pointer.scroll_usage(),
+            pointer: PointerScaler::new(pointer),
+            epoch: Instant::now(),
+            precision_held: false,
+            scroll_held: (false, false),
+            drag: DragScroll::new(),
+            inertia: Inertia::new(),
         }
     }
 
     #[inline]
     fn send(&self, cmd: AppCmd) {
-        let _ = self.cmd_tx.try_send(cmd);
+        // Failures are counted in the input stats; nothing else to do from the UI thread
+        let _ = self.input.send(cmd);
     }
 
     fn set_button(&mut self, button: winit::event::MouseButton, pressed: bool) {
@@ -80,16 +165,107 @@ impl App {
         }
     }
 
-    fn send_mouse(&self, dx: f64, dy: f64, wheel: i32) {
-        let clamp = |v: f64| v.clamp(i8::MIN as f64, i8::MAX as f64) as i8;
+    fn send_mouse(&self, dx: f64, dy: f64, wheel: i32, pan: i32) {
         self.send(AppCmd::Mouse {
             buttons: self.mouse_buttons,
-            dx: clamp(dx),
-            dy: clamp(dy),
+            dx: dx as f32,
+            dy: dy as f32,
             wheel: wheel.clamp(i8::MIN as i32, i8::MAX as i32) as i8,
+            pan: pan.clamp(i8::MIN as i32, i8::MAX as i32) as i8,
         });
     }
 
+    /// Pointer motion through the configured scaling. `window_px` marks cursor deltas, which
+    /// DPI-aware mode measures in logical pixels; raw device motion has no such scale.
+    fn send_motion(&mut self, mut dx: f64, mut dy: f64, window_px: bool) {
+        self.refresh_pointer();
+        if window_px
+            && self.pointer.settings().dpi_aware
+            && let Some(w) = self.window.as_ref()
+        {
+            dx /= w.scale_factor();
+            dy /= w.scale_factor();
+        }
+        if self.scroll_held.0 || self.scroll_held.1 {
+            let step = self.pointer.settings().scroll_step;
+            let (wheel, pan) = self.drag.motion(dx as f32, dy as f32, step);
+            if wheel != 0 || pan != 0 {
+                self.send_mouse(0.0, 0.0, wheel, pan);
+            }
+            return;
+        }
+        let now = self.epoch.elapsed();
+        let (dx, dy) = self
+            .pointer
+            .scale(dx as f32, dy as f32, now, self.precision_held);
+        let speed = self.profile_speed.get();
+        self.send_mouse((dx * speed) as f64, (dy * speed) as f64, 0, 0);
+    }
+
+    /// Trackpad scrolling in pixels, sent as whole wheel and pan steps
+    fn scroll_px(&mut self, x: f64, y: f64) {
+        // winit's positive x moves the content right, i.e. scrolls left; HID pan is the
+        // other way round. Positive y scrolls up in both.
+        let wheel = px_notches(&mut self.wheel_px_accum, y);
+        let pan = -px_notches(&mut self.pan_px_accum, x);
+        if wheel != 0 || pan != 0 {
+            self.send_mouse(0.0, 0.0, wheel, pan);
+        }
+    }
+
+    /// Kinetic scrolling: follow a trackpad gesture and coast once it lifts
+    fn track_scroll(&mut self, x: f64, y: f64, phase: TouchPhase) {
+        self.refresh_pointer();
+        if !self.pointer.settings().kinetic_scroll {
+            return;
+        }
+        let now = self.epoch.elapsed();
+        match phase {
+            TouchPhase::Started => {
+                self.inertia.cancel();
+                self.inertia.track(x as f32, y as f32, now);
+            }
+            TouchPhase::Moved => self.inertia.track(x as f32, y as f32, now),
+            TouchPhase::Ended => {
+                if self.inertia.release(now) {
+                    tracing::trace!("Kinetic scroll coasting");
+                }
+            }
+            TouchPhase::Cancelled => self.inertia.cancel(),
+        }
+    }
+
+    fn coast(&mut self) {
+        let friction = self.pointer.settings().kinetic_friction;
+        let (x, y) = self.inertia.coast(self.epoch.elapsed(), friction);
+        self.scroll_px(x as f64, y as f64);
+    }
+
+    /// Middle button in scroll mode: holding it scrolls, and only a hold that never scrolled
+    /// reaches the host, as a click on release
+    fn scroll_button(&mut self, pressed: bool) {
+        self.scroll_held.1 = pressed;
+        if pressed {
+            self.drag.reset();
+            return;
+        }
+        if !self.drag.scrolled() {
+            self.set_button(winit::event::MouseButton::Middle, true);
+            self.send_mouse(0.0, 0.0, 0, 0);
+            self.set_button(winit::event::MouseButton::Middle, false);
+            self.send_mouse(0.0, 0.0, 0, 0);
+        }
+    }
+
+    fn refresh_pointer(&mut self) {
+        if self.opts.settings.has_changed().unwrap_or(false) {
+            let pointer = self.opts.settings.borrow_and_update().pointer.clone();
+            self.precision_key = pointer.precision_usage();
+            self.scroll_key = pointer.scroll_usage();
+            self.pointer.set_settings(pointer);
+        }
+    }
+
     fn note_modifier_physical_transition(&mut self, usage: u8, down: bool) {
         let bit = match usage {
             0xE0 => 0,
@@ -109,7 +285,73 @@ impl App {
         }
     }
 
-    fn draw_once_black(&mut self) {
+    /// Forward on-screen keyboard output and repaint it
+    fn send_osk(&self, cmds: Vec<AppCmd>) {
+        for cmd in cmds {
+            self.send(cmd);
+        }
+        if let Some(w) = self.window.as_ref() {
+            w.request_redraw();
+        }
+    }
+
+    fn shutdown(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
+        self.exiting = true;
+        // Drop softbuffer resources explicitly
+        self.sb_surface = None;
+        self.sb_ctx = None;
+        event_loop.exit();
+    }
+
+    fn release_all_keys(&mut self) {
+        for &u in self.pressed_usages.clone().iter() {
+            self.send(AppCmd::KeyUp(u));
+        }
+        self.pressed_usages.clear();
+        for u in std::mem::take(&mut self.pressed_consumer) {
+            self.send(AppCmd::ConsumerUp(u));
+        }
+        self.hid_mod_mask = 0;
+    }
+
+    fn set_captured(&mut self, captured: bool) {
+        let Some(win) = self.window.as_ref() else {
+            return;
+        };
+        if captured {
+            // Locked keeps the cursor in place (raw deltas only); not every platform has it
+            if let Err(e) = win
+                .set_cursor_grab(CursorGrabMode::Locked)
+                .or_else(|_| win.set_cursor_grab(CursorGrabMode::Confined))
+            {
+                tracing::warn!(error = %e, "cursor grab failed");
+                return;
+            }
+            win.set_cursor_visible(false);
+            win.set_title("Bluper (captured)");
+        } else {
+            if let Err(e) = win.set_cursor_grab(CursorGrabMode::None) {
+                tracing::warn!(error = %e, "cursor ungrab failed");
+            }
+            win.set_cursor_visible(true);
+            win.set_title("Bluper");
+        }
+        self.captured = captured;
+        self.cursor_last = None;
+        win.request_redraw();
+        tracing::info!(%captured, "Pointer capture");
+    }
+
+    fn release_chord_held(&self) -> bool {
+        !self.opts.capture_release.is_empty()
+            && self
+                .opts
+                .capture_release
+                .iter()
+                .all(|u| self.pressed_usages.contains(u))
+    }
+
+    fn draw(&mut self) {
         // Lazy init if needed
         if self.sb_surface.is_none() {
             if let Some(win_ref) = self.window.as_ref() {
@@ -144,7 +386,24 @@ impl App {
                 return;
             }
             if let Ok(mut buf) = surf.buffer_mut() {
-                buf.fill(0xFF00_0000);
+                let status = self.status_rx.borrow_and_update().clone();
+                let mut canvas = Canvas::new(&mut buf, w.get() as usize, h.get() as usize);
+                canvas.clear(hud::BG);
+                let hud_bottom = hud::draw(
+                    &mut canvas,
+                    &HudView {
+                        status: &status,
+                        device_name: &self.opts.device_name,
+                        capture_enabled: self.opts.capture,
+                        captured: self.captured,
+                        capture_release: &self.opts.capture_release,
+                    },
+                );
+                if let Some(osk) = self.osk.as_mut() {
+                    let (w, h) = (w.get() as i32, h.get() as i32);
+                    osk.layout(Rect::new(0, hud_bottom, w, h - hud_bottom));
+                    osk.draw(&mut canvas, status.modifiers, &status.keys);
+                }
                 if let Err(e) = buf.present() {
                     tracing::error!(error = %e, "softbuffer present error");
                 }
@@ -153,12 +412,14 @@ impl App {
     }
 }
 
-impl ApplicationHandler for App {
+impl ApplicationHandler<UiEvent> for App {
     fn resumed(&mut self, el: &winit::event_loop::ActiveEventLoop) {
         if self.exiting {
             return;
         }
-        let win = el.create_window(Window::default_attributes()).unwrap();
+        let win = el
+            .create_window(Window::default_attributes().with_title("Bluper"))
+            .unwrap();
         self.size = win.inner_size();
         self.window = Some(Rc::new(win));
         self.window.as_ref().unwrap().request_redraw();
@@ -172,20 +433,59 @@ impl ApplicationHandler for App {
         event: WindowEvent,
     ) {
         tracing::trace!(?event, "winit event");
+        if matches!(
+            event,
+            WindowEvent::KeyboardInput { .. }
+                | WindowEvent::MouseInput { .. }
+                | WindowEvent::CursorMoved { .. }
+                | WindowEvent::MouseWheel {
+                    delta: MouseScrollDelta::LineDelta(..),
+                    ..
+                }
+                | WindowEvent::Touch(_)
+                | WindowEvent::Focused(false)
+        ) {
+            // New input stops a coasting scroll; trackpad scrolling is handled by `track_scroll`
+            self.inertia.cancel();
+        }
         match event {
             WindowEvent::CloseRequested | WindowEvent::Destroyed => {
                 self.send(AppCmd::Exit);
-                self.exiting = true;
-                // Drop softbuffer resources explicitly
-                self.sb_surface = None;
-                self.sb_ctx = None;
-                event_loop.exit();
+                self.shutdown(event_loop);
             }
             WindowEvent::KeyboardInput { event, .. } => {
+                if let PhysicalKey::Code(code) = event.physical_key
+                    && let Some(u) = keycode_to_consumer(code)
+                {
+                    let down = matches!(event.state, ElementState::Pressed);
+                    let changed = if down {
+                        self.pressed_consumer.insert(u)
+                    } else {
+                        self.pressed_consumer.remove(&u)
+                    };
+                    if changed {
+                        self.send(if down {
+                            AppCmd::ConsumerDown(u)
+                        } else {
+                            AppCmd::ConsumerUp(u)
+                        });
+                    }
+                    return;
+                }
                 let usage = match &event.physical_key {
                     PhysicalKey::Code(code) => keycode_to_hid(*code),
                     _ => None,
                 };
+                self.refresh_pointer();
+                if usage.is_some() && usage == self.precision_key {
+                    // Slows the pointer locally; the host never sees it
+                    self.precision_held = matches!(event.state, ElementState::Pressed);
+                    return;
+                }
+                if usage.is_some() && usage == self.scroll_key {
+                    self.scroll_held.0 = matches!(event.state, ElementState::Pressed);
+                    return;
+                }
                 if let Some(u) = usage {
                     let down = matches!(event.state, ElementState::Pressed);
                     // Track pressed usages for focus-loss cleanup
@@ -200,59 +500,117 @@ impl ApplicationHandler for App {
                         AppCmd::KeyUp(u)
                     });
                     self.note_modifier_physical_transition(u, down);
+                    if down && self.captured && self.release_chord_held() {
+                        // Don't leave the chord held on the host
+                        self.release_all_keys();
+                        self.set_captured(false);
+                    }
                 }
             }
             WindowEvent::ModifiersChanged(m) => {
                 // Record for UI state only; don't reconcile to avoid double-sends
                 self.mods_winit = m.state();
             }
+            WindowEvent::MouseInput { state, button, .. } if self.osk.is_some() => {
+                if button != winit::event::MouseButton::Left {
+                    return;
+                }
+                let osk = self.osk.as_mut().unwrap();
+                let cmds = match (state, self.cursor_pos) {
+                    (ElementState::Pressed, Some((x, y))) => osk.press(x, y),
+                    (ElementState::Released, _) => osk.release(),
+                    _ => return,
+                };
+                self.send_osk(cmds);
+            }
             WindowEvent::MouseInput { state, button, .. } => {
-                self.set_button(button, matches!(state, ElementState::Pressed));
-                self.send_mouse(0.0, 0.0, 0);
+                let pressed = matches!(state, ElementState::Pressed);
+                if self.opts.capture && !self.captured {
+                    // The click that starts capture stays local
+                    if pressed && button == winit::event::MouseButton::Left {
+                        self.set_captured(true);
+                    }
+                    return;
+                }
+                self.refresh_pointer();
+                if button == winit::event::MouseButton::Middle
+                    && self.pointer.settings().scroll_button
+                {
+                    self.scroll_button(pressed);
+                    return;
+                }
+                self.set_button(button, pressed);
+                self.send_mouse(0.0, 0.0, 0, 0);
             }
             WindowEvent::CursorEntered { .. } | WindowEvent::CursorLeft { .. } => {
                 self.cursor_last = None;
+                self.cursor_pos = None;
+            }
+            WindowEvent::CursorMoved { position, .. } if self.osk.is_some() => {
+                self.cursor_pos = Some((position.x, position.y));
+                let cmds = self.osk.as_mut().unwrap().moved(position.x, position.y);
+                if !cmds.is_empty() {
+                    self.send_osk(cmds);
+                }
+            }
+            WindowEvent::Touch(touch) if self.osk.is_some() => {
+                // Single contact: the first finger down owns the keyboard/pad until lifted
+                if self.touch_id.is_some_and(|id| id != touch.id) {
+                    return;
+                }
+                let (x, y) = (touch.location.x, touch.location.y);
+                let osk = self.osk.as_mut().unwrap();
+                let cmds = match touch.phase {
+                    TouchPhase::Started => {
+                        self.touch_id = Some(touch.id);
+                        osk.press(x, y)
+                    }
+                    TouchPhase::Moved => osk.moved(x, y),
+                    TouchPhase::Ended | TouchPhase::Cancelled => {
+                        self.touch_id = None;
+                        osk.release()
+                    }
+                };
+                self.send_osk(cmds);
+            }
+            WindowEvent::CursorMoved { .. } if self.captured => {
+                // Raw motion arrives through device_event instead
             }
             WindowEvent::CursorMoved { position, .. } => {
                 let (x, y) = (position.x, position.y);
                 if let Some((px, py)) = self.cursor_last.replace((x, y)) {
-                    self.send_mouse(x - px, y - py, 0);
+                    self.send_motion(x - px, y - py, true);
                 } else {
-                    self.send_mouse(0.0, 0.0, 0);
+                    self.send_mouse(0.0, 0.0, 0, 0);
                 }
             }
-            WindowEvent::MouseWheel { delta, .. } => {
-                const PX_PER_NOTCH: f64 = 120.0;
-                let mut notches = 0i32;
-                match delta {
-                    MouseScrollDelta::LineDelta(_, y) => {
-                        notches = y.round() as i32;
-                    }
-                    MouseScrollDelta::PixelDelta(p) => {
-                        self.wheel_px_accum += p.y;
-                        while self.wheel_px_accum.abs() >= PX_PER_NOTCH {
-                            if self.wheel_px_accum > 0.0 {
-                                notches += 1;
-                                self.wheel_px_accum -= PX_PER_NOTCH;
-                            } else {
-                                notches -= 1;
-                                self.wheel_px_accum += PX_PER_NOTCH;
-                            }
-                        }
+            WindowEvent::MouseWheel { delta, phase, .. } => match delta {
+                MouseScrollDelta::LineDelta(x, y) => {
+                    // Same directions as `scroll_px`
+                    let (wheel, pan) = (y.round() as i32, -x.round() as i32);
+                    if wheel != 0 || pan != 0 {
+                        self.send_mouse(0.0, 0.0, wheel, pan);
                     }
                 }
-                if notches != 0 {
-                    self.send_mouse(0.0, 0.0, notches);
+                MouseScrollDelta::PixelDelta(p) => {
+                    self.track_scroll(p.x, p.y, phase);
+                    self.scroll_px(p.x, p.y);
                 }
-            }
+            },
             WindowEvent::Focused(focused) => {
                 if !focused {
                     // Send key up for all pressed usages and clear modifiers
-                    for &u in self.pressed_usages.clone().iter() {
-                        self.send(AppCmd::KeyUp(u));
+                    self.release_all_keys();
+                    self.precision_held = false;
+                    self.scroll_held = (false, false);
+                    self.drag.reset();
+                    if self.captured {
+                        self.set_captured(false);
+                    }
+                    if let Some(osk) = self.osk.as_mut() {
+                        let cmds = osk.reset();
+                        self.send_osk(cmds);
                     }
-                    self.pressed_usages.clear();
-                    self.hid_mod_mask = 0;
                 }
                 tracing::info!(%focused, "Focused");
             }
@@ -263,9 +621,56 @@ impl ApplicationHandler for App {
                 }
             }
             WindowEvent::RedrawRequested => {
-                self.draw_once_black();
+                self.draw();
             }
             _ => {}
         }
     }
+
+    fn user_event(&mut self, event_loop: &winit::event_loop::ActiveEventLoop, event: UiEvent) {
+        match event {
+            UiEvent::StatusChanged => {
+                if let Some(w) = self.window.as_ref() {
+                    w.request_redraw();
+                }
+            }
+            UiEvent::Shutdown => self.shutdown(event_loop),
+        }
+    }
+
+    fn about_to_wait(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
+        // Coasting needs wakeups of its own; otherwise sleep until the next event
+        if self.inertia.is_coasting() {
+            self.coast();
+        }
+        event_loop.set_control_flow(if self.inertia.is_coasting() {
+            ControlFlow::WaitUntil(Instant::now() + COAST_TICK)
+        } else {
+            ControlFlow::Wait
+        });
+    }
+
+    fn device_event(
+        &mut self,
+        _event_loop: &winit::event_loop::ActiveEventLoop,
+        _device_id: winit::event::DeviceId,
+        event: DeviceEvent,
+    ) {
+        if let DeviceEvent::MouseMotion { delta: (dx, dy) } = event
+            && self.captured
+        {
+            self.inertia.cancel();
+            // Unaccelerated device deltas, unaffected by the window edge
+            self.send_motion(dx, dy, false);
+        }
+    }
+}
+
+/// Whole wheel notches in a trackpad's pixel scroll, keeping the remainder in `accum`
+fn px_notches(accum: &mut f64, px: f64) -> i32 {
+    const PX_PER_NOTCH: f64 = 120.0;
+    *accum += px;
+    let notches = (*accum / PX_PER_NOTCH).trunc();
+    *accum -= notches * PX_PER_NOTCH;
+    notches as i32
 }
@@ -0,0 +1,157 @@
+// Config file parsing and how changes between two versions are classified
+use bluper::config::{Apply, BatterySource, Change, Config, PnpId, VendorSource};
+use bluper::typing::{Layout, UnicodeInput};
+
+const CONFIG: &str = r#"
+[identity]
+name = "Desk"
+appearance = 0x03C2
+
+[device_info]
+serial = "0001"
+pnp_id = { vendor = 0x1d50, product = 0x615e, version = 0x0100 }
+
+[reports]
+mouse = false
+
+[battery]
+source = "fixed"
+level = 80
+
+[input]
+evdev = ["logitech"]
+layout = "de"
+remap = "remap.toml"
+"#;
+
+fn parse(text: &str) -> Config {
+    Config::from_toml(text).unwrap()
+}
+
+fn fields(changes: &[Change], apply: Apply) -> Vec<&'static str> {
+    changes
+        .iter()
+        .filter(|c| c.apply == apply)
+        .map(|c| c.field)
+        .collect()
+}
+
+#[test]
+fn sections_parse_with_defaults_for_the_rest() {
+    let c = parse(CONFIG);
+    assert_eq!(c.identity.name.as_deref(), Some("Desk"));
+    assert_eq!(c.identity.appearance, Some(0x03C2));
+    assert!(c.reports.keyboard && !c.reports.mouse);
+    assert_eq!(c.battery.source, BatterySource::Fixed);
+    assert_eq!(c.battery.poll_secs, 30);
+    assert_eq!(c.input.layout, Some(Layout::De));
+    assert!(c.advertising.enabled && c.control.enabled);
+    assert_eq!(parse(""), Config::default());
+}
+
+#[test]
+fn pnp_id_bytes_are_little_endian() {
+    let pnp = PnpId {
+        vendor_source: VendorSource::Usb,
+        vendor: 0x1d50,
+        product: 0x615e,
+        version: 0x0100,
+    };
+    assert_eq!(pnp.to_bytes(), [2, 0x50, 0x1d, 0x5e, 0x61, 0x00, 0x01]);
+    assert_eq!(parse(CONFIG).device_info.pnp_id, Some(pnp));
+}
+
+#[test]
+fn mistakes_are_rejected() {
+    assert!(Config::from_toml("[identity]\nnmae = \"x\"").is_err());
+    assert!(Config::from_toml("[input]\nlayout = \"klingon\"").is_err());
+    assert!(Config::from_toml("[battery]\nlevel = 101").is_err());
+}
+
+#[test]
+fn report_types_switch_live() {
+    let old = parse("[reports]\nconsumer = true");
+    assert!(old.reports.keyboard && old.reports.consumer && !old.reports.nkro);
+    let new = parse("[reports]\nnkro = true\nabsolute = true");
+    assert_eq!(
+        fields(&old.changes(&new), Apply::Live),
+        ["reports.nkro", "reports.consumer", "reports.absolute"]
+    );
+}
+
+#[test]
+fn load_resolves_paths_next_to_the_file() {
+    let dir = std::env::temp_dir().join(format!("bluper-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    std::fs::write(&path, format!("{CONFIG}{PROFILES}")).unwrap();
+    let c = Config::load(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(c.input.remap, Some(dir.join("remap.toml")));
+    let mac = c.profiles.get("mac").unwrap();
+    assert_eq!(mac.remap, Some(dir.join("mac.toml")));
+}
+
+#[test]
+fn changes_say_what_applying_them_takes() {
+    let old = parse(CONFIG);
+    let new = parse(
+        &CONFIG
+            .replace("Desk", "Couch")
+            .replace("0001", "0002")
+            .replace("mouse = false", "mouse = true")
+            .replace("\"de\"", "\"us\""),
+    );
+    let changes = old.changes(&new);
+    assert_eq!(
+        fields(&changes, Apply::Live),
+        ["identity.name", "reports.mouse"]
+    );
+    assert_eq!(fields(&changes, Apply::Restart), ["input.layout"]);
+    // The DIS strings default to the name, so renaming changes them too
+    assert_eq!(
+        fields(&changes, Apply::Gatt),
+        [
+            "device_info.manufacturer",
+            "device_info.model",
+            "device_info.serial"
+        ]
+    );
+    assert!(old.changes(&old.clone()).is_empty());
+}
+
+const PROFILES: &str = r#"
+[profiles.mac]
+hosts = ["AA:BB:CC:DD:EE:01"]
+unicode = "macos"
+remap = "mac.toml"
+
+[profiles.tv]
+hosts = ["AA:BB:CC:DD:EE:02", "AA:BB:CC:DD:EE:03"]
+pointer_speed = 2.5
+reports = { keyboard = false, mouse = false, consumer = true }
+"#;
+
+#[test]
+fn profiles_are_found_by_host() {
+    let c = parse(PROFILES);
+    let (name, mac) = c.profiles.for_host("aa:bb:cc:dd:ee:01").unwrap();
+    assert_eq!(name, "mac");
+    assert_eq!(mac.unicode, Some(UnicodeInput::Macos));
+    let (name, tv) = c.profiles.for_host("AA:BB:CC:DD:EE:03").unwrap();
+    assert_eq!(name, "tv");
+    let reports = tv.reports.as_ref().unwrap();
+    assert!(!reports.keyboard && !reports.mouse && reports.consumer);
+    assert!(c.profiles.for_host("AA:BB:CC:DD:EE:04").is_none());
+
+    let changes = c.changes(&parse(&PROFILES.replace("2.5", "3")));
+    assert_eq!(fields(&changes, Apply::Live), ["profiles"]);
+}
+
+#[test]
+fn bad_profiles_are_rejected() {
+    let twice = "[profiles.a]\nhosts = [\"x\"]\n[profiles.b]\nhosts = [\"X\"]";
+    let err = Config::from_toml(twice).unwrap_err();
+    assert!(format!("{err:#}").contains("profiles a and b"), "{err:#}");
+    assert!(Config::from_toml("[profiles.a]\npointer_speed = 0").is_err());
+}
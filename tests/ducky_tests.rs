@@ -0,0 +1,167 @@
+// DuckyScript payloads compiled to key events, and the reports a dry run prints
+use std::time::Duration;
+
+use bluper::ducky::{compile, reports};
+use bluper::macros::Op;
+use bluper::typing::KeyEvent::{self, Down, Up};
+use bluper::typing::{Keymap, Layout, Timing};
+
+const A: u8 = 0x04;
+const B: u8 = 0x05;
+const R: u8 = 0x15;
+const T: u8 = 0x17;
+const ENTER: u8 = 0x28;
+const DELETE: u8 = 0x4C;
+const LCTRL: u8 = 0xE0;
+const LSHIFT: u8 = 0xE1;
+const LALT: u8 = 0xE2;
+const LGUI: u8 = 0xE3;
+
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
+
+fn run(script: &str) -> anyhow::Result<Vec<Op>> {
+    let timing = Timing {
+        hold: ms(5),
+        gap: ms(5),
+    };
+    compile(script, &Keymap::new(Layout::Us), timing)
+}
+
+/// Key events only, for payloads whose timing does not matter to the test
+fn keys(script: &str) -> Vec<KeyEvent> {
+    run(script)
+        .unwrap()
+        .into_iter()
+        .filter_map(|op| match op {
+            Op::Key(ev) => Some(ev),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn combo_lines_press_in_order_and_release_in_reverse() {
+    assert_eq!(
+        run("GUI r").unwrap(),
+        vec![
+            Op::Key(Down(LGUI)),
+            Op::Key(Down(R)),
+            Op::Wait(ms(5)),
+            Op::Key(Up(R)),
+            Op::Key(Up(LGUI)),
+            Op::Wait(ms(5)),
+        ]
+    );
+    assert_eq!(
+        keys("CTRL-ALT DELETE"),
+        vec![
+            Down(LCTRL),
+            Down(LALT),
+            Down(DELETE),
+            Up(DELETE),
+            Up(LALT),
+            Up(LCTRL)
+        ]
+    );
+    assert_eq!(keys("UPARROW"), vec![Down(0x52), Up(0x52)]);
+}
+
+#[test]
+fn string_and_stringln() {
+    assert_eq!(
+        keys("REM comment\nSTRING aB\nSTRINGLN"),
+        vec![
+            Down(A),
+            Up(A),
+            Down(LSHIFT),
+            Down(B),
+            Up(B),
+            Up(LSHIFT),
+            Down(ENTER),
+            Up(ENTER)
+        ]
+    );
+}
+
+#[test]
+fn delays_and_default_delay() {
+    let ops = run("DEFAULT_DELAY 100\nDELAY 50\nSTRING a").unwrap();
+    assert_eq!(
+        ops,
+        vec![
+            Op::Wait(ms(150)),
+            Op::Key(Down(A)),
+            Op::Wait(ms(5)),
+            Op::Key(Up(A)),
+            Op::Wait(ms(105)),
+        ]
+    );
+    let ops = run("STRING_DELAY 40\nSTRING aa").unwrap();
+    assert!(ops.contains(&Op::Wait(ms(40))), "{ops:?}");
+}
+
+#[test]
+fn repeat_reruns_the_previous_command() {
+    assert_eq!(keys("t\nREPEAT 2"), [Down(T), Up(T)].repeat(3));
+    // Repeated assignments see the updated value each time
+    let script = "VAR $d = 10\n$d = $d * 2\nREPEAT 2\nDELAY $d + 1";
+    assert_eq!(run(script).unwrap(), vec![Op::Wait(ms(81))]);
+}
+
+#[test]
+fn defines_and_variables() {
+    let script = "DEFINE #NAME ab\nVAR $n = (1 + 2) * 3 % 5\nSTRING #NAME\nDELAY $n";
+    let ops = run(script).unwrap();
+    assert_eq!(ops.last(), Some(&Op::Wait(ms(5 + 4))));
+    assert_eq!(keys(script), vec![Down(A), Up(A), Down(B), Up(B)]);
+}
+
+#[test]
+fn hold_release_and_cleanup() {
+    assert_eq!(
+        keys("HOLD SHIFT\na\nRELEASE SHIFT\nHOLD CTRL"),
+        vec![
+            Down(LSHIFT),
+            Down(A),
+            Up(A),
+            Up(LSHIFT),
+            Down(LCTRL),
+            Up(LCTRL)
+        ]
+    );
+}
+
+#[test]
+fn errors_name_the_line() {
+    let err = run("STRING ok\nIF ($x == 1) THEN").unwrap_err();
+    assert!(format!("{err:#}").contains("line 2"), "{err:#}");
+    assert!(run("BOGUSKEY").is_err());
+    assert!(run("DELAY $nope").is_err());
+    assert!(run("$x = 1").is_err());
+    assert!(run("DELAY 1 / 0").is_err());
+    assert!(run("REPEAT 3").is_err());
+    assert!(run("STRING €").is_err());
+    assert!(run("STRING a\nREPEAT 2000000").is_err());
+    assert!(run("VAR $x = 1\nREPEAT 9223372036854775807").is_err());
+}
+
+#[test]
+fn dry_run_reports_follow_key_state() {
+    let reports = reports(&run("GUI r\nDELAY 100\nSTRING a").unwrap());
+    let bytes: Vec<[u8; 9]> = reports.iter().map(|r| r.bytes).collect();
+    assert_eq!(
+        bytes,
+        vec![
+            [0x02, 0x08, 0, 0, 0, 0, 0, 0, 0],
+            [0x02, 0x08, 0, R, 0, 0, 0, 0, 0],
+            [0x02, 0x08, 0, 0, 0, 0, 0, 0, 0],
+            [0x02, 0x00, 0, 0, 0, 0, 0, 0, 0],
+            [0x02, 0x00, 0, A, 0, 0, 0, 0, 0],
+            [0x02, 0x00, 0, 0, 0, 0, 0, 0, 0],
+        ]
+    );
+    assert_eq!(reports[4].at, ms(110));
+    assert_eq!(reports[4].event, Down(A));
+}
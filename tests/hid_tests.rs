@@ -1,13 +1,19 @@
 use std::collections::BTreeSet;
 
-use bluper::hid::{build_keyboard_report, build_mouse_report, keycode_to_hid};
+use bluper::hid::{
+    build_absolute_report, build_consumer_report, build_keyboard_report, build_mouse_report,
+    build_nkro_report, button_from_name, consumer_from_name, consumer_name, keycode_to_hid,
+    linux_keycode_to_hid, parse_chord,
+};
 use winit::keyboard::KeyCode;
 
 #[test]
 fn keyboard_report_length_and_padding() {
     let mut pressed = BTreeSet::new();
     // Push 7 keys; only first 6 should be included
-    for k in [0x04u8, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A] { pressed.insert(k); }
+    for k in [0x04u8, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A] {
+        pressed.insert(k);
+    }
     let mods = 0b0001_0010; // example mask
     let pkt = build_keyboard_report(mods, &pressed);
     assert_eq!(pkt.len(), 9);
@@ -20,8 +26,30 @@ fn keyboard_report_length_and_padding() {
 
 #[test]
 fn mouse_report_layout() {
-    let pkt = build_mouse_report(0b0000_0111, -10, 5, 1);
-    assert_eq!(pkt, [0x01, 0b0000_0111, 246, 5, 1]); // 246 = -10u8
+    let pkt = build_mouse_report(0b0000_0111, -10, 5, 1, -2);
+    assert_eq!(pkt, [0x01, 0b0000_0111, 246, 5, 1, 254]); // 246 = -10u8
+}
+
+#[test]
+fn nkro_report_is_a_bitmap() {
+    // Eight keys at once, plus one past the bitmap that belongs to the 6KRO report
+    let pressed: BTreeSet<u8> = [0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x7F, 0x87].into();
+    let pkt = build_nkro_report(0b0000_0001, &pressed);
+    assert_eq!(pkt.len(), 18);
+    assert_eq!(&pkt[..4], &[0x04, 0b0000_0001, 0b1111_0000, 0b0000_0111]);
+    assert_eq!(pkt[17], 0b1000_0000);
+    assert!(pkt[4..17].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn consumer_and_absolute_reports() {
+    assert_eq!(consumer_from_name("Volume_Up"), Some(0xE9));
+    assert_eq!(consumer_name(0x223), "home");
+    assert_eq!(build_consumer_report(0x223), [0x03, 0x23, 0x02]);
+    assert_eq!(
+        build_absolute_report(0b001, 0x4000, 0xFFFF),
+        [0x05, 0b001, 0x00, 0x40, 0xFF, 0x7F]
+    );
 }
 
 #[test]
@@ -30,3 +58,28 @@ fn keycode_mapping_basic() {
     assert_eq!(keycode_to_hid(KeyCode::Digit1), Some(0x1E));
     assert_eq!(keycode_to_hid(KeyCode::Enter), Some(0x28));
 }
+
+#[test]
+fn linux_keycode_mapping() {
+    assert_eq!(linux_keycode_to_hid(30), Some(0x04)); // KEY_A
+    assert_eq!(linux_keycode_to_hid(2), Some(0x1E)); // KEY_1
+    assert_eq!(linux_keycode_to_hid(68), Some(0x43)); // KEY_F10
+    assert_eq!(linux_keycode_to_hid(125), Some(0xE3)); // KEY_LEFTMETA
+    assert_eq!(linux_keycode_to_hid(0x110), None); // BTN_LEFT is a mouse button
+}
+
+#[test]
+fn chord_parsing() {
+    assert_eq!(parse_chord("ctrl+alt+t"), Some(vec![0xE0, 0xE2, 0x17]));
+    assert_eq!(parse_chord("RAlt+F12"), Some(vec![0xE6, 0x45]));
+    assert_eq!(parse_chord("ctrl+nope"), None);
+    // Keys without a name round-trip through usage_name as raw usages
+    assert_eq!(parse_chord("ctrl+0x87"), Some(vec![0xE0, 0x87]));
+}
+
+#[test]
+fn button_names() {
+    assert_eq!(button_from_name("Left"), Some(0b001));
+    assert_eq!(button_from_name("right"), Some(0b100));
+    assert_eq!(button_from_name("back"), None);
+}
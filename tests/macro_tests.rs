@@ -0,0 +1,250 @@
+// Macro definitions, trigger handling, timed playback and recording
+use std::time::Duration;
+
+use bluper::macros::{MacroEngine, MacroSet, Op, Step};
+use bluper::typing::KeyEvent::{Down, Up};
+use bluper::typing::{Keymap, Layout, Timing};
+
+const A: u8 = 0x04;
+const B: u8 = 0x05;
+const H: u8 = 0x0B;
+const I: u8 = 0x0C;
+const ONE: u8 = 0x1E;
+const TWO: u8 = 0x1F;
+const ESC: u8 = 0x29;
+const F9: u8 = 0x42;
+const F10: u8 = 0x43;
+const LCTRL: u8 = 0xE0;
+const LSHIFT: u8 = 0xE1;
+const LALT: u8 = 0xE2;
+const LGUI: u8 = 0xE3;
+
+const CONFIG: &str = r#"
+[settings]
+record = "f9"
+play = "f10"
+
+[macros.hi]
+trigger = "hyper+1"
+steps = [{ text = "Hi" }]
+
+[macros.slow]
+trigger = "ctrl+2"
+steps = [{ down = "a" }, { delay_ms = 100 }, { up = "a" }, { tap = "b" }]
+
+[macros.click]
+trigger = "2"
+steps = [{ click = "left" }, { move = [10, -5] }]
+"#;
+
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
+
+fn load(config: &str) -> anyhow::Result<MacroSet> {
+    let timing = Timing {
+        hold: ms(5),
+        gap: ms(5),
+    };
+    MacroSet::from_toml(config, Keymap::new(Layout::Us), timing)
+}
+
+fn set(config: &str) -> MacroSet {
+    load(config).unwrap()
+}
+
+fn key(k: bluper::typing::KeyEvent) -> Op {
+    Op::Key(k)
+}
+
+/// Tick at every deadline up to `until`, collecting what is played
+fn run_until(e: &mut MacroEngine, until: Duration) -> Vec<Op> {
+    let mut out = Vec::new();
+    while let Some(at) = e.deadline().filter(|&at| at <= until) {
+        out.extend(e.tick(at));
+    }
+    out
+}
+
+#[test]
+fn steps_compile_to_timed_ops() {
+    let set = set(CONFIG);
+    let names: Vec<&str> = set.macros().iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(names, ["click", "hi", "slow"]);
+    let slow = &set.macros()[2];
+    assert_eq!(slow.trigger, vec![LCTRL, TWO]);
+    assert_eq!(
+        slow.ops,
+        vec![
+            key(Down(A)),
+            Op::Wait(ms(100)),
+            key(Up(A)),
+            key(Down(B)),
+            Op::Wait(ms(5)),
+            key(Up(B)),
+            Op::Wait(ms(5)),
+        ]
+    );
+    assert_eq!(
+        set.macros()[1].trigger,
+        vec![LCTRL, LSHIFT, LALT, LGUI, ONE]
+    );
+}
+
+#[test]
+fn bad_definitions_fail_at_load() {
+    let err = load("[macros.x]\ntrigger = \"bogus\"\nsteps = []")
+        .err()
+        .unwrap();
+    assert!(format!("{err:#}").contains("macros.x"), "{err:#}");
+    assert!(load("[macros.x]\ntrigger = \"a\"\nsteps = [{ text = \"€\" }]").is_err());
+    assert!(load("[macros.x]\ntrigger = \"a\"\nsteps = [{ click = \"thumb\" }]").is_err());
+}
+
+#[test]
+fn trigger_is_swallowed_and_modifiers_lifted() {
+    let mut e = MacroEngine::new(set(CONFIG));
+    let t = Duration::ZERO;
+    for k in [LCTRL, LSHIFT, LALT, LGUI] {
+        assert_eq!(e.key(Down(k), t), vec![key(Down(k))]);
+    }
+    let out = e.key(Down(ONE), t);
+    // Modifiers come up before the text, which starts at once
+    assert_eq!(
+        &out[..5],
+        &[
+            key(Up(LCTRL)),
+            key(Up(LSHIFT)),
+            key(Up(LALT)),
+            key(Up(LGUI)),
+            key(Down(LSHIFT))
+        ]
+    );
+    assert!(e.is_running());
+    let mut played = out[4..].to_vec();
+    played.extend(run_until(&mut e, ms(1000)));
+    assert!(!e.is_running());
+    assert_eq!(
+        played,
+        vec![
+            key(Down(LSHIFT)),
+            key(Down(H)),
+            key(Up(H)),
+            key(Up(LSHIFT)),
+            key(Down(I)),
+            key(Up(I)),
+        ]
+    );
+    // Releasing the trigger keys sends nothing more
+    for k in [ONE, LCTRL, LSHIFT, LALT, LGUI] {
+        assert_eq!(e.key(Up(k), ms(1000)), vec![]);
+    }
+}
+
+#[test]
+fn longest_trigger_wins() {
+    let mut e = MacroEngine::new(set(CONFIG));
+    e.key(Down(LCTRL), Duration::ZERO);
+    let out = e.key(Down(TWO), Duration::ZERO);
+    assert_eq!(out, vec![key(Up(LCTRL)), key(Down(A))]);
+
+    let mut e = MacroEngine::new(set(CONFIG));
+    let out = e.key(Down(TWO), Duration::ZERO);
+    assert!(matches!(out[0], Op::Mouse { buttons: 1, .. }), "{out:?}");
+}
+
+#[test]
+fn cancel_chord_releases_held_keys() {
+    let mut e = MacroEngine::new(set(CONFIG));
+    e.key(Down(LCTRL), Duration::ZERO);
+    e.key(Down(TWO), Duration::ZERO);
+    e.key(Up(TWO), ms(10));
+    e.key(Up(LCTRL), ms(10));
+    assert_eq!(e.deadline(), Some(ms(100)));
+    // Escape only cancels while something runs, and never reaches the host
+    assert_eq!(e.key(Down(ESC), ms(50)), vec![key(Up(A))]);
+    assert!(!e.is_running());
+    assert_eq!(e.key(Up(ESC), ms(60)), vec![]);
+    assert_eq!(e.key(Down(ESC), ms(70)), vec![key(Down(ESC))]);
+}
+
+#[test]
+fn retriggering_restarts_cleanly() {
+    let mut e = MacroEngine::new(set(CONFIG));
+    e.key(Down(LCTRL), Duration::ZERO);
+    e.key(Down(TWO), Duration::ZERO);
+    e.key(Up(TWO), ms(10));
+    assert_eq!(e.key(Down(TWO), ms(20)), vec![key(Up(A)), key(Down(A))]);
+    assert_eq!(e.cancel(), vec![key(Up(A))]);
+}
+
+#[test]
+fn record_then_play() {
+    let mut e = MacroEngine::new(set(CONFIG));
+    assert_eq!(e.key(Down(F9), Duration::ZERO), vec![]);
+    assert!(e.is_recording());
+    e.key(Up(F9), ms(10));
+    assert_eq!(e.key(Down(A), ms(100)), vec![key(Down(A))]);
+    e.mouse(0, 3.0, 4.0, 0, -2, ms(120));
+    assert_eq!(e.key(Up(A), ms(150)), vec![key(Up(A))]);
+    e.key(Down(F9), ms(400));
+    assert!(!e.is_recording());
+    // The logged definition loads back as a macro
+    let toml = e.recorded_toml().unwrap();
+    let saved = set(&toml);
+    assert_eq!(saved.macros()[0].name, "recorded");
+    assert_eq!(saved.macros()[0].trigger, vec![F10]);
+    let file: toml::Value = toml::from_str(&toml).unwrap();
+    let steps: Vec<Step> = file["macros"]["recorded"]["steps"]
+        .clone()
+        .try_into()
+        .unwrap();
+    assert_eq!(
+        steps,
+        vec![
+            Step::Down("a".into()),
+            Step::DelayMs(20),
+            Step::Move([3.0, 4.0]),
+            Step::Pan(-2),
+            Step::DelayMs(30),
+            Step::Up("a".into()),
+        ]
+    );
+
+    let t = ms(1000);
+    let mut played = e.key(Down(F10), t);
+    played.extend(run_until(&mut e, t + ms(100)));
+    assert_eq!(
+        played,
+        vec![
+            key(Down(A)),
+            Op::Mouse {
+                buttons: 0,
+                dx: 3.0,
+                dy: 4.0,
+                wheel: 0,
+                pan: 0
+            },
+            Op::Mouse {
+                buttons: 0,
+                dx: 0.0,
+                dy: 0.0,
+                wheel: 0,
+                pan: -2
+            },
+            key(Up(A)),
+        ]
+    );
+    assert_eq!(e.key(Up(F10), t), vec![]);
+}
+
+#[test]
+fn recording_releases_what_it_left_down() {
+    let mut e = MacroEngine::new(set(CONFIG));
+    e.key(Down(F9), Duration::ZERO);
+    e.key(Up(F9), Duration::ZERO);
+    e.key(Down(B), Duration::ZERO);
+    e.key(Down(F9), Duration::ZERO);
+    let toml = e.recorded_toml().unwrap();
+    assert!(toml.ends_with("up = \"b\"\n"), "{toml}");
+}
@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use bluper::config::Config;
+use bluper::mouse::{DragScroll, Inertia, MouseScheduler, PointerScaler, PointerSettings};
+
+#[test]
+fn fractional_motion_carries_over() {
+    let mut m = MouseScheduler::new();
+    // Four quarter-count moves add up to one count instead of truncating to zero
+    for _ in 0..3 {
+        assert!(m.push(0, 0.25, -0.25, 0, 0).is_empty());
+        assert_eq!(m.take_report(), None);
+    }
+    m.push(0, 0.25, -0.25, 0, 0);
+    assert_eq!(m.take_report(), Some([0x01, 0, 1, 0xFF, 0, 0]));
+    assert_eq!(m.take_report(), None);
+}
+
+#[test]
+fn large_motion_spreads_over_slots() {
+    let mut m = MouseScheduler::new();
+    m.push(0, 300.0, 0.0, 0, 0);
+    assert_eq!(m.take_report(), Some([0x01, 0, 127, 0, 0, 0]));
+    assert_eq!(m.take_report(), Some([0x01, 0, 127, 0, 0, 0]));
+    assert_eq!(m.take_report(), Some([0x01, 0, 46, 0, 0, 0]));
+    assert!(!m.has_pending());
+}
+
+#[test]
+fn pan_rides_with_the_wheel_and_splits_like_it() {
+    let mut m = MouseScheduler::new();
+    m.push(0, 0.0, 0.0, 1, -200);
+    assert_eq!(m.take_report(), Some([0x01, 0, 0, 0, 1, 0x80]));
+    assert_eq!(m.take_report(), Some([0x01, 0, 0, 0, 0, 0xB8])); // -72
+    assert_eq!(m.take_report(), None);
+}
+
+#[test]
+fn button_change_flushes_immediately() {
+    let mut m = MouseScheduler::new();
+    m.push(0, 3.0, 0.0, 0, 0);
+    let urgent = m.push(0b001, 0.0, 0.0, 0, 0);
+    // Pending motion under the old buttons, then the press
+    assert_eq!(
+        urgent,
+        vec![[0x01, 0, 3, 0, 0, 0], [0x01, 0b001, 0, 0, 0, 0]]
+    );
+    assert_eq!(m.take_report(), None);
+}
+
+fn curve() -> PointerSettings {
+    PointerSettings {
+        curve: vec![[100.0, 1.0], [1000.0, 3.0]],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn acceleration_gain_interpolates_and_holds_at_the_ends() {
+    let s = curve();
+    assert_eq!(s.gain_at(0.0), 1.0);
+    assert_eq!(s.gain_at(550.0), 2.0);
+    assert_eq!(s.gain_at(5000.0), 3.0);
+    assert_eq!(PointerSettings::default().gain_at(5000.0), 1.0);
+}
+
+#[test]
+fn scaling_applies_speed_gains_and_precision() {
+    let ms = Duration::from_millis;
+    let mut p = PointerScaler::new(PointerSettings {
+        speed: 2.0,
+        x_gain: 0.5,
+        ..curve()
+    });
+    // The first delta counts as starting from rest: 1 count over 50 ms is slow
+    assert_eq!(p.scale(1.0, 1.0, ms(0), false), (1.0, 2.0));
+    // 10 counts in 10 ms is fast enough for the top of the curve
+    assert_eq!(p.scale(10.0, 0.0, ms(10), false), (30.0, 0.0));
+    assert_eq!(p.scale(0.0, 4.0, ms(1000), true), (0.0, 2.0));
+}
+
+#[test]
+fn pointer_section_parses_and_is_checked() {
+    let c = Config::from_toml(
+        "[pointer]\nspeed = 1.5\ncurve = [[0, 1], [800, 2]]\nprecision_key = \"ralt\"",
+    )
+    .unwrap();
+    assert_eq!(c.pointer.speed, 1.5);
+    assert_eq!(c.pointer.curve.len(), 2);
+    assert!(c.pointer.precision_usage().is_some());
+    for bad in [
+        "speed = 0",
+        "curve = [[500, 1], [100, 2]]",
+        "curve = [[0, -1]]",
+        "precision_key = \"Nope\"",
+        "scroll_step = 0",
+        "kinetic_friction = 0",
+        "scroll_key = \"ralt\"\nprecision_key = \"ralt\"",
+    ] {
+        assert!(
+            Config::from_toml(&format!("[pointer]\n{bad}")).is_err(),
+            "{bad}"
+        );
+    }
+}
+
+#[test]
+fn drag_scroll_turns_motion_into_steps() {
+    let mut d = DragScroll::new();
+    assert_eq!(d.motion(5.0, -15.0, 20.0), (0, 0));
+    assert!(!d.scrolled());
+    // Up and right scroll up and right; the remainder carries over
+    assert_eq!(d.motion(40.0, -10.0, 20.0), (1, 2));
+    assert!(d.scrolled());
+    assert_eq!(d.motion(0.0, 45.0, 20.0), (-2, 0));
+    d.reset();
+    assert!(!d.scrolled());
+    assert_eq!(d.motion(-19.0, 0.0, 20.0), (0, 0));
+}
+
+fn flick(i: &mut Inertia) {
+    let ms = Duration::from_millis;
+    for t in [0, 10, 20] {
+        i.track(0.0, 10.0, ms(t));
+    }
+}
+
+#[test]
+fn flick_coasts_and_slows_to_a_stop() {
+    let ms = Duration::from_millis;
+    let mut i = Inertia::new();
+    flick(&mut i);
+    assert!(i.release(ms(30)));
+    // Smoothed to 750 px/s; 100 ms at friction 4 covers 750 * (1 - e^-0.4) / 4
+    let (x, y) = i.coast(ms(130), 4.0);
+    assert_eq!(x, 0.0);
+    assert!((y - 61.8).abs() < 0.1, "{y}");
+    let mut total = y;
+    let mut t = 130;
+    while i.is_coasting() {
+        t += 15;
+        total += i.coast(ms(t), 4.0).1;
+    }
+    // Never further than the whole integral, 750 / 4
+    assert!(total > 150.0 && total < 187.5, "{total}");
+    assert_eq!(i.coast(ms(t + 15), 4.0), (0.0, 0.0));
+}
+
+#[test]
+fn pauses_and_cancels_stop_coasting() {
+    let ms = Duration::from_millis;
+    let mut i = Inertia::new();
+    flick(&mut i);
+    // Held still before lifting
+    assert!(!i.release(ms(200)));
+    flick(&mut i);
+    assert!(i.release(ms(30)));
+    i.cancel();
+    assert!(!i.is_coasting());
+    assert_eq!(i.coast(ms(50), 4.0), (0.0, 0.0));
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use bluper::remap::{Action, RemapConfig, Remapper};
+use bluper::typing::KeyEvent::{Down, Up};
+
+// Untimed tests run at a fixed instant
+const T: Duration = Duration::ZERO;
+
+const CONFIG: &str = r#"
+[base]
+capslock = "ctrl"
+lalt = "lgui"
+lgui = "lalt"
+menu = "mo(fn)"
+scrolllock = "tg(nav)"
+insert = "none"
+
+[layers.fn]
+h = "left"
+j = "down"
+k = "up"
+l = "right"
+
+[layers.nav]
+h = "home"
+"#;
+
+fn remapper() -> Remapper {
+    Remapper::new(RemapConfig::from_toml(CONFIG).unwrap())
+}
+
+#[test]
+fn base_layer_remaps_and_swaps() {
+    let mut r = remapper();
+    assert_eq!(r.key_down(0x39, T), vec![Down(0xE0)]);
+    assert_eq!(r.key_up(0x39, T), vec![Up(0xE0)]);
+    assert_eq!(r.key_down(0xE2, T), vec![Down(0xE3)]);
+    assert_eq!(r.key_down(0xE3, T), vec![Down(0xE2)]);
+    // Unmapped keys pass through, disabled ones vanish
+    assert_eq!(r.key_down(0x04, T), vec![Down(0x04)]);
+    assert_eq!(r.key_down(0x49, T), vec![]);
+    assert_eq!(r.key_up(0x49, T), vec![]);
+}
+
+#[test]
+fn momentary_layer_while_held() {
+    let mut r = remapper();
+    assert_eq!(r.key_down(0x65, T), vec![]);
+    assert_eq!(r.active_layers(), vec!["fn"]);
+    assert_eq!(r.key_down(0x0B, T), vec![Down(0x50)]);
+    assert_eq!(r.key_up(0x0B, T), vec![Up(0x50)]);
+    assert_eq!(r.key_up(0x65, T), vec![]);
+    assert!(r.active_layers().is_empty());
+    assert_eq!(r.key_down(0x0B, T), vec![Down(0x0B)]);
+}
+
+#[test]
+fn key_up_releases_what_key_down_produced() {
+    let mut r = remapper();
+    r.key_down(0x65, T);
+    assert_eq!(r.key_down(0x0D, T), vec![Down(0x51)]);
+    // Layer goes away while J is still down
+    r.key_up(0x65, T);
+    assert_eq!(r.key_up(0x0D, T), vec![Up(0x51)]);
+}
+
+#[test]
+fn toggle_layer_and_stack_order() {
+    let mut r = remapper();
+    r.key_down(0x47, T);
+    r.key_up(0x47, T);
+    assert_eq!(r.active_layers(), vec!["nav"]);
+    assert_eq!(r.resolve(0x0B), Action::Key(0x4A));
+    // The most recently activated layer wins
+    r.key_down(0x65, T);
+    assert_eq!(r.resolve(0x0B), Action::Key(0x50));
+    r.key_up(0x65, T);
+    r.key_down(0x47, T);
+    assert!(r.active_layers().is_empty());
+}
+
+#[test]
+fn shared_output_stays_down_until_last_source_lifts() {
+    let mut r = remapper();
+    assert_eq!(r.key_down(0x39, T), vec![Down(0xE0)]);
+    assert_eq!(r.key_down(0xE0, T), vec![]);
+    assert_eq!(r.key_up(0x39, T), vec![]);
+    assert_eq!(r.key_up(0xE0, T), vec![Up(0xE0)]);
+}
+
+#[test]
+fn release_all_lifts_outputs_and_resets_layers() {
+    let mut r = remapper();
+    r.key_down(0x39, T);
+    r.key_down(0x47, T);
+    assert_eq!(r.active_layers(), ["nav"]);
+    r.key_down(0x0B, T);
+    assert_eq!(r.release_all(), vec![Up(0x4A), Up(0xE0)]);
+    assert!(r.active_layers().is_empty());
+    // Keys that were down before the reset come up silently
+    assert_eq!(r.key_up(0x0B, T), vec![]);
+    assert_eq!(r.key_down(0x0B, T), vec![Down(0x0B)]);
+}
+
+#[test]
+fn config_errors_name_the_entry() {
+    let err = RemapConfig::from_toml("[base]\na = \"mo(nope)\"").unwrap_err();
+    assert!(format!("{err:#}").contains("nope"), "{err:#}");
+    assert!(RemapConfig::from_toml("[base]\nbogus = \"a\"").is_err());
+    assert!(RemapConfig::from_toml("[layers.x]\na = \"bogus\"").is_err());
+}
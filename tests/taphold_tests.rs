@@ -0,0 +1,205 @@
+// Tap-hold, combos and one-shot modifiers, driven by a virtual clock
+use std::time::Duration;
+
+use bluper::remap::{RemapConfig, Remapper};
+use bluper::typing::KeyEvent::{self, Down, Up};
+
+const A: u8 = 0x04;
+const H: u8 = 0x0B;
+const J: u8 = 0x0D;
+const K: u8 = 0x0E;
+const S: u8 = 0x16;
+const X: u8 = 0x1B;
+const SPACE: u8 = 0x2C;
+const ESC: u8 = 0x29;
+const LEFT: u8 = 0x50;
+const LCTRL: u8 = 0xE0;
+const LSHIFT: u8 = 0xE1;
+
+const CONFIG: &str = r#"
+[base]
+a = "th(a, ctrl)"
+s = "th(s, shift)"
+space = "th(space, mo(fn))"
+lshift = "os(shift)"
+
+[layers.fn]
+h = "left"
+
+[combos]
+"j+k" = "esc"
+"#;
+
+/// Remapper plus a clock that only moves when the test says so
+struct Sim {
+    r: Remapper,
+    now: Duration,
+}
+
+impl Sim {
+    fn new(config: &str) -> Self {
+        Self {
+            r: Remapper::new(RemapConfig::from_toml(config).unwrap()),
+            now: Duration::ZERO,
+        }
+    }
+
+    fn down(&mut self, usage: u8) -> Vec<KeyEvent> {
+        self.r.key_down(usage, self.now)
+    }
+
+    fn up(&mut self, usage: u8) -> Vec<KeyEvent> {
+        self.r.key_up(usage, self.now)
+    }
+
+    /// Advance the clock, firing timers the way the input task would
+    fn wait(&mut self, ms: u64) -> Vec<KeyEvent> {
+        let target = self.now + Duration::from_millis(ms);
+        let mut out = Vec::new();
+        while let Some(at) = self.r.deadline().filter(|&at| at <= target) {
+            self.now = at;
+            out.extend(self.r.tick(at));
+        }
+        self.now = target;
+        out
+    }
+}
+
+#[test]
+fn quick_tap_sends_the_tap_key() {
+    let mut sim = Sim::new(CONFIG);
+    assert_eq!(sim.down(A), vec![]);
+    assert_eq!(sim.r.deadline(), Some(Duration::from_millis(200)));
+    assert_eq!(sim.wait(120), vec![]);
+    assert_eq!(sim.up(A), vec![Down(A), Up(A)]);
+    assert_eq!(sim.r.deadline(), None);
+}
+
+#[test]
+fn holding_past_the_tapping_term_sends_the_hold_key() {
+    let mut sim = Sim::new(CONFIG);
+    sim.down(A);
+    assert_eq!(sim.wait(199), vec![]);
+    assert_eq!(sim.wait(1), vec![Down(LCTRL)]);
+    assert_eq!(sim.down(X), vec![Down(X)]);
+    assert_eq!(sim.up(X), vec![Up(X)]);
+    assert_eq!(sim.up(A), vec![Up(LCTRL)]);
+}
+
+#[test]
+fn permissive_hold_on_nested_tap() {
+    let mut sim = Sim::new(CONFIG);
+    sim.down(A);
+    sim.wait(30);
+    assert_eq!(sim.down(X), vec![]);
+    sim.wait(30);
+    assert_eq!(sim.up(X), vec![Down(LCTRL), Down(X), Up(X)]);
+    assert_eq!(sim.up(A), vec![Up(LCTRL)]);
+}
+
+#[test]
+fn without_permissive_hold_a_nested_tap_stays_a_tap() {
+    let config = format!("[settings]\npermissive_hold = false\n{CONFIG}");
+    let mut sim = Sim::new(&config);
+    sim.down(A);
+    sim.wait(30);
+    sim.down(X);
+    sim.wait(30);
+    assert_eq!(sim.up(X), vec![]);
+    assert_eq!(sim.up(A), vec![Down(A), Up(A), Down(X), Up(X)]);
+}
+
+#[test]
+fn rolling_home_row_keys_type_both_letters() {
+    let mut sim = Sim::new(CONFIG);
+    sim.down(A);
+    sim.wait(40);
+    sim.down(S);
+    sim.wait(40);
+    assert_eq!(sim.up(A), vec![Down(A), Up(A)]);
+    sim.wait(40);
+    assert_eq!(sim.up(S), vec![Down(S), Up(S)]);
+}
+
+#[test]
+fn late_event_sees_the_expired_timer_first() {
+    let mut sim = Sim::new(CONFIG);
+    sim.down(A);
+    // No tick in between: the next event arrives well after the tapping term
+    sim.now = Duration::from_millis(250);
+    assert_eq!(sim.down(X), vec![Down(LCTRL), Down(X)]);
+}
+
+#[test]
+fn tap_hold_layer() {
+    let mut sim = Sim::new(CONFIG);
+    sim.down(SPACE);
+    sim.wait(250);
+    assert_eq!(sim.r.active_layers(), vec!["fn"]);
+    assert_eq!(sim.down(H), vec![Down(LEFT)]);
+    assert_eq!(sim.up(SPACE), vec![]);
+    assert_eq!(sim.up(H), vec![Up(LEFT)]);
+    sim.down(SPACE);
+    assert_eq!(sim.up(SPACE), vec![Down(SPACE), Up(SPACE)]);
+}
+
+#[test]
+fn combo_fires_and_releases_with_first_key_up() {
+    let mut sim = Sim::new(CONFIG);
+    assert_eq!(sim.down(J), vec![]);
+    sim.wait(10);
+    assert_eq!(sim.down(K), vec![Down(ESC)]);
+    assert_eq!(sim.up(J), vec![Up(ESC)]);
+    assert_eq!(sim.up(K), vec![]);
+}
+
+#[test]
+fn combo_key_alone_after_the_combo_term() {
+    let mut sim = Sim::new(CONFIG);
+    sim.down(J);
+    assert_eq!(sim.wait(60), vec![Down(J)]);
+    assert_eq!(sim.down(K), vec![]);
+    assert_eq!(sim.up(K), vec![Down(K), Up(K)]);
+    assert_eq!(sim.up(J), vec![Up(J)]);
+}
+
+#[test]
+fn other_key_breaks_a_combo() {
+    let mut sim = Sim::new(CONFIG);
+    sim.down(J);
+    sim.wait(5);
+    assert_eq!(sim.down(X), vec![Down(J), Down(X)]);
+}
+
+#[test]
+fn one_shot_modifier_applies_to_the_next_key() {
+    let mut sim = Sim::new(CONFIG);
+    assert_eq!(sim.down(LSHIFT), vec![Down(LSHIFT)]);
+    assert_eq!(sim.up(LSHIFT), vec![]);
+    sim.wait(500);
+    assert_eq!(sim.down(X), vec![Down(X), Up(LSHIFT)]);
+    assert_eq!(sim.up(X), vec![Up(X)]);
+}
+
+#[test]
+fn one_shot_held_acts_as_a_modifier() {
+    let mut sim = Sim::new(CONFIG);
+    sim.down(LSHIFT);
+    assert_eq!(sim.down(X), vec![Down(X)]);
+    sim.up(X);
+    assert_eq!(sim.up(LSHIFT), vec![Up(LSHIFT)]);
+}
+
+#[test]
+fn one_shot_times_out_or_cancels() {
+    let mut sim = Sim::new(CONFIG);
+    sim.down(LSHIFT);
+    sim.up(LSHIFT);
+    assert_eq!(sim.wait(3000), vec![Up(LSHIFT)]);
+
+    sim.down(LSHIFT);
+    sim.up(LSHIFT);
+    assert_eq!(sim.down(LSHIFT), vec![Up(LSHIFT)]);
+    assert_eq!(sim.up(LSHIFT), vec![]);
+    assert_eq!(sim.r.deadline(), None);
+}
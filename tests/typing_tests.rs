@@ -0,0 +1,237 @@
+use std::time::Duration;
+
+use bluper::typing::{
+    KeyEvent, Keymap, Layout, MOD_ALT, MOD_ALTGR, MOD_CTRL, MOD_SHIFT, Stroke, Timing,
+    UnicodeInput, type_text,
+};
+
+#[test]
+fn us_letters_and_shift() {
+    let t = type_text("aA!", Layout::Us);
+    assert_eq!(
+        t.strokes,
+        vec![
+            Stroke::new(0, 0x04),
+            Stroke::new(MOD_SHIFT, 0x04),
+            Stroke::new(MOD_SHIFT, 0x1E),
+        ]
+    );
+    assert!(t.unmappable.is_empty());
+}
+
+#[test]
+fn layouts_move_keys() {
+    // QWERTZ swaps Y/Z, AZERTY swaps A/Q, Dvorak puts 'o' on the S key, Colemak 's' on D
+    assert_eq!(
+        type_text("z", Layout::De).strokes,
+        vec![Stroke::new(0, 0x1C)]
+    );
+    assert_eq!(
+        type_text("a", Layout::Fr).strokes,
+        vec![Stroke::new(0, 0x14)]
+    );
+    assert_eq!(
+        type_text("o", Layout::Dvorak).strokes,
+        vec![Stroke::new(0, 0x16)]
+    );
+    assert_eq!(
+        type_text("s", Layout::Colemak).strokes,
+        vec![Stroke::new(0, 0x07)]
+    );
+}
+
+#[test]
+fn altgr_characters() {
+    assert_eq!(
+        type_text("@", Layout::De).strokes,
+        vec![Stroke::new(MOD_ALTGR, 0x14)]
+    );
+    assert_eq!(
+        type_text("€", Layout::Es).strokes,
+        vec![Stroke::new(MOD_ALTGR, 0x22)]
+    );
+    assert_eq!(
+        type_text("É", Layout::Uk).strokes,
+        vec![Stroke::new(MOD_ALTGR | MOD_SHIFT, 0x08)]
+    );
+}
+
+#[test]
+fn dead_keys_compose_and_stand_alone() {
+    // ê on AZERTY: dead circumflex, then e
+    assert_eq!(
+        type_text("ê", Layout::Fr).strokes,
+        vec![Stroke::new(0, 0x2F), Stroke::new(0, 0x08)]
+    );
+    // A bare dead accent is the dead key followed by space
+    assert_eq!(
+        type_text("^", Layout::De).strokes,
+        vec![Stroke::new(0, 0x35), Stroke::new(0, 0x2C)]
+    );
+    // Characters on their own key never go through a dead key
+    assert_eq!(
+        type_text("ä", Layout::De).strokes,
+        vec![Stroke::new(0, 0x34)]
+    );
+}
+
+#[test]
+fn unmappable_characters_are_reported() {
+    let t = type_text("añ😀", Layout::Us);
+    assert_eq!(t.strokes, vec![Stroke::new(0, 0x04)]);
+    assert_eq!(t.unmappable, vec![(1, 'ñ'), (2, '😀')]);
+}
+
+#[test]
+fn every_layout_types_printable_ascii() {
+    for layout in Layout::ALL {
+        let map = Keymap::new(layout);
+        for c in (0x20u8..0x7F).map(char::from) {
+            assert!(map.lookup(c).is_some(), "{layout} cannot type {c:?}");
+        }
+    }
+}
+
+#[test]
+fn events_wrap_modifiers_and_apply_timing() {
+    let timing = Timing {
+        hold: Duration::from_millis(5),
+        gap: Duration::from_millis(20),
+    };
+    let ev = type_text("A", Layout::Us).events(&timing);
+    assert_eq!(
+        ev,
+        vec![
+            (KeyEvent::Down(0xE1), Duration::ZERO),
+            (KeyEvent::Down(0x04), Duration::from_millis(5)),
+            (KeyEvent::Up(0x04), Duration::ZERO),
+            (KeyEvent::Up(0xE1), Duration::from_millis(20)),
+        ]
+    );
+}
+
+fn held(mods: u8, usage: u8) -> Stroke {
+    Stroke {
+        hold: true,
+        ..Stroke::new(mods, usage)
+    }
+}
+
+#[test]
+fn linux_unicode_entry() {
+    let t = Keymap::new(Layout::Us)
+        .with_unicode(UnicodeInput::Linux)
+        .type_text("a😀");
+    assert_eq!(
+        t.strokes,
+        vec![
+            Stroke::new(0, 0x04),
+            Stroke::new(MOD_CTRL | MOD_SHIFT, 0x18),
+            Stroke::new(0, 0x1E),
+            Stroke::new(0, 0x09),
+            Stroke::new(0, 0x23),
+            Stroke::new(0, 0x27),
+            Stroke::new(0, 0x27),
+            Stroke::new(0, 0x2C),
+        ]
+    );
+    assert!(t.unmappable.is_empty());
+    // Hex digits come from the host layout: shifted number row on AZERTY
+    let fr = Keymap::new(Layout::Fr).with_unicode(UnicodeInput::Linux);
+    assert_eq!(fr.lookup('“').unwrap()[1], Stroke::new(MOD_SHIFT, 0x1F));
+}
+
+#[test]
+fn windows_alt_codes_hold_alt() {
+    let t = Keymap::new(Layout::Us)
+        .with_unicode(UnicodeInput::WindowsAlt)
+        .type_text("é");
+    assert_eq!(
+        t.strokes,
+        vec![
+            held(MOD_ALT, 0x62),
+            held(MOD_ALT, 0x5A),
+            held(MOD_ALT, 0x5B),
+            Stroke::new(MOD_ALT, 0x5B),
+        ]
+    );
+    let timing = Timing {
+        hold: Duration::from_millis(5),
+        gap: Duration::from_millis(20),
+    };
+    let ev = t.events(&timing);
+    assert_eq!(ev.first(), Some(&(KeyEvent::Down(0xE2), Duration::ZERO)));
+    assert_eq!(
+        ev.last(),
+        Some(&(KeyEvent::Up(0xE2), Duration::from_millis(20)))
+    );
+    let alt_presses = ev
+        .iter()
+        .filter(|(e, _)| *e == KeyEvent::Down(0xE2))
+        .count();
+    assert_eq!(alt_presses, 1);
+    // Supplementary planes have no Alt code
+    let emoji = Keymap::new(Layout::Us)
+        .with_unicode(UnicodeInput::WindowsAlt)
+        .type_text("😀");
+    assert_eq!(emoji.unmappable, vec![(0, '😀')]);
+}
+
+#[test]
+fn windows_hex_numpad_entry() {
+    let map = Keymap::new(Layout::Us).with_unicode(UnicodeInput::WindowsHex);
+    assert_eq!(
+        map.lookup('€').unwrap(),
+        vec![
+            held(MOD_ALT, 0x57),
+            held(MOD_ALT, 0x5A),
+            held(MOD_ALT, 0x62),
+            held(MOD_ALT, 0x04),
+            Stroke::new(MOD_ALT, 0x06),
+        ]
+    );
+}
+
+#[test]
+fn macos_hex_input_uses_utf16() {
+    let map = Keymap::new(Layout::De).with_unicode(UnicodeInput::Macos);
+    let strokes = map.lookup('😀').unwrap();
+    // d83d de00, at US positions whatever the layout
+    let usages: Vec<u8> = strokes.iter().map(|s| s.usage).collect();
+    assert_eq!(usages, vec![0x07, 0x25, 0x20, 0x07, 0x07, 0x08, 0x27, 0x27]);
+    assert!(strokes.iter().all(|s| s.mods == MOD_ALT));
+    assert!(strokes[..7].iter().all(|s| s.hold));
+    assert!(!strokes[7].hold);
+}
+
+#[test]
+fn layout_keys_win_over_unicode_entry() {
+    let map = Keymap::new(Layout::De).with_unicode(UnicodeInput::Linux);
+    assert_eq!(map.lookup('ä').unwrap(), vec![Stroke::new(0, 0x34)]);
+}
+
+#[test]
+fn unicode_input_names_parse() {
+    assert_eq!("ibus".parse::<UnicodeInput>(), Ok(UnicodeInput::Linux));
+    assert_eq!(
+        "windows-hex".parse::<UnicodeInput>(),
+        Ok(UnicodeInput::WindowsHex)
+    );
+    assert!("morse".parse::<UnicodeInput>().is_err());
+}
+
+#[test]
+fn layout_names_parse() {
+    assert_eq!("DE".parse::<Layout>(), Ok(Layout::De));
+    assert_eq!("se".parse::<Layout>(), Ok(Layout::Nordic));
+    assert!("klingon".parse::<Layout>().is_err());
+}
+
+#[test]
+fn single_key_lookup() {
+    let de = Keymap::new(Layout::De);
+    assert_eq!(de.key('z'), Some(Stroke::new(0, 0x1C)));
+    assert_eq!(de.key('@'), Some(Stroke::new(MOD_ALTGR, 0x14)));
+    // Dead-key compositions take two strokes, so there's no single key for them
+    assert_eq!(Keymap::new(Layout::Fr).key('ê'), None);
+}